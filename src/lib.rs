@@ -0,0 +1,980 @@
+pub mod mlog_emulator;
+pub mod mlog_generator;
+pub mod parser;
+pub mod tokenizer;
+
+mod mlog_constants;
+mod mlog_inliner;
+mod mlog_ir;
+mod mlog_optimizer;
+mod mlog_register_allocator;
+
+/// Transpiles MLOG Elevated source into mlog, returning the first error in the program, like a
+/// call of undefined function
+pub fn transpile(
+    source: &str,
+    options: &mlog_generator::GeneratorOptions,
+) -> Result<mlog_generator::GeneratorOutput, String> {
+    let tokens = tokenizer::tokenize(source)?;
+    let ast = parser::parse_program(&tokens)?;
+    ast.generate(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mlog_emulator::{Emulator, Processor};
+    use mlog_generator::{GeneratorOptions, OptimizationLevel, TargetVersion};
+
+    fn options(level: OptimizationLevel) -> GeneratorOptions {
+        GeneratorOptions {
+            target: TargetVersion::V7,
+            debug: false,
+            level,
+            passes: level.passes(),
+            allow_unknown_constants: false,
+        }
+    }
+
+    const LEVELS: [OptimizationLevel; 4] = [
+        OptimizationLevel::O0,
+        OptimizationLevel::O1,
+        OptimizationLevel::Os,
+        OptimizationLevel::Ospeed,
+    ];
+
+    /// Runs the program for a second and returns the text printed into message1
+    fn run(source: &str, options: &GeneratorOptions) -> String {
+        let output = transpile(source, options).unwrap();
+        let mut emulator = Emulator::new(&output.code, Processor::Logic).unwrap();
+        emulator.run(60).unwrap();
+        emulator.message("message1").unwrap_or_default().into()
+    }
+
+    /// Runs the program at every optimization level, which all have to print the same text
+    fn run_at_every_level(source: &str) -> String {
+        let printed = run(source, &options(OptimizationLevel::O0));
+        for level in &LEVELS[1..] {
+            assert_eq!(run(source, &options(*level)), printed, "{:?}", level);
+        }
+        printed
+    }
+
+    #[test]
+    fn generator_errors_are_returned() {
+        let undefined_function =
+            transpile("fn main() {\nf()\n}\n", &options(OptimizationLevel::O1));
+        assert_eq!(
+            undefined_function.err().as_deref(),
+            Some("Function f not defined")
+        );
+        let undefined_variable =
+            transpile("fn main() {\nx = 1\n}\n", &options(OptimizationLevel::O1));
+        assert_eq!(
+            undefined_variable.err().as_deref(),
+            Some("Variable x not defined")
+        );
+        let break_outside_loop =
+            transpile("fn main() {\nbreak\n}\n", &options(OptimizationLevel::O1));
+        assert_eq!(
+            break_outside_loop.err().as_deref(),
+            Some("break used outside of a loop")
+        );
+        let overlapping_arrays = transpile(
+            "let a: [num; 4] @ cell1[0]\nlet b: [num; 4] @ cell1[2]\nfn main() {\na[0] = b[0]\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            overlapping_arrays.err().as_deref(),
+            Some("Arrays a (cell1[0..4]) and b (cell1[2..6]) overlap")
+        );
+        let recursive_inline = transpile(
+            "inline fn f() {\nf()\n}\nfn main() {\nf()\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            recursive_inline.err().as_deref(),
+            Some("Inline function f calls itself")
+        );
+    }
+
+    #[test]
+    fn parser_errors_are_returned() {
+        let result = transpile(
+            "fn main() {\nif a { } else",
+            &options(OptimizationLevel::O1),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn program_gives_same_result_at_every_level() {
+        let source = "
+fn sq(a) {
+    let t
+    t = mul(a, a)
+    return t
+}
+fn main() {
+    let i
+    let sum
+    i = 0
+    sum = 0
+    while lessThan(i, 10) {
+        let s
+        s = sq(i)
+        sum = add(sum, s)
+        i = add(i, 1)
+    }
+    print(sum)
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "285");
+    }
+
+    #[test]
+    fn match_uses_jump_table_for_dense_cases() {
+        let source = "
+fn main() {
+    let k
+    k = 0
+    while lessThan(k, 7) {
+        match k {
+            0 => { print(\"a\") }
+            1 => { print(\"b\") }
+            2 | 3 => { print(\"c\") }
+            5 => { print(\"d\") }
+            _ => { print(\"-\") }
+        }
+        k = add(k, 1)
+    }
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "abcc-d-");
+        let report = |level| {
+            transpile(source, &options(level))
+                .unwrap()
+                .optimization_report
+        };
+        assert_eq!(report(OptimizationLevel::O0).compare_chains, 1);
+        assert_eq!(report(OptimizationLevel::O1).jump_tables, 1);
+    }
+
+    #[test]
+    fn match_uses_compare_chain_for_sparse_cases() {
+        let source = "
+fn main() {
+    let k
+    k = 1
+    while lessThan(k, 2000) {
+        match k {
+            1 => { print(\"a\") }
+            10 => { print(\"b\") }
+            100 | 1000 => { print(\"c\") }
+        }
+        k = mul(k, 10)
+    }
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "abcc");
+        for level in LEVELS {
+            let report = transpile(source, &options(level))
+                .unwrap()
+                .optimization_report;
+            assert_eq!(report.compare_chains, 1, "{:?}", level);
+        }
+    }
+
+    #[test]
+    fn else_if_chain_takes_first_true_branch() {
+        let source = "
+fn main() {
+    let k
+    k = 0
+    while lessThan(k, 3) {
+        if equal(k, 0) {
+            print(\"a\")
+        } else if equal(k, 1) {
+            print(\"b\")
+        } else {
+            print(\"c\")
+        }
+        k = add(k, 1)
+    }
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "abc");
+    }
+
+    #[test]
+    fn repeated_match_pattern_is_an_error() {
+        let result = transpile(
+            "fn main() {\nlet k\nk = 1\nmatch k {\n1 => {}\n1 => {}\n}\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Match pattern 1 repeats a value matched earlier")
+        );
+        let result = transpile(
+            "enum E { A, B }\nfn main() {\nlet k\nk = E.A\nmatch k {\nE.A | E.B => {}\nE.A => {}\n}\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Match pattern E.A repeats a value matched earlier")
+        );
+    }
+
+    #[test]
+    fn labeled_break_and_continue_leave_outer_loop() {
+        let source = "
+fn main() {
+    let i
+    i = 0
+    'outer: loop {
+        i = add(i, 1)
+        let j
+        j = 0
+        while lessThan(j, 5) {
+            j = add(j, 1)
+            if equal(j, 3) { continue 'outer }
+            if equal(i, 3) { break 'outer }
+            print(j)
+        }
+    }
+    print(\";\")
+    for k in 0..6 {
+        if equal(k, 1) { continue }
+        if equal(k, 4) { break }
+        print(k)
+    }
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "1212;023");
+    }
+
+    #[test]
+    fn undefined_loop_label_is_an_error() {
+        let result = transpile(
+            "fn main() {\nloop {\nbreak 'outer\n}\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            result.err().as_deref(),
+            Some("break refers to loop label 'outer which is not defined")
+        );
+    }
+
+    #[test]
+    fn for_loop_steps_over_range() {
+        let source = "
+fn main() {
+    for k in 0..10 step 3 {
+        print(k)
+    }
+    print(\";\")
+    for k in 5..0 step -2 {
+        print(k)
+    }
+    print(\";\")
+    let n
+    n = 3
+    for k in 0..n {
+        print(k)
+    }
+    for k in n..0 {
+        print(\"never\")
+    }
+    print(\";\")
+    do {
+        print(\"once\")
+    } while 0
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "0369;531;012;once");
+        let unrolled = transpile(source, &options(OptimizationLevel::Ospeed))
+            .unwrap()
+            .optimization_report
+            .unrolled_loops;
+        assert_eq!(unrolled, vec![4, 3]);
+    }
+
+    #[test]
+    fn compound_assignment_updates_variable() {
+        let source = "
+fn main() {
+    let x
+    x = 5
+    x += 3
+    x *= 2
+    x -= 1
+    print(x, \",\")
+    x %= 4
+    x <<= 2
+    x >>= 1
+    print(x, \",\")
+    x |= 1
+    x ^= 2
+    x &= 4
+    print(x, \",\")
+    x /= 8
+    x += add(x, 1)
+    print(x, \",\")
+    let i
+    i = 0
+    while lessThan(i, 3) {
+        i++
+    }
+    i--
+    print(i)
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "15,6,4,2,2");
+    }
+
+    #[test]
+    fn logical_operators_skip_right_side() {
+        let source = "
+fn side(name, value) {
+    print(name)
+    return value
+}
+fn main() {
+    if side(\"a\", 0) && side(\"b\", 1) {
+        print(\"!\")
+    }
+    if side(\"c\", 1) || side(\"d\", 1) {
+        print(\"!\")
+    }
+    let x
+    x = side(\"e\", 1) && side(\"f\", 0)
+    print(x)
+    x = side(\"g\", 0) || side(\"h\", 0) || side(\"i\", 2)
+    print(x)
+    side(\"j\", 1) || side(\"k\", 1)
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "ac!ef0ghi1j");
+    }
+
+    #[test]
+    fn if_expression_gives_value_of_taken_branch() {
+        let source = "
+fn side(name, value) {
+    print(name)
+    return value
+}
+fn main() {
+    for k in 0..3 {
+        let x
+        x = if lessThan(k, 1) { \"a\" } else if equal(k, 1) { \"b\" } else { \"c\" }
+        print(x)
+        let y
+        y = if k { k } else { 9 }
+        print(y)
+    }
+    let z
+    z = if 1 { side(\"t\", 1) } else { side(\"f\", 2) }
+    print(z)
+    printflush(message1)
+}
+";
+        for target in [TargetVersion::V7, TargetVersion::V8] {
+            for level in LEVELS {
+                let options = GeneratorOptions {
+                    target,
+                    ..options(level)
+                };
+                assert_eq!(
+                    run(source, &options),
+                    "a9b1c2t1",
+                    "{:?} {:?}",
+                    target,
+                    level
+                );
+                let code = transpile(source, &options).unwrap().code;
+                assert_eq!(
+                    code.contains("select"),
+                    target == TargetVersion::V8,
+                    "{:?} {:?}",
+                    target,
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn memory_array_elements_go_to_their_slots() {
+        let source = "
+let buf: [num; 8] @ cell1[2]
+fn main() {
+    for i in 0..8 {
+        buf[i] = mul(i, i)
+    }
+    let k
+    k = 3
+    print(buf[k], \",\", buf[7])
+    printflush(message1)
+}
+";
+        for level in LEVELS {
+            let output = transpile(source, &options(level)).unwrap();
+            let mut emulator = Emulator::new(&output.code, Processor::Logic).unwrap();
+            emulator.run(60).unwrap();
+            assert_eq!(emulator.message("message1"), Some("9,49"), "{:?}", level);
+            let memory = emulator.memory("cell1").unwrap();
+            assert_eq!(
+                memory[..11],
+                [0.0, 0.0, 0.0, 1.0, 4.0, 9.0, 16.0, 25.0, 36.0, 49.0, 0.0],
+                "{:?}",
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn memory_array_bounds_are_checked() {
+        let literal_index = transpile(
+            "let buf: [num; 8] @ cell1[0]\nfn main() {\nbuf[8] = 1\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            literal_index.err().as_deref(),
+            Some("Index 8 is out of bounds of array buf with length 8")
+        );
+        let too_long = transpile(
+            "let buf: [num; 70] @ cell1[0]\nfn main() {\nbuf[0] = 1\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            too_long.err().as_deref(),
+            Some("Array buf occupies slots 0..70 of cell1, which has only 64 slots")
+        );
+
+        let dynamic_index = "
+let buf: [num; 8] @ cell1[0]
+fn main() {
+    let k
+    k = 8
+    buf[k] = 1
+    print(\"written\")
+    printflush(message1)
+}
+";
+        let debug_options = GeneratorOptions {
+            debug: true,
+            ..options(OptimizationLevel::O1)
+        };
+        let output = transpile(dynamic_index, &debug_options).unwrap();
+        let mut emulator = Emulator::new(&output.code, Processor::Logic).unwrap();
+        emulator.run(60).unwrap();
+        assert!(emulator.is_stopped());
+        assert_eq!(emulator.message("message1"), None);
+    }
+
+    #[test]
+    fn register_array_is_indexed_through_jump_table() {
+        let source = "
+let squares: [num; 5]
+fn main() {
+    let i
+    i = 0
+    while lessThan(i, 5) {
+        squares[i] = mul(i, i)
+        i = add(i, 1)
+    }
+    let sum
+    sum = 0
+    for j in 0..5 {
+        sum = add(sum, squares[j])
+    }
+    let k
+    k = 2.5
+    print(sum, \",\", squares[k], \",\", squares[4])
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "30,4,16");
+        let output = transpile(source, &options(OptimizationLevel::O1)).unwrap();
+        assert_eq!(output.size_report.register_arrays["squares"].length, 5);
+
+        let result = transpile(
+            "fn main() {\nlet r: [num; 2]\nr[2] = 1\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Index 2 is out of bounds of array r with length 2")
+        );
+    }
+
+    #[test]
+    fn declaration_errors_are_not_lost() {
+        let error = |source| transpile(source, &options(OptimizationLevel::O1)).err();
+        assert_eq!(
+            error("struct S { x: num, x: num }\n").as_deref(),
+            Some("Field x of struct S is defined twice")
+        );
+        assert_eq!(
+            error("link switch1 as\n").as_deref(),
+            Some("Expected alias of link switch1 after \"as\", but got \"None\"")
+        );
+        assert_eq!(
+            error("link a as b\n").as_deref(),
+            Some("a is not a link name, which is block name followed by a number, like switch1")
+        );
+        assert_eq!(
+            error("fn main() {\nfor i in 0..4 step 0 {\n}\n}\n").as_deref(),
+            Some(
+                "Expected non-zero number literal as for loop step, but got \"Some(Number(0.0))\""
+            )
+        );
+        assert_eq!(
+            error("fn main() {\n'outer: if 1 {\n}\n}\n").as_deref(),
+            Some("Expected loop after label 'outer, but got \"Some(Keyword(If))\"")
+        );
+    }
+
+    #[test]
+    fn declarations_cut_off_by_end_of_input_are_errors() {
+        let error = |source| transpile(source, &options(OptimizationLevel::O1)).err();
+        assert_eq!(
+            error("#[noinline]\n").as_deref(),
+            Some("Invalid function definition")
+        );
+        assert_eq!(
+            error("inline fn f(").as_deref(),
+            Some("Expected either identifier or \")\", but got \"None\"")
+        );
+        assert_eq!(
+            error("fn f(a").as_deref(),
+            Some("Expected either \")\" or \",\", but got \"None\"")
+        );
+        assert_eq!(
+            error("struct S").as_deref(),
+            Some("Invalid struct definition")
+        );
+        assert_eq!(
+            error("enum E {").as_deref(),
+            Some("Expected variant name in enum E, but got \"None\"")
+        );
+    }
+
+    #[test]
+    fn repeated_declarations_are_errors() {
+        let error = |source| transpile(source, &options(OptimizationLevel::O1)).err();
+        assert_eq!(
+            error("struct S { x }\nstruct S { y }\n").as_deref(),
+            Some("Struct S is defined twice")
+        );
+        assert_eq!(
+            error("enum E { A }\nenum E { B }\n").as_deref(),
+            Some("Enum E is defined twice")
+        );
+        assert_eq!(
+            error("let a: [num; 2]\nlet a: [num; 3]\n").as_deref(),
+            Some("Array a is declared twice")
+        );
+        assert_eq!(
+            error("struct S { x }\nenum S { A }\n").as_deref(),
+            Some("S is defined both as an enum and as a struct")
+        );
+        assert_eq!(
+            error("let a: [num; 2]\nlet a\n").as_deref(),
+            Some("a is declared both as an array and as a global variable")
+        );
+    }
+
+    #[test]
+    fn structs_are_copied_and_stored_field_by_field() {
+        let source = "
+struct Vec2 { x: num, y: num }
+struct Target { pos: Vec2, hp: num }
+let best: Target
+fn score(t: Target, bonus) {
+    return add(add(t.hp, t.pos.y), bonus)
+}
+fn main() {
+    let t: Target
+    t.pos.x = 3
+    t.pos.y = 4
+    t.hp = 10
+    best = t
+    t.hp = 0
+    print(best.hp, \",\", score(best, 2), \",\")
+    writeStruct(best, cell1, 4)
+    let copy: Target
+    let offset
+    offset = 4
+    readStruct(copy, cell1, offset)
+    print(copy.pos.x, copy.pos.y, copy.hp)
+    printflush(message1)
+}
+";
+        for level in LEVELS {
+            let output = transpile(source, &options(level)).unwrap();
+            let mut emulator = Emulator::new(&output.code, Processor::Logic).unwrap();
+            emulator.run(60).unwrap();
+            assert_eq!(
+                emulator.message("message1"),
+                Some("10,16,3410"),
+                "{:?}",
+                level
+            );
+            let memory = emulator.memory("cell1").unwrap();
+            assert_eq!(memory[4..7], [3.0, 4.0, 10.0], "{:?}", level);
+        }
+
+        let result = transpile(
+            "struct S { x, y }\nfn main() {\nlet s: S\nwriteStruct(s, cell1, 63)\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Struct s needs slots 63..65 of cell1, which has only 64 slots")
+        );
+    }
+
+    #[test]
+    fn enum_variants_are_matched_and_named() {
+        let source = "
+enum State { Idle, Moving, Attacking }
+fn main() {
+    let state
+    state = State.Idle
+    for i in 0..3 {
+        match state {
+            State.Idle => { state = State.Moving }
+            State.Moving => { state = State.Attacking }
+            State.Attacking => { state = State.Idle }
+        }
+        print(variantName(State, state), \",\")
+    }
+    print(variantName(State, 7))
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "Moving,Attacking,Idle,7");
+
+        let result = transpile(
+            "enum E { A, B, C }\nfn main() {\nlet e\ne = E.A\nmatch e {\nE.A => {}\n}\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Match on enum E doesn't cover variants B, C")
+        );
+        let with_wildcard = transpile(
+            "enum E { A, B, C }\nfn main() {\nlet e\ne = E.A\nmatch e {\nE.A => {}\n_ => {}\n}\n}\n",
+            &options(OptimizationLevel::O1),
+        );
+        assert!(with_wildcard.is_ok());
+    }
+
+    #[test]
+    fn string_escapes_reach_the_message() {
+        let source = "
+fn main() {
+    print(\"say \\\"hi\\\"\\nnext \\u{41}\\tB\")
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "say 'hi'\nnext A\tB");
+        let output = transpile(source, &options(OptimizationLevel::O1)).unwrap();
+        assert!(output
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("which mlog can't represent")));
+    }
+
+    #[test]
+    fn string_escape_errors_have_position() {
+        let error = |source| transpile(source, &options(OptimizationLevel::O1)).err();
+        assert_eq!(
+            error("fn main() {\nprint(\"ab\\q\")\n}\n").as_deref(),
+            Some("Unknown escape sequence \"\\q\" at 2:10")
+        );
+        assert_eq!(
+            error("fn main() {\nprint(\"\\u{zz}\")\n}\n").as_deref(),
+            Some("Invalid unicode escape \"\\u{zz}\" at 2:8")
+        );
+    }
+
+    #[test]
+    fn number_literals_have_their_values() {
+        let source = "
+fn main() {
+    let c
+    c = %ff0000
+    print(0x1F, \",\", 0b101, \",\", 1_000, \",\", 1.5e2, \",\", -3, \",\", 2.5E-1)
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "31,5,1000,150,-3,0.25");
+        let output = transpile(source, &options(OptimizationLevel::O0)).unwrap();
+        assert!(output.code.contains("%ff0000ff"));
+    }
+
+    #[test]
+    fn number_literal_errors_point_at_literal_start() {
+        let error = |source| transpile(source, &options(OptimizationLevel::O1)).err();
+        assert_eq!(
+            error("fn main() {\nprint(1.2.3)\n}\n").as_deref(),
+            Some("Number literal \"1.2.3\" has more than one decimal point at 2:7")
+        );
+        assert_eq!(
+            error("fn main() {\nprint(0x)\n}\n").as_deref(),
+            Some("Invalid number literal \"0x\" at 2:7")
+        );
+        assert_eq!(
+            error("fn main() {\nprint(1e400)\n}\n").as_deref(),
+            Some("Number literal \"1e400\" is out of range at 2:7")
+        );
+        assert_eq!(
+            error("fn main() {\nprint(%ff00)\n}\n").as_deref(),
+            Some("Invalid color literal \"%ff00\" at 2:7")
+        );
+    }
+
+    #[test]
+    fn unknown_constant_is_an_error_unless_allowed() {
+        let source = "fn main() {\nlet t\nt = @thsix\n}\n";
+        let result = transpile(source, &options(OptimizationLevel::O1));
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Unknown constant @thsix, did you mean @thisx?")
+        );
+        let allowed = GeneratorOptions {
+            allow_unknown_constants: true,
+            ..options(OptimizationLevel::O1)
+        };
+        let output = transpile(source, &allowed).unwrap();
+        assert_eq!(
+            output.warnings,
+            ["Unknown constant @thsix, did you mean @thisx?"]
+        );
+        assert!(transpile(
+            "fn main() {\nlet t\nt = @unit.health\nt = @poly\n}\n",
+            &options(OptimizationLevel::O1)
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn link_manifest_lists_buildings_by_function() {
+        let source = "
+link switch1 as doorSwitch: Building
+link door1 as frontDoor
+link door1 as backDoor
+link cell1 as store
+link message2
+let buf: [num; 4] @ store[0]
+fn toggle(s) {
+    frontDoor.enabled = s
+    backDoor.enabled = s
+}
+fn main() {
+    let s
+    s = doorSwitch.enabled
+    toggle(s)
+    buf[1] = s
+    printflush(message1)
+}
+";
+        let output = transpile(source, &options(OptimizationLevel::O0)).unwrap();
+        assert_eq!(
+            output.link_manifest.to_string(),
+            "cell1 as store, used by main
+door1 as backDoor, frontDoor, used by toggle
+message1 (not declared), used by main
+message2, unused
+switch1 as doorSwitch: Building, used by main
+"
+        );
+        assert_eq!(
+            output.warnings,
+            [
+                "Function main uses link message1, which isn't declared",
+                "Link message2 is declared, but never used"
+            ]
+        );
+        let result = transpile(
+            "link switch1\nlink switch1\n",
+            &options(OptimizationLevel::O0),
+        );
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Link switch1 is declared twice")
+        );
+    }
+
+    #[test]
+    fn inlining_weighs_copies_against_calls() {
+        let source = "
+fn double(x) {
+    return mul(x, 2)
+}
+fn mix(x) {
+    let y
+    y = add(x, 1)
+    y = mul(y, y)
+    y = sub(y, x)
+    y = div(y, 2)
+    return add(y, x)
+}
+#[noinline]
+fn once(x) {
+    return add(x, 1)
+}
+fn main() {
+    print(double(1))
+    print(double(2))
+    print(mix(3))
+    print(mix(4))
+    print(mix(5))
+    print(once(6))
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "249.514.520.57");
+        let decisions = |level| {
+            transpile(source, &options(level))
+                .unwrap()
+                .optimization_report
+                .inlining_decisions
+        };
+        assert!(decisions(OptimizationLevel::O0).is_empty());
+        assert_eq!(
+            decisions(OptimizationLevel::O1),
+            [
+                "double (size 1, calls 2): inlined, cheaper than the calls",
+                "main (size 23, calls 1): inlined, cheaper than the calls",
+                "mix (size 5, calls 3): kept, copies would cost more than the calls",
+                "once (size 1, calls 1): kept, marked #[noinline]",
+            ]
+        );
+        assert_eq!(
+            decisions(OptimizationLevel::Ospeed)[2],
+            "mix (size 5, calls 3): inlined, cheaper than the calls"
+        );
+    }
+
+    #[test]
+    fn recursive_functions_are_not_inlined() {
+        let source = "
+fn countdown(n) {
+    if greaterThan(n, 0) {
+        countdown(sub(n, 1))
+    }
+}
+fn main() {
+    countdown(2)
+}
+";
+        let output = transpile(source, &options(OptimizationLevel::Ospeed)).unwrap();
+        assert_eq!(
+            output.optimization_report.inlining_decisions[0],
+            "countdown (size 5, calls 2): kept, calls itself"
+        );
+        let inline_source = source.replace("fn countdown", "inline fn countdown");
+        assert_eq!(
+            transpile(&inline_source, &options(OptimizationLevel::O1))
+                .err()
+                .as_deref(),
+            Some("Inline function countdown calls itself")
+        );
+    }
+
+    #[test]
+    fn unused_functions_and_globals_are_left_out() {
+        let source = "
+let unused
+let kept
+
+fn dead_a() {
+    dead_b()
+}
+
+fn dead_b() {
+    dead_a()
+}
+
+fn main() {
+    unused = 5
+    kept = add(unused, 1)
+    unused = 2
+    print(kept)
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "6");
+        for level in LEVELS {
+            let output = transpile(source, &options(level)).unwrap();
+            assert_eq!(
+                output.warnings[1..],
+                [
+                    "Function dead_a is never called from main, so it's left out",
+                    "Function dead_b is never called from main, so it's left out",
+                ]
+            );
+            assert!(!output.code.contains("dead_"));
+        }
+        let source = source.replace("kept = add(unused, 1)", "kept = 6");
+        for level in LEVELS {
+            let output = transpile(&source, &options(level)).unwrap();
+            assert_eq!(
+                output.warnings.last().unwrap(),
+                "Global variable unused is never read, so stores into it are left out"
+            );
+            assert!(!output.code.contains("unused"));
+        }
+    }
+
+    #[test]
+    fn returned_calls_become_tail_calls() {
+        let source = "
+#[noinline]
+fn g(x) {
+    print(x)
+    return add(x, 1)
+}
+#[noinline]
+fn k(x) {
+    return g(mul(x, 10))
+}
+#[noinline]
+fn f(x) {
+    if lessThan(x, 2) {
+        return 0
+    }
+    if lessThan(x, 4) {
+        return k(x)
+    }
+    return g(x)
+}
+fn main() {
+    let a
+    a = g(7)
+    print(f(1))
+    print(f(3))
+    print(f(5))
+    print(a)
+    printflush(message1)
+}
+";
+        assert_eq!(run_at_every_level(source), "703031568");
+        let output = transpile(source, &options(OptimizationLevel::O1)).unwrap();
+        assert_eq!(output.optimization_report.tail_calls, 3);
+        assert!(!output.code.contains("f_result"));
+    }
+}
@@ -1,35 +1,130 @@
-mod mlog_generator;
-mod parser;
-mod tokenizer;
-
-use clap;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use mlog_elevated_transpiler::{mlog_emulator, mlog_generator};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    transpile: Option<TranspileArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Transpile the program and run it in the mlog emulator
+    Run {
+        #[command(flatten)]
+        transpile: TranspileArgs,
+        /// Number of ticks to run, 60 ticks per second
+        #[arg(long, default_value_t = 60)]
+        ticks: usize,
+        /// Processor whose speed is emulated
+        #[arg(long, value_enum, default_value = "logic")]
+        processor: mlog_emulator::Processor,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct TranspileArgs {
     input_file: String,
+    /// Mindustry version to generate code for
+    #[arg(long, value_enum, default_value = "v7")]
+    target: mlog_generator::TargetVersion,
+    /// Generate runtime checks, like array bounds checking
+    #[arg(long)]
+    debug: bool,
+    /// Optimization level: 0, 1, s (size) or speed
+    #[arg(short = 'O', value_enum, default_value = "1")]
+    optimization_level: mlog_generator::OptimizationLevel,
+    /// Turn the optimization pass off, can be repeated
+    #[arg(long = "disable-pass", value_enum, value_name = "PASS")]
+    disabled_passes: Vec<mlog_generator::OptimizationPass>,
+    /// Only warn about unknown `@` names, like content added by mods
+    #[arg(long)]
+    allow_unknown_constants: bool,
+    /// Explain optimization decisions, like which functions were inlined
+    #[arg(short, long)]
+    verbose: bool,
 }
 
 fn main() {
     let args = Args::parse();
-    println!("Transpiling {}...", args.input_file);
-
-    let file_contents = std::fs::read_to_string(&args.input_file)
-        .expect(format!("Error occurred during reading file {}", &args.input_file).as_str());
-
-    let tokens = tokenizer::tokenize(file_contents.as_str()).unwrap();
+    if let Err(err) = run(args) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
 
-    println!("Tokens:");
-    for (i, token) in tokens.iter().enumerate() {
-        println!("{}\t{:?}", i, token);
+fn run(args: Args) -> Result<(), String> {
+    match (args.command, args.transpile) {
+        (
+            Some(Command::Run {
+                transpile: args,
+                ticks,
+                processor,
+            }),
+            _,
+        ) => {
+            let output = transpile(&args)?;
+            let mut emulator = mlog_emulator::Emulator::new(&output.code, processor)
+                .map_err(|err| format!("Emulator can't load the generated code: {}", err))?;
+            if let Err(err) = emulator.run(ticks) {
+                eprintln!("Error: {}", err);
+            }
+            println!("Emulator:\n{}", emulator);
+        }
+        (None, Some(args)) => {
+            transpile(&args)?;
+        }
+        (None, None) => unreachable!("clap requires the input file without a subcommand"),
     }
+    Ok(())
+}
 
-    let ast = parser::parse_program(&tokens).unwrap();
+/// Transpiles the input file, printing every stage along the way
+fn transpile(args: &TranspileArgs) -> Result<mlog_generator::GeneratorOutput, String> {
+    println!("Transpiling {}...", args.input_file);
 
-    println!("AST:\n{:#?}", ast);
+    let file_contents = std::fs::read_to_string(&args.input_file)
+        .map_err(|err| format!("Can't read file {}: {}", args.input_file, err))?;
 
-    let mlog_code = ast.generate();
+    let output = mlog_elevated_transpiler::transpile(
+        &file_contents,
+        &mlog_generator::GeneratorOptions {
+            target: args.target,
+            debug: args.debug,
+            level: args.optimization_level,
+            passes: args
+                .optimization_level
+                .passes()
+                .into_iter()
+                .filter(|pass| !args.disabled_passes.contains(pass))
+                .collect(),
+            allow_unknown_constants: args.allow_unknown_constants,
+        },
+    )?;
 
-    println!("Generted MLOG code:\n{}", mlog_code);
+    for warning in &output.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    println!("Generted MLOG code:\n{}", output.code);
+    println!("Size report:\n{}", output.size_report);
+    println!(
+        "Optimizations (-O{}):\n{}",
+        args.optimization_level
+            .to_possible_value()
+            .unwrap()
+            .get_name(),
+        output.optimization_report
+    );
+    if args.verbose {
+        println!("Inlining decisions:");
+        for decision in &output.optimization_report.inlining_decisions {
+            println!("{}", decision);
+        }
+        println!();
+    }
+    println!("Link manifest:\n{}", output.link_manifest);
+    Ok(output)
 }
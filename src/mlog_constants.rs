@@ -0,0 +1,466 @@
+use crate::mlog_generator::TargetVersion;
+
+/// Built-in variables and constants, without the leading `@`, with the version they appeared in
+const GLOBALS: &[(&str, TargetVersion)] = &[
+    ("counter", TargetVersion::V7),
+    ("unit", TargetVersion::V7),
+    ("this", TargetVersion::V7),
+    ("thisx", TargetVersion::V7),
+    ("thisy", TargetVersion::V7),
+    ("links", TargetVersion::V7),
+    ("ipt", TargetVersion::V7),
+    ("time", TargetVersion::V7),
+    ("tick", TargetVersion::V7),
+    ("second", TargetVersion::V7),
+    ("minute", TargetVersion::V7),
+    ("waveNumber", TargetVersion::V7),
+    ("waveTime", TargetVersion::V7),
+    ("mapw", TargetVersion::V7),
+    ("maph", TargetVersion::V7),
+    ("server", TargetVersion::V7),
+    ("client", TargetVersion::V7),
+    ("clientLocale", TargetVersion::V7),
+    ("clientUnit", TargetVersion::V7),
+    ("clientName", TargetVersion::V7),
+    ("clientTeam", TargetVersion::V7),
+    ("clientMobile", TargetVersion::V7),
+    ("pi", TargetVersion::V7),
+    ("e", TargetVersion::V7),
+    ("degToRad", TargetVersion::V7),
+    ("radToDeg", TargetVersion::V7),
+    ("air", TargetVersion::V7),
+    ("solid", TargetVersion::V7),
+    ("blockCount", TargetVersion::V7),
+    ("unitCount", TargetVersion::V7),
+    ("itemCount", TargetVersion::V7),
+    ("liquidCount", TargetVersion::V7),
+    ("ctrlProcessor", TargetVersion::V7),
+    ("ctrlPlayer", TargetVersion::V7),
+    ("ctrlCommand", TargetVersion::V7),
+    ("derelict", TargetVersion::V7),
+    ("sharded", TargetVersion::V7),
+    ("crux", TargetVersion::V7),
+    ("malis", TargetVersion::V7),
+    ("green", TargetVersion::V7),
+    ("blue", TargetVersion::V7),
+];
+
+/// Properties readable with `sensor`, without the leading `@`
+const SENSOR_PROPERTIES: &[(&str, TargetVersion)] = &[
+    ("totalItems", TargetVersion::V7),
+    ("firstItem", TargetVersion::V7),
+    ("totalLiquids", TargetVersion::V7),
+    ("totalPower", TargetVersion::V7),
+    ("itemCapacity", TargetVersion::V7),
+    ("liquidCapacity", TargetVersion::V7),
+    ("powerCapacity", TargetVersion::V7),
+    ("powerNetStored", TargetVersion::V7),
+    ("powerNetCapacity", TargetVersion::V7),
+    ("powerNetIn", TargetVersion::V7),
+    ("powerNetOut", TargetVersion::V7),
+    ("ammo", TargetVersion::V7),
+    ("ammoCapacity", TargetVersion::V7),
+    ("health", TargetVersion::V7),
+    ("maxHealth", TargetVersion::V7),
+    ("heat", TargetVersion::V7),
+    ("shield", TargetVersion::V7),
+    ("armor", TargetVersion::V7),
+    ("efficiency", TargetVersion::V7),
+    ("progress", TargetVersion::V7),
+    ("timescale", TargetVersion::V7),
+    ("rotation", TargetVersion::V7),
+    ("x", TargetVersion::V7),
+    ("y", TargetVersion::V7),
+    ("shootX", TargetVersion::V7),
+    ("shootY", TargetVersion::V7),
+    ("cameraX", TargetVersion::V7),
+    ("cameraY", TargetVersion::V7),
+    ("cameraWidth", TargetVersion::V7),
+    ("cameraHeight", TargetVersion::V7),
+    ("size", TargetVersion::V7),
+    ("dead", TargetVersion::V7),
+    ("range", TargetVersion::V7),
+    ("shooting", TargetVersion::V7),
+    ("boosting", TargetVersion::V7),
+    ("mineX", TargetVersion::V7),
+    ("mineY", TargetVersion::V7),
+    ("mining", TargetVersion::V7),
+    ("speed", TargetVersion::V7),
+    ("team", TargetVersion::V7),
+    ("type", TargetVersion::V7),
+    ("flag", TargetVersion::V7),
+    ("controlled", TargetVersion::V7),
+    ("controller", TargetVersion::V7),
+    ("name", TargetVersion::V7),
+    ("payloadCount", TargetVersion::V7),
+    ("payloadType", TargetVersion::V7),
+    ("id", TargetVersion::V7),
+    ("enabled", TargetVersion::V7),
+    ("config", TargetVersion::V7),
+    ("color", TargetVersion::V7),
+    ("velocityX", TargetVersion::V8),
+    ("velocityY", TargetVersion::V8),
+    ("displayWidth", TargetVersion::V8),
+    ("displayHeight", TargetVersion::V8),
+    ("bufferSize", TargetVersion::V8),
+    ("operations", TargetVersion::V8),
+    ("memoryCapacity", TargetVersion::V8),
+];
+
+/// Subcommands of `control` with the number of arguments they take after the building
+const CONTROL_COMMANDS: &[(&str, usize, TargetVersion)] = &[
+    ("enabled", 1, TargetVersion::V7),
+    ("shoot", 3, TargetVersion::V7),
+    ("shootp", 2, TargetVersion::V7),
+    ("config", 1, TargetVersion::V7),
+    ("color", 1, TargetVersion::V7),
+];
+
+/// Items and liquids, which can also be sensed to get the amount stored
+const RESOURCES: &[&str] = &[
+    "copper",
+    "lead",
+    "metaglass",
+    "graphite",
+    "sand",
+    "coal",
+    "titanium",
+    "thorium",
+    "scrap",
+    "silicon",
+    "plastanium",
+    "phase-fabric",
+    "surge-alloy",
+    "spore-pod",
+    "blast-compound",
+    "pyratite",
+    "beryllium",
+    "tungsten",
+    "oxide",
+    "carbide",
+    "fissile-matter",
+    "dormant-cyst",
+    "water",
+    "slag",
+    "oil",
+    "cryofluid",
+    "neoplasm",
+    "arkycite",
+    "gallium",
+    "ozone",
+    "hydrogen",
+    "nitrogen",
+    "cyanogen",
+];
+
+/// Unit types and commonly used blocks
+const CONTENT: &[&str] = &[
+    //Units
+    "dagger",
+    "mace",
+    "fortress",
+    "scepter",
+    "reign",
+    "nova",
+    "pulsar",
+    "quasar",
+    "vela",
+    "corvus",
+    "crawler",
+    "atrax",
+    "spiroct",
+    "arkyid",
+    "toxopid",
+    "flare",
+    "horizon",
+    "zenith",
+    "antumbra",
+    "eclipse",
+    "mono",
+    "poly",
+    "mega",
+    "quad",
+    "oct",
+    "risso",
+    "minke",
+    "bryde",
+    "sei",
+    "omura",
+    "retusa",
+    "oxynoe",
+    "cyerce",
+    "aegires",
+    "navanax",
+    "alpha",
+    "beta",
+    "gamma",
+    "stell",
+    "locus",
+    "precept",
+    "vanquish",
+    "conquer",
+    "merui",
+    "cleroi",
+    "anthicus",
+    "tecta",
+    "collaris",
+    "elude",
+    "avert",
+    "obviate",
+    "quell",
+    "disrupt",
+    "evoke",
+    "incite",
+    "emanate",
+    //Turrets
+    "duo",
+    "scatter",
+    "scorch",
+    "hail",
+    "wave",
+    "lancer",
+    "arc",
+    "parallax",
+    "swarmer",
+    "salvo",
+    "segment",
+    "tsunami",
+    "fuse",
+    "ripple",
+    "cyclone",
+    "foreshadow",
+    "spectre",
+    "meltdown",
+    "breach",
+    "diffuse",
+    "sublimate",
+    "titan",
+    "disperse",
+    "afflict",
+    "lustre",
+    "scathe",
+    "smite",
+    "malign",
+    //Logic
+    "message",
+    "switch",
+    "micro-processor",
+    "logic-processor",
+    "hyper-processor",
+    "memory-cell",
+    "memory-bank",
+    "logic-display",
+    "large-logic-display",
+    "canvas",
+    "world-processor",
+    "world-cell",
+    "world-message",
+    "world-switch",
+    //Storage and distribution
+    "core-shard",
+    "core-foundation",
+    "core-nucleus",
+    "core-bastion",
+    "core-citadel",
+    "core-acropolis",
+    "container",
+    "vault",
+    "unloader",
+    "conveyor",
+    "titanium-conveyor",
+    "plastanium-conveyor",
+    "armored-conveyor",
+    "junction",
+    "bridge-conveyor",
+    "phase-conveyor",
+    "sorter",
+    "inverted-sorter",
+    "router",
+    "distributor",
+    "overflow-gate",
+    "underflow-gate",
+    "mass-driver",
+    //Power
+    "power-node",
+    "power-node-large",
+    "surge-tower",
+    "diode",
+    "battery",
+    "battery-large",
+    "combustion-generator",
+    "thermal-generator",
+    "steam-generator",
+    "differential-generator",
+    "rtg-generator",
+    "solar-panel",
+    "large-solar-panel",
+    "thorium-reactor",
+    "impact-reactor",
+    //Production and crafting
+    "mechanical-drill",
+    "pneumatic-drill",
+    "laser-drill",
+    "blast-drill",
+    "water-extractor",
+    "cultivator",
+    "oil-extractor",
+    "graphite-press",
+    "multi-press",
+    "silicon-smelter",
+    "silicon-crucible",
+    "kiln",
+    "plastanium-compressor",
+    "phase-weaver",
+    "surge-smelter",
+    "cryofluid-mixer",
+    "pyratite-mixer",
+    "blast-mixer",
+    "melter",
+    "separator",
+    "disassembler",
+    "spore-press",
+    "pulverizer",
+    "coal-centrifuge",
+    "incinerator",
+    //Units and defense
+    "ground-factory",
+    "air-factory",
+    "naval-factory",
+    "additive-reconstructor",
+    "multiplicative-reconstructor",
+    "exponential-reconstructor",
+    "tetrative-reconstructor",
+    "repair-point",
+    "repair-turret",
+    "mender",
+    "mend-projector",
+    "overdrive-projector",
+    "overdrive-dome",
+    "force-projector",
+    "shock-mine",
+    "illuminator",
+    "door",
+    "door-large",
+    //Liquids
+    "mechanical-pump",
+    "rotary-pump",
+    "impulse-pump",
+    "conduit",
+    "pulse-conduit",
+    "plated-conduit",
+    "liquid-router",
+    "liquid-container",
+    "liquid-tank",
+    "liquid-junction",
+    "bridge-conduit",
+    "phase-conduit",
+];
+
+/// Whether `@name` is a known built-in variable or constant of the target version
+pub fn is_known_constant(name: &str, target: TargetVersion) -> bool {
+    GLOBALS
+        .iter()
+        .chain(SENSOR_PROPERTIES)
+        .any(|(constant, since)| *constant == name && *since <= target)
+        || RESOURCES.contains(&name)
+        || CONTENT.contains(&name)
+}
+
+/// Whether `@name` can be read with `sensor`
+pub fn is_sensor_property(name: &str, target: TargetVersion) -> bool {
+    SENSOR_PROPERTIES
+        .iter()
+        .any(|(property, since)| *property == name && *since <= target)
+        || RESOURCES.contains(&name)
+}
+
+/// Number of arguments the `control` subcommand takes, or None if there's no such subcommand
+pub fn control_command_arity(name: &str, target: TargetVersion) -> Option<usize> {
+    CONTROL_COMMANDS
+        .iter()
+        .find(|(command, _, since)| *command == name && *since <= target)
+        .map(|(_, arity, _)| *arity)
+}
+
+/// `control` subcommands of the target version, for error messages
+pub fn control_commands(target: TargetVersion) -> Vec<&'static str> {
+    CONTROL_COMMANDS
+        .iter()
+        .filter(|(_, _, since)| *since <= target)
+        .map(|(command, _, _)| *command)
+        .collect()
+}
+
+/// Whether the name looks like a name the processor gives to linked buildings, which is the last
+/// word of the block name followed by a number, like `cell1` or `switch12`
+pub fn is_link_name(name: &str) -> bool {
+    let digits_start = name.trim_end_matches(|ch: char| ch.is_ascii_digit()).len();
+    digits_start > 0
+        && digits_start < name.len()
+        && name[..digits_start]
+            .chars()
+            .all(|ch| ch.is_ascii_lowercase())
+}
+
+/// Memory capacity of a linked cell or bank, judging by its name
+pub fn memory_capacity(cell: &str) -> Option<usize> {
+    if cell.starts_with("cell") {
+        Some(64)
+    } else if cell.starts_with("bank") {
+        Some(512)
+    } else {
+        None
+    }
+}
+
+/// Closest known constant to the misspelled one
+pub fn suggest_constant(name: &str, target: TargetVersion) -> Option<&'static str> {
+    let candidates = GLOBALS
+        .iter()
+        .chain(SENSOR_PROPERTIES)
+        .filter(|(_, since)| *since <= target)
+        .map(|(constant, _)| *constant)
+        .chain(RESOURCES.iter().copied())
+        .chain(CONTENT.iter().copied());
+    closest(name, candidates)
+}
+
+/// Closest sensor property to the misspelled one
+pub fn suggest_sensor_property(name: &str, target: TargetVersion) -> Option<&'static str> {
+    let candidates = SENSOR_PROPERTIES
+        .iter()
+        .filter(|(_, since)| *since <= target)
+        .map(|(property, _)| *property)
+        .chain(RESOURCES.iter().copied());
+    closest(name, candidates)
+}
+
+/// Candidate with the smallest edit distance, if it's close enough to be a typo
+fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(2);
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, candidate)| (*distance, candidate.len().abs_diff(name.len())))
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance, ignoring case
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_ch) in a.iter().enumerate() {
+        let mut current_row = vec![i + 1];
+        for (j, b_ch) in b.iter().enumerate() {
+            let substitution = previous_row[j] + usize::from(a_ch != b_ch);
+            current_row.push(
+                substitution
+                    .min(previous_row[j + 1] + 1)
+                    .min(current_row[j] + 1),
+            );
+        }
+        previous_row = current_row;
+    }
+    previous_row[b.len()]
+}
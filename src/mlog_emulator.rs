@@ -0,0 +1,905 @@
+use std::collections::BTreeMap;
+
+use crate::mlog_constants::{is_link_name, memory_capacity};
+
+/// Milliseconds per tick, the game runs at 60 ticks per second
+const TICK_MILLIS: f64 = 1000.0 / 60.0;
+
+/// Most characters the print buffer holds, the rest is dropped like in the game
+const PRINT_BUFFER_CAPACITY: usize = 400;
+
+/// Processor block, which decides how many instructions are executed per tick
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Processor {
+    /// 2 instructions per tick
+    Micro,
+    /// 8 instructions per tick
+    Logic,
+    /// 25 instructions per tick
+    Hyper,
+}
+
+impl Processor {
+    pub fn instructions_per_tick(self) -> usize {
+        match self {
+            Processor::Micro => 2,
+            Processor::Logic => 8,
+            Processor::Hyper => 25,
+        }
+    }
+}
+
+/// Value of mlog variable
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Number(f64),
+    String(String),
+    /// Building, unit or content, by its name like `cell1` or `@copper`
+    Object(String),
+}
+
+impl Value {
+    /// Number stored by `op` and similar instructions. Results which aren't finite become `null`.
+    pub fn number(number: f64) -> Self {
+        if number.is_finite() {
+            Value::Number(number)
+        } else {
+            Value::Null
+        }
+    }
+
+    /// Numeric value, which is 1 for strings and objects and 0 for `null`
+    pub fn num(&self) -> f64 {
+        match self {
+            Value::Null => 0.0,
+            Value::Number(number) => *number,
+            Value::String(_) | Value::Object(_) => 1.0,
+        }
+    }
+
+    fn is_object(&self) -> bool {
+        !matches!(self, Value::Number(_))
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            //Numbers close to an integer are printed without the fraction
+            Value::Number(number) if (number - number.round()).abs() < 0.00001 => {
+                write!(f, "{}", number.round() as i64)
+            }
+            Value::Number(number) => write!(f, "{}", number),
+            Value::String(string) => write!(f, "{}", string),
+            Value::Object(name) => write!(f, "{}", name.trim_start_matches('@')),
+        }
+    }
+}
+
+/// Surroundings of the emulated processor, which tests replace with mocks. Every method has a
+/// default finding nothing and doing nothing, so mocks implement only what the program uses.
+pub trait World {
+    /// Property of a building, unit or content, read by `sensor`
+    fn sensor(&mut self, _object: &Value, _property: &Value) -> Value {
+        Value::Null
+    }
+
+    /// Unit found by `radar` of the building, matching all three target filters
+    fn radar(&mut self, _building: &Value, _targets: [&str; 3], _sort: &str, _order: f64) -> Value {
+        Value::Null
+    }
+
+    /// Next unit of the type, bound by `ubind`
+    fn ubind(&mut self, _unit_type: &Value) -> Value {
+        Value::Null
+    }
+
+    /// `ucontrol` command given to the bound unit. The result is stored by commands with an
+    /// output, like `within`.
+    fn ucontrol(&mut self, _unit: &Value, _command: &str, _args: &[Value]) -> Value {
+        Value::Null
+    }
+
+    /// `control` command given to a linked building
+    fn control(&mut self, _building: &Value, _command: &str, _args: &[Value]) {}
+}
+
+/// World with nothing to find, where commands have no effect
+pub struct EmptyWorld;
+
+impl World for EmptyWorld {}
+
+/// Whether the processor can execute next instruction in the same tick
+enum Flow {
+    Continue,
+    Yield,
+}
+
+/// Splits line of mlog code into tokens, keeping string literals whole
+fn tokenize_line(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::<String>::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&ch) = chars.peek() {
+        match ch {
+            ' ' | '\t' => {
+                chars.next();
+            }
+            '#' => break,
+            '"' => {
+                let mut token = String::from(chars.next().unwrap());
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(ch) => token.push(ch),
+                        None => return Err(format!("Unterminated string in \"{}\"", line)),
+                    }
+                }
+                token.push('"');
+                tokens.push(token);
+            }
+            _ => {
+                let mut token = String::new();
+                while let Some(&ch) = chars.peek().filter(|ch| !matches!(ch, ' ' | '\t' | '#')) {
+                    token.push(ch);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Value of literal operand, if it is one
+fn literal(token: &str) -> Option<Value> {
+    if let Some(string) = token.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        return Some(Value::String(string.replace("\\n", "\n")));
+    }
+    if let Some(color) = token.strip_prefix('%') {
+        let rgba = match color.len() {
+            6 => u32::from_str_radix(color, 16).ok()? << 8 | 0xff,
+            8 => u32::from_str_radix(color, 16).ok()?,
+            _ => return None,
+        };
+        return Some(Value::Number(f64::from_bits(rgba as u64)));
+    }
+    match token {
+        "null" => return Some(Value::Null),
+        "true" => return Some(Value::Number(1.0)),
+        "false" => return Some(Value::Number(0.0)),
+        _ => {}
+    }
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+    let number = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|ch: char| ch.is_ascii_digit() || ch == '.') {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+    Some(Value::Number(if negative { -number } else { number }))
+}
+
+/// Result of `op` which doesn't need the processor state
+fn operation(operation: &str, a: f64, b: f64) -> Option<f64> {
+    Some(match operation {
+        "add" => a + b,
+        "sub" => a - b,
+        "mul" => a * b,
+        "div" => a / b,
+        "idiv" => (a / b).floor(),
+        "mod" => a % b,
+        "emod" => (a % b + b) % b,
+        "pow" => a.powf(b),
+        "land" => (a != 0.0 && b != 0.0) as i64 as f64,
+        "lessThan" => (a < b) as i64 as f64,
+        "lessThanEq" => (a <= b) as i64 as f64,
+        "greaterThan" => (a > b) as i64 as f64,
+        "greaterThanEq" => (a >= b) as i64 as f64,
+        "shl" => ((a as i64) << (b as i64 & 63)) as f64,
+        "shr" => ((a as i64) >> (b as i64 & 63)) as f64,
+        "ushr" => ((a as i64 as u64) >> (b as i64 & 63)) as f64,
+        "or" => ((a as i64) | (b as i64)) as f64,
+        "and" => ((a as i64) & (b as i64)) as f64,
+        "xor" => ((a as i64) ^ (b as i64)) as f64,
+        "not" => !(a as i64) as f64,
+        "max" => a.max(b),
+        "min" => a.min(b),
+        "angle" => b.atan2(a).to_degrees().rem_euclid(360.0),
+        "angleDiff" => {
+            let diff = (a - b).rem_euclid(360.0);
+            diff.min(360.0 - diff)
+        }
+        "len" => a.hypot(b),
+        "abs" => a.abs(),
+        "sign" => {
+            if a == 0.0 {
+                0.0
+            } else {
+                a.signum()
+            }
+        }
+        "log" => a.ln(),
+        "logn" => a.ln() / b.ln(),
+        "log10" => a.log10(),
+        "floor" => a.floor(),
+        "ceil" => a.ceil(),
+        "round" => a.round(),
+        "sqrt" => a.sqrt(),
+        "sin" => a.to_radians().sin(),
+        "cos" => a.to_radians().cos(),
+        "tan" => a.to_radians().tan(),
+        "asin" => a.asin().to_degrees(),
+        "acos" => a.acos().to_degrees(),
+        "atan" => a.atan().to_degrees(),
+        _ => return None,
+    })
+}
+
+/// Whether the values are equal, comparing objects by identity and anything else by number
+fn equal(a: &Value, b: &Value) -> bool {
+    if a.is_object() && b.is_object() {
+        a == b
+    } else {
+        (a.num() - b.num()).abs() < 0.000001
+    }
+}
+
+/// Executes mlog code the way a processor does, tick by tick
+pub struct Emulator {
+    instructions: Vec<Vec<String>>,
+    /// Instruction index of each label
+    labels: BTreeMap<String, usize>,
+    variables: BTreeMap<String, Value>,
+    /// Index of the next instruction
+    counter: usize,
+    /// Buildings linked to the processor, which are the ones the code refers to by link name
+    links: Vec<String>,
+    instructions_per_tick: usize,
+    print_buffer: String,
+    /// Text of each message block, as flushed by `printflush`
+    messages: BTreeMap<String, String>,
+    /// Slots of each memory cell or bank
+    memory: BTreeMap<String, Vec<f64>>,
+    ticks: usize,
+    executed: usize,
+    /// Time in milliseconds `wait` is waiting for
+    wait_until: f64,
+    stopped: bool,
+    /// State of `rand` operation
+    seed: u64,
+    pub world: Box<dyn World>,
+}
+
+impl Emulator {
+    pub fn new(code: &str, processor: Processor) -> Result<Self, String> {
+        let mut instructions = Vec::<Vec<String>>::new();
+        let mut labels = BTreeMap::<String, usize>::new();
+        for line in code.lines() {
+            let tokens = tokenize_line(line)?;
+            match tokens.as_slice() {
+                [] => {}
+                [label] if label.ends_with(':') => {
+                    labels.insert(label.trim_end_matches(':').into(), instructions.len());
+                }
+                _ => instructions.push(tokens),
+            }
+        }
+
+        let mut links = Vec::<String>::new();
+        for instruction in &instructions {
+            //Arguments taking a building
+            let buildings: &[usize] = match instruction[0].as_str() {
+                "printflush" | "drawflush" => &[1],
+                "read" | "write" | "sensor" => &[2],
+                "control" => &[2],
+                "radar" => &[5],
+                _ => &[],
+            };
+            for i in buildings {
+                if let Some(name) = instruction.get(*i).filter(|name| is_link_name(name)) {
+                    if !links.contains(name) {
+                        links.push(name.clone());
+                    }
+                }
+            }
+        }
+        links.sort();
+
+        Ok(Self {
+            instructions,
+            labels,
+            variables: BTreeMap::new(),
+            counter: 0,
+            links,
+            instructions_per_tick: processor.instructions_per_tick(),
+            print_buffer: String::new(),
+            messages: BTreeMap::new(),
+            memory: BTreeMap::new(),
+            ticks: 0,
+            executed: 0,
+            wait_until: 0.0,
+            stopped: false,
+            seed: 0x2545f4914f6cdd1d,
+            world: Box::new(EmptyWorld),
+        })
+    }
+
+    /// Value of the variable or constant, as the code would read it
+    pub fn variable(&self, name: &str) -> Value {
+        if let Some(value) = self.variables.get(name) {
+            return value.clone();
+        }
+        if let Some(value) = literal(name) {
+            return value;
+        }
+        let time = self.ticks as f64 * TICK_MILLIS;
+        match name {
+            "@counter" => Value::Number(self.counter as f64),
+            "@time" => Value::Number(time),
+            "@tick" => Value::Number(self.ticks as f64),
+            "@second" => Value::Number(time / 1000.0),
+            "@ipt" => Value::Number(self.instructions_per_tick as f64),
+            "@links" => Value::Number(self.links.len() as f64),
+            "@this" => Value::Object("processor1".into()),
+            "@thisx" | "@thisy" => Value::Number(0.0),
+            "@unit" => Value::Null,
+            "@pi" => Value::Number(std::f64::consts::PI),
+            "@e" => Value::Number(std::f64::consts::E),
+            "@degToRad" => Value::Number(std::f64::consts::PI / 180.0),
+            "@radToDeg" => Value::Number(180.0 / std::f64::consts::PI),
+            constant if constant.starts_with('@') => Value::Object(constant.into()),
+            link if self.links.iter().any(|linked| linked == link) => Value::Object(link.into()),
+            _ => Value::Null,
+        }
+    }
+
+    /// Text the message block shows
+    pub fn message(&self, building: &str) -> Option<&str> {
+        self.messages.get(building).map(String::as_str)
+    }
+
+    /// Text printed, but not flushed to a message block yet
+    pub fn printed(&self) -> &str {
+        &self.print_buffer
+    }
+
+    /// Slots of the memory cell or bank, if the code accessed it
+    pub fn memory(&self, cell: &str) -> Option<&[f64]> {
+        self.memory.get(cell).map(Vec::as_slice)
+    }
+
+    /// Buildings linked to the processor
+    pub fn links(&self) -> &[String] {
+        &self.links
+    }
+
+    pub fn ticks(&self) -> usize {
+        self.ticks
+    }
+
+    /// Number of instructions executed so far
+    pub fn executed(&self) -> usize {
+        self.executed
+    }
+
+    /// Whether the code executed `stop`
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Runs the given number of ticks
+    pub fn run(&mut self, ticks: usize) -> Result<(), String> {
+        for _ in 0..ticks {
+            self.run_tick()?;
+        }
+        Ok(())
+    }
+
+    /// Executes instructions of one tick, unless the code waits or stopped
+    pub fn run_tick(&mut self) -> Result<(), String> {
+        if !self.stopped && self.ticks as f64 * TICK_MILLIS >= self.wait_until {
+            for _ in 0..self.instructions_per_tick {
+                if let Flow::Yield = self.step()? {
+                    break;
+                }
+            }
+        }
+        self.ticks += 1;
+        Ok(())
+    }
+
+    fn set(&mut self, name: &str, value: Value) {
+        if name == "@counter" {
+            let counter = value.num();
+            self.counter = if counter >= 0.0 { counter as usize } else { 0 };
+        } else if !name.starts_with('@') && literal(name).is_none() {
+            self.variables.insert(name.into(), value);
+        }
+    }
+
+    fn condition(&self, condition: &str, a: &Value, b: &Value) -> Result<bool, String> {
+        Ok(match condition {
+            "always" => true,
+            "equal" => equal(a, b),
+            "notEqual" => !equal(a, b),
+            "strictEqual" => a == b,
+            _ => {
+                let result = operation(condition, a.num(), b.num())
+                    .ok_or_else(|| format!("Unknown condition \"{}\"", condition))?;
+                result != 0.0
+            }
+        })
+    }
+
+    /// Slots of the memory cell the value refers to
+    fn memory_cell(&mut self, cell: &Value) -> Result<&mut Vec<f64>, String> {
+        let Value::Object(name) = cell else {
+            return Err(format!("{} is not a memory cell", cell));
+        };
+        let capacity =
+            memory_capacity(name).ok_or_else(|| format!("{} is not a memory cell", name))?;
+        Ok(self
+            .memory
+            .entry(name.clone())
+            .or_insert_with(|| vec![0.0; capacity]))
+    }
+
+    /// Executes the next instruction
+    fn step(&mut self) -> Result<Flow, String> {
+        if self.counter >= self.instructions.len() {
+            self.counter = 0;
+        }
+        let Some(instruction) = self.instructions.get(self.counter).cloned() else {
+            return Ok(Flow::Yield);
+        };
+        let line = self.counter;
+        self.counter += 1;
+        self.executed += 1;
+        let arg = |i: usize| -> Result<&str, String> {
+            instruction.get(i).map(String::as_str).ok_or_else(|| {
+                format!(
+                    "Instruction {} \"{}\" is missing arguments",
+                    line,
+                    instruction.join(" ")
+                )
+            })
+        };
+
+        match arg(0)? {
+            "set" => self.set(arg(1)?, self.variable(arg(2)?)),
+            "op" => {
+                let a = self.variable(arg(3)?);
+                let b = instruction
+                    .get(4)
+                    .map_or(Value::Null, |operand| self.variable(operand));
+                let result = match arg(1)? {
+                    "equal" => Value::Number(equal(&a, &b) as i64 as f64),
+                    "notEqual" => Value::Number(!equal(&a, &b) as i64 as f64),
+                    "strictEqual" => Value::Number((a == b) as i64 as f64),
+                    "rand" => {
+                        //xorshift
+                        self.seed ^= self.seed << 13;
+                        self.seed ^= self.seed >> 7;
+                        self.seed ^= self.seed << 17;
+                        Value::number(a.num() * (self.seed >> 11) as f64 / (1u64 << 53) as f64)
+                    }
+                    name => Value::number(
+                        operation(name, a.num(), b.num())
+                            .ok_or_else(|| format!("Unknown operation \"{}\"", name))?,
+                    ),
+                };
+                self.set(arg(2)?, result);
+            }
+            "select" => {
+                let condition =
+                    self.condition(arg(2)?, &self.variable(arg(3)?), &self.variable(arg(4)?))?;
+                let value = self.variable(arg(if condition { 5 } else { 6 })?);
+                self.set(arg(1)?, value);
+            }
+            "jump" => {
+                let target = arg(1)?;
+                let condition = arg(2)?;
+                let taken = condition == "always"
+                    || self.condition(
+                        condition,
+                        &self.variable(arg(3)?),
+                        &self.variable(arg(4)?),
+                    )?;
+                if taken {
+                    self.counter = match self.labels.get(target) {
+                        Some(index) => *index,
+                        None => target
+                            .parse()
+                            .map_err(|_| format!("Label {} is not defined", target))?,
+                    };
+                }
+            }
+            "end" => {
+                self.counter = self.instructions.len();
+            }
+            "stop" => {
+                self.counter = line;
+                self.stopped = true;
+                return Ok(Flow::Yield);
+            }
+            "wait" => {
+                let seconds = self.variable(arg(1)?).num();
+                self.wait_until = self.ticks as f64 * TICK_MILLIS + seconds * 1000.0;
+                return Ok(Flow::Yield);
+            }
+            "noop" => {}
+            "print" => {
+                let text = self.variable(arg(1)?).to_string();
+                self.print_buffer.push_str(&text);
+                if let Some((cut, _)) = self.print_buffer.char_indices().nth(PRINT_BUFFER_CAPACITY)
+                {
+                    self.print_buffer.truncate(cut);
+                }
+            }
+            "printflush" => {
+                if let Value::Object(building) = self.variable(arg(1)?) {
+                    let text = std::mem::take(&mut self.print_buffer);
+                    self.messages.insert(building, text);
+                }
+            }
+            "read" => {
+                let address = self.variable(arg(3)?).num();
+                let cell = self.variable(arg(2)?);
+                let value = self
+                    .memory_cell(&cell)?
+                    .get(address as usize)
+                    .filter(|_| address >= 0.0)
+                    .copied();
+                //Out of bounds reads leave the variable as it is
+                if let Some(value) = value {
+                    self.set(arg(1)?, Value::number(value));
+                }
+            }
+            "write" => {
+                let value = self.variable(arg(1)?).num();
+                let address = self.variable(arg(3)?).num();
+                let cell = self.variable(arg(2)?);
+                if let Some(slot) = self
+                    .memory_cell(&cell)?
+                    .get_mut(address as usize)
+                    .filter(|_| address >= 0.0)
+                {
+                    *slot = value;
+                }
+            }
+            "getlink" => {
+                let index = self.variable(arg(2)?).num();
+                let link = self
+                    .links
+                    .get(index as usize)
+                    .filter(|_| index >= 0.0)
+                    .map_or(Value::Null, |link| Value::Object(link.clone()));
+                self.set(arg(1)?, link);
+            }
+            "sensor" => {
+                let object = self.variable(arg(2)?);
+                let property = self.variable(arg(3)?);
+                let value = self.world.sensor(&object, &property);
+                self.set(arg(1)?, value);
+            }
+            "radar" => {
+                let building = self.variable(arg(5)?);
+                let order = self.variable(arg(6)?).num();
+                let unit = self
+                    .world
+                    .radar(&building, [arg(1)?, arg(2)?, arg(3)?], arg(4)?, order);
+                self.set(arg(7)?, unit);
+            }
+            "ubind" => {
+                let unit_type = self.variable(arg(1)?);
+                let unit = self.world.ubind(&unit_type);
+                self.variables.insert("@unit".into(), unit);
+            }
+            "ucontrol" => {
+                let command = arg(1)?;
+                let args: Vec<Value> = instruction[2..]
+                    .iter()
+                    .map(|operand| self.variable(operand))
+                    .collect();
+                let unit = self.variable("@unit");
+                let result = self.world.ucontrol(&unit, command, &args);
+                if command == "within" {
+                    self.set(arg(5)?, result);
+                }
+            }
+            "control" => {
+                let command = arg(1)?;
+                let building = self.variable(arg(2)?);
+                let args: Vec<Value> = instruction[3..]
+                    .iter()
+                    .map(|operand| self.variable(operand))
+                    .collect();
+                self.world.control(&building, command, &args);
+            }
+            other => {
+                return Err(format!(
+                    "Instruction {} \"{}\" isn't supported by the emulator",
+                    line, other
+                ))
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+impl std::fmt::Display for Emulator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} instructions executed in {} ticks{}",
+            self.executed,
+            self.ticks,
+            if self.stopped { ", stopped" } else { "" }
+        )?;
+        for (building, text) in &self.messages {
+            writeln!(f, "{}:\n{}", building, text)?;
+        }
+        if !self.print_buffer.is_empty() {
+            writeln!(f, "Not flushed:\n{}", self.print_buffer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: &str, processor: Processor, ticks: usize) -> Emulator {
+        let mut emulator = Emulator::new(code, processor).unwrap();
+        emulator.run(ticks).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn op_set_and_jump() {
+        let emulator = run(
+            "
+            set i 0
+            set sum 0
+            loop:
+            op add sum sum i
+            op add i i 1
+            jump loop lessThan i 5
+            stop
+            ",
+            Processor::Hyper,
+            10,
+        );
+        assert_eq!(emulator.variable("sum"), Value::Number(10.0));
+        assert_eq!(emulator.variable("i"), Value::Number(5.0));
+        assert!(emulator.is_stopped());
+        assert_eq!(emulator.executed(), 2 + 5 * 3 + 1);
+    }
+
+    #[test]
+    fn counter_writes_jump() {
+        //Jump table entry picked by `x`, then call and return through a return address
+        let emulator = run(
+            "
+            set x 2
+            op add @counter @counter x
+            set r 0
+            set r 1
+            set r 2
+            op add ret_addr_f @counter 1
+            jump f always
+            stop
+            f:
+            set called 1
+            set @counter ret_addr_f
+            ",
+            Processor::Hyper,
+            1,
+        );
+        assert_eq!(emulator.variable("r"), Value::Number(2.0));
+        assert_eq!(emulator.variable("called"), Value::Number(1.0));
+        assert!(emulator.is_stopped());
+    }
+
+    #[test]
+    fn end_and_end_of_code_wrap_around() {
+        let emulator = run("op add n n 1\nend\nset never 1\n", Processor::Logic, 1);
+        assert_eq!(emulator.variable("n"), Value::Number(4.0));
+        assert_eq!(emulator.variable("never"), Value::Null);
+
+        let emulator = run("op add n n 1\nop add m m 1\n", Processor::Logic, 1);
+        assert_eq!(emulator.variable("n"), Value::Number(4.0));
+        assert_eq!(emulator.variable("m"), Value::Number(4.0));
+    }
+
+    #[test]
+    fn wait_pauses_for_the_given_time() {
+        let mut emulator = Emulator::new("op add n n 1\nwait 0.5\n", Processor::Hyper).unwrap();
+        //`wait` yields the rest of the tick, and 0.5 seconds are 30 ticks
+        emulator.run(30).unwrap();
+        assert_eq!(emulator.variable("n"), Value::Number(1.0));
+        emulator.run(1).unwrap();
+        assert_eq!(emulator.variable("n"), Value::Number(2.0));
+
+        //Waiting starts from the tick it was reached in
+        emulator.run(29).unwrap();
+        assert_eq!(emulator.variable("n"), Value::Number(2.0));
+        emulator.run(1).unwrap();
+        assert_eq!(emulator.variable("n"), Value::Number(3.0));
+        assert_eq!(emulator.executed(), 6);
+    }
+
+    #[test]
+    fn print_buffer_is_flushed_to_message_block() {
+        let emulator = run(
+            "
+            print \"a \"
+            print 1.5
+            print \" \"
+            print x
+            print 3
+            printflush message1
+            print \"rest\"
+            printflush nothing
+            stop
+            ",
+            Processor::Hyper,
+            1,
+        );
+        assert_eq!(emulator.message("message1"), Some("a 1.5 null3"));
+        //Flushing to something other than a building keeps the buffer
+        assert_eq!(emulator.printed(), "rest");
+        assert_eq!(emulator.links(), ["message1"]);
+    }
+
+    #[test]
+    fn print_buffer_is_limited() {
+        let emulator = run("print \"0123456789\"\n", Processor::Hyper, 2);
+        assert_eq!(emulator.printed().len(), PRINT_BUFFER_CAPACITY);
+    }
+
+    #[test]
+    fn memory_cells_keep_written_values() {
+        let emulator = run(
+            "
+            write 5 cell1 3
+            read x cell1 3
+            set y 7
+            read y cell1 64
+            write 1 bank1 511
+            stop
+            ",
+            Processor::Hyper,
+            1,
+        );
+        let cell = emulator.memory("cell1").unwrap();
+        assert_eq!(cell.len(), 64);
+        assert_eq!(cell[3], 5.0);
+        assert_eq!(emulator.variable("x"), Value::Number(5.0));
+        //Out of bounds read leaves the variable as it is
+        assert_eq!(emulator.variable("y"), Value::Number(7.0));
+        let bank = emulator.memory("bank1").unwrap();
+        assert_eq!((bank.len(), bank[511]), (512, 1.0));
+        assert_eq!(emulator.links(), ["bank1", "cell1"]);
+    }
+
+    #[test]
+    fn memory_access_to_other_building_fails() {
+        let mut emulator = Emulator::new("write 1 message1 0\n", Processor::Micro).unwrap();
+        assert!(emulator.run(1).is_err());
+    }
+
+    #[test]
+    fn processors_execute_their_instructions_per_tick() {
+        for (processor, per_tick) in [
+            (Processor::Micro, 2.0),
+            (Processor::Logic, 8.0),
+            (Processor::Hyper, 25.0),
+        ] {
+            let emulator = run("op add n n 1\n", processor, 3);
+            assert_eq!(emulator.variable("n"), Value::Number(3.0 * per_tick));
+            assert_eq!(emulator.variable("@ipt"), Value::Number(per_tick));
+            assert_eq!(emulator.ticks(), 3);
+        }
+    }
+
+    /// World with a single flare and poly, which records the commands given
+    struct MockWorld {
+        commands: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    }
+
+    impl MockWorld {
+        fn record(&self, target: &Value, command: &str, args: &[Value]) {
+            let args: Vec<String> = args.iter().map(Value::to_string).collect();
+            self.commands
+                .borrow_mut()
+                .push(format!("{} {} {}", target, command, args.join(" ")));
+        }
+    }
+
+    impl World for MockWorld {
+        fn sensor(&mut self, object: &Value, property: &Value) -> Value {
+            match (object, property) {
+                (Value::Object(unit), Value::Object(property))
+                    if unit == "poly1" && property == "@health" =>
+                {
+                    Value::Number(400.0)
+                }
+                _ => Value::Null,
+            }
+        }
+
+        fn radar(&mut self, building: &Value, targets: [&str; 3], sort: &str, order: f64) -> Value {
+            if *building == Value::Object("turret1".into())
+                && targets == ["enemy", "any", "any"]
+                && sort == "distance"
+                && order == 1.0
+            {
+                Value::Object("flare1".into())
+            } else {
+                Value::Null
+            }
+        }
+
+        fn ubind(&mut self, unit_type: &Value) -> Value {
+            if *unit_type == Value::Object("@poly".into()) {
+                Value::Object("poly1".into())
+            } else {
+                Value::Null
+            }
+        }
+
+        fn ucontrol(&mut self, unit: &Value, command: &str, args: &[Value]) -> Value {
+            self.record(unit, command, args);
+            Value::Number(1.0)
+        }
+
+        fn control(&mut self, building: &Value, command: &str, args: &[Value]) {
+            self.record(building, command, args);
+        }
+    }
+
+    #[test]
+    fn world_mock_answers_unit_and_building_instructions() {
+        let commands = std::rc::Rc::default();
+        let mut emulator = Emulator::new(
+            "
+            ubind @poly
+            sensor health @unit @health
+            radar enemy any any distance turret1 1 target
+            ucontrol move 10 20 0 0 0
+            ucontrol within 10 20 5 inside 0
+            control enabled door1 0 0 0 0
+            stop
+            ",
+            Processor::Hyper,
+        )
+        .unwrap();
+        emulator.world = Box::new(MockWorld {
+            commands: std::rc::Rc::clone(&commands),
+        });
+        emulator.run(1).unwrap();
+
+        assert_eq!(emulator.variable("@unit"), Value::Object("poly1".into()));
+        assert_eq!(emulator.variable("health"), Value::Number(400.0));
+        assert_eq!(emulator.variable("target"), Value::Object("flare1".into()));
+        assert_eq!(emulator.variable("inside"), Value::Number(1.0));
+        assert_eq!(
+            *commands.borrow(),
+            [
+                "poly1 move 10 20 0 0 0",
+                "poly1 within 10 20 5 null 0",
+                "door1 enabled 0 0 0 0",
+            ]
+        );
+    }
+}
@@ -1,57 +1,1211 @@
+use crate::mlog_constants::*;
+use crate::mlog_inliner::{call_sites, inline_calls, inlined_size};
+use crate::mlog_ir::{Code, Instruction, Line, Opcode, Operand};
+use crate::mlog_optimizer::{
+    convert_tail_calls, eliminate_common_subexpressions, hoist_loop_invariants, peephole,
+    propagate_copies, remove_stores,
+};
+use crate::mlog_register_allocator::allocate_registers;
 use crate::parser::*;
 
-use lazy_static;
-
 #[derive(Debug)]
 struct VariableScope {
     variables: Vec<LocalVariableAST>,
+    arrays: Vec<ArrayDeclarationAST>,
     mangle: String,
 }
 
 fn mangle_variable(
     variable_name: &str,
-    global_variables: &std::collections::BTreeMap<String, GlobalVariableAST>,
+    program_ast: &ProgramAST,
     local_variables: &[VariableScope],
-) -> Option<String> {
+) -> Result<String, String> {
     if variable_name.chars().next().unwrap_or('@') == '@' {
-        return Some(variable_name.into());
+        return Ok(variable_name.into());
+    }
+
+    for lvs in local_variables.iter().rev() {
+        for lv in &lvs.variables {
+            if lv.name == variable_name {
+                let mut mangled_name = String::from(variable_name);
+                mangled_name.push_str(&lvs.mangle);
+                return Ok(mangled_name);
+            }
+        }
+    }
+
+    if program_ast.variables.contains_key(variable_name) {
+        Ok(variable_name.into())
+    } else if let Some(link) = program_ast.links.get(variable_name) {
+        Ok(link.link_name.clone())
+    } else if is_link_name(variable_name) {
+        //Undeclared links are reported by check_links
+        Ok(variable_name.into())
+    } else {
+        Err(format!("Variable {} not defined", variable_name))
+    }
+}
+
+impl VariableScope {
+    fn new(mangle: &str) -> Self {
+        Self {
+            variables: Vec::new(),
+            arrays: Vec::new(),
+            mangle: mangle.into(),
+        }
+    }
+
+    fn mangled_names(&self) -> impl Iterator<Item = String> + '_ {
+        self.variables
+            .iter()
+            .map(|variable| format!("{}{}", variable.name, self.mangle))
+    }
+
+    /// Declares the variable, along with a variable for each member if it's a struct
+    fn declare(&mut self, variable: &LocalVariableAST, program_ast: &ProgramAST) {
+        self.variables.push(variable.clone());
+        if let Some(members) = variable
+            .type_name
+            .as_ref()
+            .and_then(|type_name| program_ast.struct_members(type_name))
+        {
+            for (member_name, member_type) in members {
+                self.variables.push(LocalVariableAST {
+                    name: format!("{}.{}", variable.name, member_name),
+                    type_name: member_type,
+                });
+            }
+        }
+    }
+}
+
+/// Struct type of the variable, or None if it isn't a struct
+fn struct_type_of(
+    variable_name: &str,
+    program_ast: &ProgramAST,
+    local_variables: &[VariableScope],
+) -> Option<String> {
+    let type_name = local_variables
+        .iter()
+        .rev()
+        .find_map(|lvs| {
+            lvs.variables
+                .iter()
+                .rev()
+                .find(|lv| lv.name == variable_name)
+        })
+        .map(|lv| lv.type_name.clone())
+        .unwrap_or_else(|| {
+            program_ast
+                .variables
+                .get(variable_name)
+                .and_then(|global_var| global_var.type_name.clone())
+        })?;
+    program_ast
+        .structs
+        .contains_key(&type_name)
+        .then_some(type_name)
+}
+
+/// Returns name of the struct variable the value refers to, if it's of the expected struct type
+fn expect_struct_value<'a>(
+    value: &'a ExpressionASTNode,
+    type_name: &str,
+    program_ast: &ProgramAST,
+    local_variables: &[VariableScope],
+) -> Result<&'a str, String> {
+    match value {
+        ExpressionASTNode::VariableReference(vr)
+            if struct_type_of(vr, program_ast, local_variables).as_deref() == Some(type_name) =>
+        {
+            Ok(vr)
+        }
+        _ => Err(format!(
+            "Expected variable of struct type {}, but got {:?}",
+            type_name, value
+        )),
+    }
+}
+
+/// Copies struct field-by-field
+fn generate_struct_copy(
+    target: &str,
+    source: &str,
+    type_name: &str,
+    program_ast: &ProgramAST,
+    local_variables: &[VariableScope],
+    result_code: &mut Code,
+) -> Result<(), String> {
+    for field in program_ast.struct_fields(type_name).unwrap() {
+        result_code.push(Instruction::set(
+            &mangle_variable(
+                &format!("{}.{}", target, field),
+                program_ast,
+                local_variables,
+            )?,
+            &mangle_variable(
+                &format!("{}.{}", source, field),
+                program_ast,
+                local_variables,
+            )?,
+        ));
+    }
+    Ok(())
+}
+
+/// Writes struct fields into consecutive memory slots or reads them back, depending on the
+/// opcode (`write` or `read`)
+fn generate_struct_memory_transfer(
+    opcode: Opcode,
+    args: &[ExpressionASTNode],
+    program_ast: &ProgramAST,
+    local_variables: &mut Vec<VariableScope>,
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) -> Result<(), String> {
+    let function_name = format!("{}Struct", opcode.name());
+    if args.len() != 3 {
+        return Err(format!(
+            "{} function takes struct variable, memory cell and offset as arguments",
+            function_name
+        ));
+    }
+    let ExpressionASTNode::VariableReference(struct_var) = &args[0] else {
+        return Err(format!(
+            "1st argument to {} function must be struct variable",
+            function_name
+        ));
+    };
+    let type_name = struct_type_of(struct_var, program_ast, local_variables)
+        .ok_or_else(|| format!("{} is not a struct variable", struct_var))?;
+    let ExpressionASTNode::VariableReference(cell) = &args[1] else {
+        return Err(format!(
+            "2nd argument to {} function must be memory cell",
+            function_name
+        ));
+    };
+    let cell = link_name(cell, program_ast);
+    let fields = program_ast.struct_fields(&type_name).unwrap();
+    let field_mangled = |field: &str, local_variables: &[VariableScope]| {
+        mangle_variable(
+            &format!("{}.{}", struct_var, field),
+            program_ast,
+            local_variables,
+        )
+    };
+
+    if let ExpressionASTNode::NumberLiteral(offset) = &args[2] {
+        if offset.fract() != 0.0 || *offset < 0.0 {
+            return Err(format!("Invalid struct offset {}", offset));
+        }
+        let offset = *offset as usize;
+        if let Some(capacity) = memory_capacity(cell) {
+            if offset + fields.len() > capacity {
+                return Err(format!(
+                    "Struct {} needs slots {}..{} of {}, which has only {} slots",
+                    struct_var,
+                    offset,
+                    offset + fields.len(),
+                    cell,
+                    capacity
+                ));
+            }
+        }
+        for (i, field) in fields.iter().enumerate() {
+            result_code.push(Instruction::new(
+                opcode,
+                vec![
+                    Operand::value(&field_mangled(field, local_variables)?),
+                    Operand::value(cell),
+                    Operand::value(&(offset + i).to_string()),
+                ],
+            ));
+        }
+        return Ok(());
+    }
+
+    let offset_operand = make_operand(&args[2], program_ast, local_variables, result_code, ctx)?;
+    let address_tmp = make_tmp_variable(&None, program_ast, local_variables, result_code, ctx)?;
+    let address_mangled = mangle_variable(&address_tmp, program_ast, local_variables)?;
+    for (i, field) in fields.iter().enumerate() {
+        let address = if i == 0 {
+            offset_operand.clone()
+        } else {
+            result_code.push(Instruction::op(
+                "add",
+                &address_mangled,
+                &offset_operand,
+                &i.to_string(),
+            ));
+            address_mangled.clone()
+        };
+        result_code.push(Instruction::new(
+            opcode,
+            vec![
+                Operand::value(&field_mangled(field, local_variables)?),
+                Operand::value(cell),
+                Operand::value(&address),
+            ],
+        ));
+    }
+    Ok(())
+}
+
+fn is_declared_variable(
+    variable_name: &str,
+    program_ast: &ProgramAST,
+    local_variables: &[VariableScope],
+) -> bool {
+    local_variables
+        .iter()
+        .any(|lvs| lvs.variables.iter().any(|lv| lv.name == variable_name))
+        || program_ast.variables.contains_key(variable_name)
+}
+
+/// Splits `object.property` reference into object operand and property to read with `sensor`,
+/// unless the whole reference is a variable, like a struct field. Objects which aren't variables
+/// are linked buildings.
+fn property_access(
+    name: &str,
+    program_ast: &ProgramAST,
+    local_variables: &[VariableScope],
+    ctx: &GeneratorContext,
+) -> Result<Option<(String, String)>, String> {
+    let Some((object, property)) = name.rsplit_once('.') else {
+        return Ok(None);
+    };
+    if is_declared_variable(name, program_ast, local_variables) {
+        return Ok(None);
+    }
+    if !is_sensor_property(property, ctx.options.target) {
+        return Err(
+            match suggest_sensor_property(property, ctx.options.target) {
+                Some(suggestion) => format!(
+                    "{} is neither a variable nor a sensor property, did you mean {}.{}?",
+                    name, object, suggestion
+                ),
+                None => format!("{} is neither a variable nor a sensor property", name),
+            },
+        );
+    }
+    Ok(Some((
+        object_operand(object, program_ast, local_variables)?,
+        format!("@{}", property),
+    )))
+}
+
+/// Operand of the object whose property is accessed, which is either a variable or a linked
+/// building
+fn object_operand(
+    object: &str,
+    program_ast: &ProgramAST,
+    local_variables: &[VariableScope],
+) -> Result<String, String> {
+    if object.starts_with('@') || is_declared_variable(object, program_ast, local_variables) {
+        mangle_variable(object, program_ast, local_variables)
+    } else {
+        Ok(link_name(object, program_ast).to_string())
+    }
+}
+
+/// Name the processor gives to the linked building, resolving aliases
+fn link_name<'a>(name: &'a str, program_ast: &'a ProgramAST) -> &'a str {
+    program_ast
+        .links
+        .get(name)
+        .map_or(name, |link| link.link_name.as_str())
+}
+
+/// Generates `control` instruction for `building.command(args)` or `building.property = value`
+fn generate_control(
+    object: &str,
+    command: &str,
+    args: &[ExpressionASTNode],
+    program_ast: &ProgramAST,
+    local_variables: &mut Vec<VariableScope>,
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) -> Result<(), String> {
+    let arity = control_command_arity(command, ctx.options.target).ok_or_else(|| {
+        format!(
+            "{} is not a control command of {}, available commands are {}",
+            command,
+            object,
+            control_commands(ctx.options.target).join(", ")
+        )
+    })?;
+    if args.len() != arity {
+        return Err(format!(
+            "Control command {} takes {} arguments, but {} were given",
+            command,
+            arity,
+            args.len()
+        ));
+    }
+    let mut operands = Vec::<String>::new();
+    for arg in args {
+        operands.push(make_operand(
+            arg,
+            program_ast,
+            local_variables,
+            result_code,
+            ctx,
+        )?);
+    }
+    //Unused arguments are filled with zeros
+    operands.resize(4, String::from("0"));
+    let mut args = vec![
+        Operand::keyword(command),
+        Operand::value(&object_operand(object, program_ast, local_variables)?),
+    ];
+    args.extend(operands.iter().map(|operand| Operand::value(operand)));
+    result_code.push(Instruction::new(Opcode::Control, args));
+    Ok(())
+}
+
+/// Rejects `@` names unknown to the target version, which are most likely typos, or only warns
+/// about them if they're allowed
+fn check_constants(program_ast: &ProgramAST, ctx: &mut GeneratorContext) -> Result<(), String> {
+    let target = ctx.options.target;
+    let mut unknown_constants = std::collections::BTreeSet::<String>::new();
+    for function_ast in program_ast.functions.values() {
+        visit_statements(&function_ast.statements, &mut |statement| {
+            for expression in statement.expressions() {
+                visit_expression(expression, &mut |expression| {
+                    if let Some(constant) = match expression {
+                        ExpressionASTNode::VariableReference(vr) => vr.strip_prefix('@'),
+                        _ => None,
+                    } {
+                        //Only the object of property access like `@unit.health`
+                        let constant = constant.split('.').next().unwrap();
+                        if !is_known_constant(constant, target) {
+                            unknown_constants.insert(constant.into());
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    for constant in unknown_constants {
+        let message = match suggest_constant(&constant, target) {
+            Some(suggestion) => format!(
+                "Unknown constant @{}, did you mean @{}?",
+                constant, suggestion
+            ),
+            None => format!("Unknown constant @{}", constant),
+        };
+        if !ctx.options.allow_unknown_constants {
+            return Err(message);
+        }
+        ctx.warnings.push(message);
+    }
+    Ok(())
+}
+
+/// Collects linked buildings used by each function into the link manifest. Warns about links
+/// used without a declaration and declared links which are never used.
+fn check_links(program_ast: &ProgramAST, ctx: &mut GeneratorContext) -> LinkManifest {
+    let mut manifest = LinkManifest::default();
+    for link in program_ast.links.values() {
+        let entry = manifest.links.entry(link.link_name.clone()).or_default();
+        entry.declared = true;
+        entry.aliases.extend(link.alias.clone());
+        if link.type_name.is_some() {
+            entry.type_name = link.type_name.clone();
+        }
+    }
+
+    for function_ast in program_ast.functions.values() {
+        let mut declared_names = std::collections::BTreeSet::<String>::new();
+        let mut used_names = std::collections::BTreeSet::<String>::new();
+        for param in &function_ast.params {
+            declared_names.insert(param.name.clone());
+        }
+        visit_statements(&function_ast.statements, &mut |statement| {
+            match statement {
+                StatementASTNode::LocalVariableAST(lv) => {
+                    declared_names.insert(lv.name.clone());
+                }
+                StatementASTNode::ArrayDeclarationAST(array) => {
+                    declared_names.insert(array.name.clone());
+                    if let ArrayStorageAST::Memory { cell, .. } = &array.storage {
+                        used_names.insert(cell.clone());
+                    }
+                }
+                StatementASTNode::ForAST(for_ast) => {
+                    declared_names.insert(for_ast.counter_name.clone());
+                }
+                StatementASTNode::AssignmentAST(AssignmentAST {
+                    target_var_name, ..
+                })
+                | StatementASTNode::CompoundAssignmentAST(CompoundAssignmentAST {
+                    target_var_name,
+                    ..
+                }) => {
+                    used_names.insert(target_var_name.clone());
+                }
+                StatementASTNode::IndexAssignmentAST(index_assignment) => {
+                    used_names.insert(index_assignment.array_name.clone());
+                }
+                _ => {}
+            }
+            for expression in statement.expressions() {
+                visit_expression(expression, &mut |expression| match expression {
+                    ExpressionASTNode::VariableReference(name) => {
+                        used_names.insert(name.clone());
+                    }
+                    ExpressionASTNode::IndexAST(IndexAST { array_name, .. }) => {
+                        used_names.insert(array_name.clone());
+                    }
+                    ExpressionASTNode::FunctionCallAST(FunctionCallAST {
+                        function_name, ..
+                    }) if function_name.contains('.') => {
+                        used_names.insert(function_name.clone());
+                    }
+                    _ => {}
+                });
+            }
+        });
+
+        for name in used_names {
+            let mut object = name.split('.').next().unwrap();
+            if object.starts_with('@')
+                || declared_names.contains(object)
+                || program_ast.variables.contains_key(object)
+            {
+                continue;
+            }
+            //Global memory arrays use their cell
+            if let Some(ArrayDeclarationAST {
+                storage: ArrayStorageAST::Memory { cell, .. },
+                ..
+            }) = program_ast.arrays.get(object)
+            {
+                object = cell;
+            }
+            let link_name = link_name(object, program_ast);
+            if !program_ast.links.contains_key(object) && !is_link_name(link_name) {
+                continue;
+            }
+            let entry = manifest.links.entry(link_name.into()).or_default();
+            if !entry.declared && !entry.used_by.contains(&function_ast.name) {
+                ctx.warnings.push(format!(
+                    "Function {} uses link {}, which isn't declared",
+                    function_ast.name, link_name
+                ));
+            }
+            entry.used_by.insert(function_ast.name.clone());
+        }
+    }
+
+    for (link_name, entry) in &manifest.links {
+        if entry.used_by.is_empty() {
+            ctx.warnings
+                .push(format!("Link {} is declared, but never used", link_name));
+        }
+    }
+    manifest
+}
+
+/// Functions reachable from `main` through calls. Warns about the rest, which are left out of the
+/// generated code.
+fn check_reachable_functions<'a>(
+    program_ast: &'a ProgramAST,
+    ctx: &mut GeneratorContext,
+) -> std::collections::BTreeSet<&'a str> {
+    let mut calls = std::collections::BTreeMap::<&str, std::collections::BTreeSet<&str>>::new();
+    for (function_name, function_ast) in &program_ast.functions {
+        let callees = calls.entry(function_name).or_default();
+        visit_statements(&function_ast.statements, &mut |statement| {
+            for expression in statement.expressions() {
+                visit_expression(expression, &mut |expression| {
+                    if let ExpressionASTNode::FunctionCallAST(FunctionCallAST {
+                        function_name,
+                        ..
+                    }) = expression
+                    {
+                        if let Some((callee, _)) =
+                            program_ast.functions.get_key_value(function_name)
+                        {
+                            callees.insert(callee);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    let mut reachable = std::collections::BTreeSet::<&str>::new();
+    let mut stack = vec!["main"];
+    while let Some(function_name) = stack.pop() {
+        if reachable.insert(function_name) {
+            stack.extend(calls.get(function_name).into_iter().flatten());
+        }
+    }
+    for function_name in program_ast.functions.keys() {
+        if !reachable.contains(function_name.as_str()) {
+            ctx.warnings.push(format!(
+                "Function {} is never called from main, so it's left out",
+                function_name
+            ));
+        }
+    }
+    reachable
+}
+
+/// Removes stores into global variables which are never read, and warns about them
+fn remove_unread_globals(program_ast: &ProgramAST, code: &mut Code, ctx: &mut GeneratorContext) {
+    let read: std::collections::BTreeSet<&str> = code
+        .lines
+        .iter()
+        .filter_map(Line::as_instruction)
+        .flat_map(|instruction| instruction.inputs().map(|(_, variable)| variable))
+        .collect();
+    //Globals of struct type are only their members
+    let unread: std::collections::BTreeSet<String> = program_ast
+        .variables
+        .iter()
+        .filter(|(_, global_var)| {
+            !global_var
+                .type_name
+                .as_ref()
+                .is_some_and(|type_name| program_ast.structs.contains_key(type_name))
+        })
+        .map(|(name, _)| name)
+        .filter(|name| !read.contains(name.as_str()))
+        .cloned()
+        .collect();
+    for name in &unread {
+        ctx.warnings.push(format!(
+            "Global variable {} is never read, so stores into it are left out",
+            name
+        ));
+    }
+    remove_stores(code, &unread);
+}
+
+/// Mangle of function parameters, known to callers without generating the function
+fn function_param_mangle(function_name: &str) -> String {
+    format!("_{}", function_name)
+}
+
+fn return_address_variable(function_name: &str) -> String {
+    format!("ret_addr_{}", function_name)
+}
+
+fn function_result_variable(function_name: &str) -> String {
+    format!("{}_result", function_name)
+}
+
+fn find_array(
+    array_name: &str,
+    program_ast: &ProgramAST,
+    local_variables: &[VariableScope],
+) -> Option<ArrayDeclarationAST> {
+    for lvs in local_variables.iter().rev() {
+        if let Some(array) = lvs.arrays.iter().find(|array| array.name == array_name) {
+            return Some(array.clone());
+        }
+    }
+    program_ast.arrays.get(array_name).cloned()
+}
+
+/// Checks that memory-backed arrays don't share slots of the same cell
+fn check_memory_arrays(program_ast: &ProgramAST) -> Result<(), String> {
+    let mut memory_arrays: Vec<ArrayDeclarationAST> =
+        program_ast.arrays.values().cloned().collect();
+    for function_ast in program_ast.functions.values() {
+        visit_statements(&function_ast.statements, &mut |statement| {
+            if let StatementASTNode::ArrayDeclarationAST(array) = statement {
+                memory_arrays.push(array.clone());
+            }
+        });
+    }
+
+    for (i, array) in memory_arrays.iter().enumerate() {
+        let ArrayStorageAST::Memory { cell, offset } = &array.storage else {
+            continue;
+        };
+        let cell = link_name(cell, program_ast);
+        if let Some(capacity) = memory_capacity(cell) {
+            if offset + array.length > capacity {
+                return Err(format!(
+                    "Array {} occupies slots {}..{} of {}, which has only {} slots",
+                    array.name,
+                    offset,
+                    offset + array.length,
+                    cell,
+                    capacity
+                ));
+            }
+        }
+        for other_array in &memory_arrays[i + 1..] {
+            let ArrayStorageAST::Memory {
+                cell: other_cell,
+                offset: other_offset,
+            } = &other_array.storage
+            else {
+                continue;
+            };
+            let other_cell = link_name(other_cell, program_ast);
+            if cell == other_cell
+                && *offset < other_offset + other_array.length
+                && *other_offset < offset + array.length
+            {
+                return Err(format!(
+                    "Arrays {} ({}[{}..{}]) and {} ({}[{}..{}]) overlap",
+                    array.name,
+                    cell,
+                    offset,
+                    offset + array.length,
+                    other_array.name,
+                    other_cell,
+                    other_offset,
+                    other_offset + other_array.length
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_literal_array_index(array: &ArrayDeclarationAST, index: f64) -> Result<usize, String> {
+    if index.fract() != 0.0 || index < 0.0 || index >= array.length as f64 {
+        return Err(format!(
+            "Index {} is out of bounds of array {} with length {}",
+            index, array.name, array.length
+        ));
+    }
+    Ok(index as usize)
+}
+
+/// Stops the processor when the index is out of bounds, only in debug mode
+fn generate_bounds_check(
+    array: &ArrayDeclarationAST,
+    index_operand: &str,
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) {
+    if ctx.options.debug {
+        let bounds_fail_label = format!("bounds_fail_{}", ctx.uid);
+        ctx.uid += 1;
+        let bounds_ok_label = format!("bounds_ok_{}", ctx.uid);
+        ctx.uid += 1;
+        result_code.push(Instruction::jump(
+            &bounds_fail_label,
+            "lessThan",
+            index_operand,
+            "0",
+        ));
+        result_code.push(Instruction::jump(
+            &bounds_ok_label,
+            "lessThan",
+            index_operand,
+            &array.length.to_string(),
+        ));
+        result_code.label(&bounds_fail_label);
+        result_code.push(Instruction::new(Opcode::Stop, Vec::new()));
+        result_code.label(&bounds_ok_label);
+    }
+}
+
+/// Generates address of the memory array element, with bounds checking in debug mode. Returns
+/// memory cell and the address operand.
+fn generate_array_address(
+    array: &ArrayDeclarationAST,
+    index: &ExpressionASTNode,
+    program_ast: &ProgramAST,
+    local_variables: &mut Vec<VariableScope>,
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) -> Result<(String, String), String> {
+    let ArrayStorageAST::Memory { cell, offset } = &array.storage else {
+        return Err(format!("Array {} is not stored in memory", array.name));
+    };
+
+    if let ExpressionASTNode::NumberLiteral(nl) = index {
+        let index = check_literal_array_index(array, *nl)?;
+        return Ok((
+            link_name(cell, program_ast).to_string(),
+            (index + offset).to_string(),
+        ));
+    }
+
+    let index_operand = make_operand(index, program_ast, local_variables, result_code, ctx)?;
+    generate_bounds_check(array, &index_operand, result_code, ctx);
+
+    if *offset == 0 {
+        Ok((link_name(cell, program_ast).to_string(), index_operand))
+    } else {
+        let address_tmp = make_tmp_variable(&None, program_ast, local_variables, result_code, ctx)?;
+        let address_mangled = mangle_variable(&address_tmp, program_ast, local_variables)?;
+        result_code.push(Instruction::op(
+            "add",
+            &address_mangled,
+            &index_operand,
+            &offset.to_string(),
+        ));
+        Ok((link_name(cell, program_ast).to_string(), address_mangled))
+    }
+}
+
+/// Mangled name of the variable holding an element of the register array
+fn register_array_element(
+    array_name: &str,
+    element: usize,
+    program_ast: &ProgramAST,
+    local_variables: &[VariableScope],
+) -> Result<String, String> {
+    for lvs in local_variables.iter().rev() {
+        if lvs.arrays.iter().any(|array| array.name == array_name) {
+            return Ok(format!("{}.{}{}", array_name, element, lvs.mangle));
+        }
+    }
+    if program_ast.arrays.contains_key(array_name) {
+        Ok(format!("{}.{}", array_name, element))
+    } else {
+        Err(format!("Array {} not defined", array_name))
+    }
+}
+
+/// Generates access to an element of the register array, `entry` makes the accessing instruction
+/// from the element variable. Dynamic index jumps with `@counter` into a table holding one entry
+/// per element.
+fn generate_register_array_access(
+    array: &ArrayDeclarationAST,
+    index: &ExpressionASTNode,
+    entry: impl Fn(&str) -> Instruction,
+    program_ast: &ProgramAST,
+    local_variables: &mut Vec<VariableScope>,
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) -> Result<(), String> {
+    if let ExpressionASTNode::NumberLiteral(nl) = index {
+        let index = check_literal_array_index(array, *nl)?;
+        let element = register_array_element(&array.name, index, program_ast, local_variables)?;
+        result_code.push(entry(&element));
+        return Ok(());
+    }
+
+    let code_start = result_code.lines.len();
+    let table_end_label = format!("array_table_end_{}", ctx.uid);
+    ctx.uid += 1;
+
+    let index_operand = make_operand(index, program_ast, local_variables, result_code, ctx)?;
+    generate_bounds_check(array, &index_operand, result_code, ctx);
+
+    //Every entry but the last is followed by a jump out of the table, so it takes 2 instructions.
+    //Fractional index is truncated first, otherwise it could land on one of the jumps.
+    let table_offset = if array.length > 1 {
+        let offset_tmp = make_tmp_variable(&None, program_ast, local_variables, result_code, ctx)?;
+        let offset_mangled = mangle_variable(&offset_tmp, program_ast, local_variables)?;
+        result_code.push(Instruction::op(
+            "floor",
+            &offset_mangled,
+            &index_operand,
+            "0",
+        ));
+        result_code.push(Instruction::op(
+            "mul",
+            &offset_mangled,
+            &offset_mangled,
+            "2",
+        ));
+        offset_mangled
+    } else {
+        index_operand
+    };
+    result_code.push(Instruction::op(
+        "add",
+        "@counter",
+        "@counter",
+        &table_offset,
+    ));
+    for element_index in 0..array.length {
+        let element =
+            register_array_element(&array.name, element_index, program_ast, local_variables)?;
+        result_code.push(entry(&element));
+        if element_index + 1 < array.length {
+            result_code.push(Instruction::jump_always(&table_end_label));
+        }
+    }
+    result_code.label(&table_end_label);
+
+    let access_cost = result_code.instruction_count_from(code_start);
+    let array_report = ctx
+        .size_report
+        .register_arrays
+        .entry(array.name.clone())
+        .or_insert(RegisterArrayReport {
+            length: array.length,
+            dynamic_accesses: 0,
+            instructions: 0,
+        });
+    array_report.dynamic_accesses += 1;
+    array_report.instructions += access_cost;
+    Ok(())
+}
+
+/// Maximal number of instructions a processor can hold
+pub const MAX_INSTRUCTIONS: usize = 1000;
+
+#[derive(Debug)]
+pub struct RegisterArrayReport {
+    pub length: usize,
+    pub dynamic_accesses: usize,
+    /// Instructions spent on all dynamic accesses
+    pub instructions: usize,
+}
+
+/// Estimate of where the instructions of the generated code went
+#[derive(Debug, Default)]
+pub struct SizeReport {
+    pub instructions: usize,
+    /// Variables used by the code before register allocation
+    pub variables: usize,
+    /// Variables left after register allocation, or zero if it's disabled
+    pub allocated_variables: usize,
+    pub register_arrays: std::collections::BTreeMap<String, RegisterArrayReport>,
+}
+
+impl std::fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Total: {} instructions (limit {})",
+            self.instructions, MAX_INSTRUCTIONS
+        )?;
+        if self.allocated_variables > 0 {
+            writeln!(
+                f,
+                "Variables: {} ({} before register allocation)",
+                self.allocated_variables, self.variables
+            )?;
+        } else {
+            writeln!(f, "Variables: {}", self.variables)?;
+        }
+        for (name, report) in &self.register_arrays {
+            writeln!(
+                f,
+                "Register array {} [{}]: {} dynamic accesses, ~{} instructions each, {} in total",
+                name,
+                report.length,
+                report.dynamic_accesses,
+                report.instructions / report.dynamic_accesses,
+                report.instructions
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LinkManifestEntry {
+    pub aliases: Vec<String>,
+    pub type_name: Option<String>,
+    pub declared: bool,
+    /// Functions using the link
+    pub used_by: std::collections::BTreeSet<String>,
+}
+
+/// Buildings which have to be linked to the processor, by link name
+#[derive(Debug, Default)]
+pub struct LinkManifest {
+    pub links: std::collections::BTreeMap<String, LinkManifestEntry>,
+}
+
+impl std::fmt::Display for LinkManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (link_name, entry) in &self.links {
+            write!(f, "{}", link_name)?;
+            if !entry.aliases.is_empty() {
+                write!(f, " as {}", entry.aliases.join(", "))?;
+            }
+            if let Some(type_name) = &entry.type_name {
+                write!(f, ": {}", type_name)?;
+            }
+            if !entry.declared {
+                write!(f, " (not declared)")?;
+            }
+            if entry.used_by.is_empty() {
+                writeln!(f, ", unused")?;
+            } else {
+                let used_by: Vec<&str> = entry.used_by.iter().map(String::as_str).collect();
+                writeln!(f, ", used by {}", used_by.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the optimizations changed
+#[derive(Debug, Default)]
+pub struct OptimizationReport {
+    /// Functions replaced with a copy of their body at each call site
+    pub inlined_functions: usize,
+    /// Whether each called function was inlined, and why
+    pub inlining_decisions: Vec<String>,
+    /// `while` loops with the condition checked at the bottom
+    pub rotated_loops: usize,
+    /// `op` instructions moved in front of `while` loops
+    pub hoisted_invariants: usize,
+    /// `for` loops replaced with a copy of the body per iteration, with the copy count
+    pub unrolled_loops: Vec<usize>,
+    /// `match` statements lowered to a `@counter` jump table
+    pub jump_tables: usize,
+    /// `match` statements lowered to a compare-and-jump chain
+    pub compare_chains: usize,
+    /// Instructions replaced with a copy of the value computed earlier
+    pub common_subexpressions: usize,
+    /// Operands replaced with the value copied into the variable
+    pub propagated_copies: usize,
+    /// Instructions removed by peephole optimizer
+    pub peephole_removed: usize,
+    /// Calls replaced with a jump
+    pub tail_calls: usize,
+    /// Local variables and temporaries renamed by register allocation, with the number of names
+    pub allocated_registers: Option<(usize, usize)>,
+}
+
+impl std::fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Inlined functions: {}", self.inlined_functions)?;
+        writeln!(f, "Rotated while loops: {}", self.rotated_loops)?;
+        writeln!(f, "Hoisted loop invariants: {}", self.hoisted_invariants)?;
+        write!(f, "Unrolled for loops: {}", self.unrolled_loops.len())?;
+        if !self.unrolled_loops.is_empty() {
+            let copies: Vec<String> = self
+                .unrolled_loops
+                .iter()
+                .map(|count| count.to_string())
+                .collect();
+            write!(f, " ({} iterations)", copies.join(", "))?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Match jump tables: {}, compare chains: {}",
+            self.jump_tables, self.compare_chains
+        )?;
+        writeln!(f, "Reused results (CSE): {}", self.common_subexpressions)?;
+        writeln!(f, "Propagated copies: {}", self.propagated_copies)?;
+        writeln!(f, "Removed by peephole: {}", self.peephole_removed)?;
+        writeln!(f, "Tail calls: {}", self.tail_calls)?;
+        if let Some((variables, names)) = self.allocated_registers {
+            writeln!(
+                f,
+                "Register allocation: {} variables into {} names",
+                variables, names
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct GeneratorOutput {
+    pub code: String,
+    pub size_report: SizeReport,
+    pub optimization_report: OptimizationReport,
+    pub link_manifest: LinkManifest,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug)]
+struct LoopLabels {
+    label: Option<String>,
+    continue_label: String,
+    break_label: String,
+}
+
+/// Mindustry version the generated code is meant to run on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum TargetVersion {
+    V7,
+    /// Adds `select` instruction
+    V8,
+}
+
+/// Optimization pass over the generated code, which can be turned off to diagnose miscompiles
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum OptimizationPass {
+    /// Replaces calls to small functions, and functions called once, with their body
+    Inlining,
+    /// Moves `op` instructions computing the same value in every iteration out of `while` loops
+    Licm,
+    /// Reuses results of identical `op` instructions, and `sensor` reads in `@pure_tick` blocks
+    Cse,
+    /// Replaces reads of copied variables with the copied value
+    CopyPropagation,
+    /// Local simplifications, like removing jumps to the next instruction
+    Peephole,
+    /// Replaces calls right before a return, including `return f(..)`, with a jump, so that the
+    /// called function returns straight to the caller
+    TailCalls,
+    /// Reuses names of local variables and temporaries which aren't live at the same time
+    RegisterAllocation,
+}
+
+/// Trade-off between code size and instructions executed per tick
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum OptimizationLevel {
+    /// No optimizations, code follows the source closely
+    #[value(name = "0")]
+    O0,
+    /// Optimizations which don't make the code larger
+    #[value(name = "1")]
+    O1,
+    /// Smallest code, for programs close to the instruction limit
+    #[value(name = "s")]
+    Os,
+    /// Fewest instructions executed, at the cost of larger code
+    #[value(name = "speed")]
+    Ospeed,
+}
+
+impl OptimizationLevel {
+    /// Passes run over the generated code
+    pub fn passes(self) -> std::collections::BTreeSet<OptimizationPass> {
+        match self {
+            OptimizationLevel::O0 => std::collections::BTreeSet::new(),
+            _ => <OptimizationPass as clap::ValueEnum>::value_variants()
+                .iter()
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Whether function with body of the given size should be inlined at all of its call sites.
+    /// Each call takes the return address `op add` and the jump, and the function its return.
+    fn inlines(self, body_size: usize, call_sites: usize) -> bool {
+        let grows_code = call_sites * body_size > body_size + 1 + call_sites * 2;
+        match self {
+            OptimizationLevel::O0 => false,
+            OptimizationLevel::O1 | OptimizationLevel::Os => !grows_code,
+            OptimizationLevel::Ospeed => !grows_code || body_size <= INLINE_THRESHOLD,
+        }
+    }
+
+    /// Whether the `while` loop with condition check of the given size should be rotated, so that
+    /// the condition is checked at the bottom. It saves a jump per iteration, but the check is
+    /// generated twice.
+    fn rotates_loop(self, condition_size: usize) -> bool {
+        match self {
+            OptimizationLevel::O0 => false,
+            OptimizationLevel::O1 | OptimizationLevel::Os => condition_size <= 1,
+            OptimizationLevel::Ospeed => true,
+        }
     }
 
-    for lvs in local_variables.iter().rev() {
-        for lv in &lvs.variables {
-            if lv.name == variable_name {
-                let mut mangled_name = String::from(variable_name);
-                mangled_name.push_str(&lvs.mangle);
-                return Some(mangled_name);
-            }
+    /// Largest number of iterations of `for` loop over constant range which is unrolled
+    fn unroll_limit(self) -> usize {
+        match self {
+            OptimizationLevel::Ospeed => 8,
+            _ => 0,
         }
     }
 
-    if let Some(gv) = global_variables.get(variable_name) {
-        return Some(variable_name.into());
-    } else {
-        println!("Not found variable {}", variable_name);
-        return None;
+    /// Whether `match` with the given number of cases spanning the given range of integers should
+    /// use a `@counter` jump table rather than compare-and-jump chain
+    fn prefers_jump_table(self, cases: usize, range: usize, starts_at_zero: bool) -> bool {
+        //Index conversion, range checks, offset subtraction, the jump into the table and the
+        //jump from it
+        let dispatch = if starts_at_zero { 6 } else { 7 };
+        //Comparison for each case and the jump to the default arm
+        let chain = cases + 1;
+        match self {
+            OptimizationLevel::O0 => false,
+            OptimizationLevel::O1 => cases >= MATCH_JUMP_TABLE_MIN_CASES && range <= cases * 2,
+            OptimizationLevel::Os => range + dispatch - 1 < chain,
+            //Table has to beat the chain in the worst case, and its size is kept in check
+            OptimizationLevel::Ospeed => dispatch <= chain && range <= cases * 4,
+        }
     }
 }
 
-impl VariableScope {
-    fn new(mangle: &str) -> Self {
+#[derive(Clone, Debug)]
+pub struct GeneratorOptions {
+    pub target: TargetVersion,
+    /// Enables runtime checks, like array bounds checking
+    pub debug: bool,
+    pub level: OptimizationLevel,
+    /// Optimization passes to run
+    pub passes: std::collections::BTreeSet<OptimizationPass>,
+    /// Reports `@` names unknown to the target version as warnings rather than errors, for
+    /// content added by mods or newer versions
+    pub allow_unknown_constants: bool,
+}
+
+/// State shared by the whole generation process
+#[derive(Debug)]
+struct GeneratorContext {
+    uid: usize,
+    options: GeneratorOptions,
+    /// Loops enclosing the currently generated statement, innermost last
+    loops: Vec<LoopLabels>,
+    /// Function whose body is being generated
+    current_function: Option<String>,
+    /// Mangled names of local variables, including parameters and temporaries
+    locals: std::collections::BTreeSet<String>,
+    size_report: SizeReport,
+    optimization_report: OptimizationReport,
+    warnings: Vec<String>,
+}
+
+impl GeneratorContext {
+    fn new(options: &GeneratorOptions) -> Self {
         Self {
-            variables: Vec::new(),
-            mangle: mangle.into(),
+            uid: 0,
+            options: options.clone(),
+            loops: Vec::new(),
+            current_function: None,
+            locals: std::collections::BTreeSet::new(),
+            size_report: SizeReport::default(),
+            optimization_report: OptimizationReport::default(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn find_loop(
+        &self,
+        label: &Option<String>,
+        statement_name: &str,
+    ) -> Result<&LoopLabels, String> {
+        match label {
+            Some(label_name) => self
+                .loops
+                .iter()
+                .rev()
+                .find(|loop_labels| loop_labels.label.as_ref() == Some(label_name))
+                .ok_or_else(|| {
+                    format!(
+                        "{} refers to loop label '{} which is not defined",
+                        statement_name, label_name
+                    )
+                }),
+            None => self
+                .loops
+                .last()
+                .ok_or_else(|| format!("{} used outside of a loop", statement_name)),
         }
     }
 }
 
 impl ProgramAST {
-    pub fn generate(&self) -> String {
-        let mut result_code = String::new();
-        let mut uid: usize = 0;
+    pub fn generate(&self, options: &GeneratorOptions) -> Result<GeneratorOutput, String> {
+        let mut result_code = Code::new();
+        let mut ctx = GeneratorContext::new(options);
+
+        check_memory_arrays(self)?;
+        check_constants(self, &mut ctx)?;
+        let link_manifest = check_links(self, &mut ctx);
+        let reachable_functions = check_reachable_functions(self, &mut ctx);
 
-        let mut functions_codes = Vec::<(&str, String)>::new();
+        let mut functions_codes = Vec::<(&str, Code)>::new();
         for (function_name, function_ast) in &self.functions {
-            functions_codes.push((&function_name, function_ast.generate(&self, &mut uid)));
+            if reachable_functions.contains(function_name.as_str()) {
+                functions_codes.push((function_name, function_ast.generate(self, &mut ctx)?));
+            }
         }
 
         let main_call_statement =
@@ -59,29 +1213,91 @@ impl ProgramAST {
                 function_name: "main".into(),
                 args: Vec::new(),
             }));
-        main_call_statement.generate(&self, &mut Vec::new(), &mut result_code, &mut uid);
+        main_call_statement.generate(self, &mut Vec::new(), &mut result_code, &mut ctx)?;
+        result_code.push(Instruction::new(Opcode::End, Vec::new()));
 
+        inline_functions(self, &mut result_code, &mut functions_codes, &mut ctx)?;
         for function_code in functions_codes {
-            result_code.push_str(&function_code.0);
-            result_code.push_str(":\n");
-            result_code.push_str(&function_code.1);
+            result_code.label(function_code.0);
+            result_code.append(function_code.1);
+        }
+        remove_unread_globals(self, &mut result_code, &mut ctx);
+
+        let passes = &options.passes;
+        let mut optimization_report = ctx.optimization_report;
+        if passes.contains(&OptimizationPass::Cse) {
+            optimization_report.common_subexpressions =
+                eliminate_common_subexpressions(&mut result_code);
+        }
+        if passes.contains(&OptimizationPass::CopyPropagation) {
+            optimization_report.propagated_copies = propagate_copies(&mut result_code);
+        }
+        if passes.contains(&OptimizationPass::Peephole) {
+            optimization_report.peephole_removed = peephole(&mut result_code);
+        }
+        if passes.contains(&OptimizationPass::TailCalls) {
+            //Calls are only followed by the return once the peephole optimizer removes the copy
+            //of the unused result
+            let results = self
+                .functions
+                .keys()
+                .map(|function_name| function_result_variable(function_name))
+                .collect();
+            optimization_report.tail_calls = convert_tail_calls(&mut result_code, &results);
+            if passes.contains(&OptimizationPass::Peephole) {
+                optimization_report.peephole_removed += peephole(&mut result_code);
+            }
+        }
+        let mut size_report = ctx.size_report;
+        size_report.variables = result_code.variables().len();
+        if passes.contains(&OptimizationPass::RegisterAllocation) {
+            optimization_report.allocated_registers =
+                Some(allocate_registers(&mut result_code, &ctx.locals));
+            if passes.contains(&OptimizationPass::Peephole) {
+                //Removed stores may leave jumps to the next line behind
+                optimization_report.peephole_removed += peephole(&mut result_code);
+            }
+            size_report.allocated_variables = result_code.variables().len();
         }
 
-        result_code
+        size_report.instructions = result_code.instruction_count();
+        Ok(GeneratorOutput {
+            code: result_code.to_string(),
+            size_report,
+            optimization_report,
+            link_manifest,
+            warnings: ctx.warnings,
+        })
     }
 }
 
 impl FunctionAST {
-    fn generate(&self, program_ast: &ProgramAST, uid: &mut usize) -> String {
-        let mut result_code = String::new();
+    fn generate(
+        &self,
+        program_ast: &ProgramAST,
+        ctx: &mut GeneratorContext,
+    ) -> Result<Code, String> {
+        let mut result_code = Code::new();
         let mut local_variables = Vec::<VariableScope>::new();
-        let local_mangle = format!("_{}", uid);
-        *uid += 1;
+        let mut param_scope = VariableScope::new(&function_param_mangle(&self.name));
+        for param in &self.params {
+            param_scope.declare(param, program_ast);
+        }
+        ctx.locals.extend(param_scope.mangled_names());
+        local_variables.push(param_scope);
+        let local_mangle = format!("_{}", ctx.uid);
+        ctx.uid += 1;
         local_variables.push(VariableScope::new(&local_mangle));
+        ctx.current_function = Some(self.name.clone());
         for statement in &self.statements {
-            statement.generate(program_ast, &mut local_variables, &mut result_code, uid);
+            statement.generate(program_ast, &mut local_variables, &mut result_code, ctx)?;
         }
-        result_code
+        ctx.current_function = None;
+        result_code.push(Instruction::set(
+            "@counter",
+            &return_address_variable(&self.name),
+        ));
+        Ok(result_code)
     }
 }
 
@@ -90,192 +1306,1386 @@ impl StatementASTNode {
         &self,
         program_ast: &ProgramAST,
         local_variables: &mut Vec<VariableScope>,
-        result_code: &mut String,
-        uid: &mut usize,
-    ) {
+        result_code: &mut Code,
+        ctx: &mut GeneratorContext,
+    ) -> Result<(), String> {
         match self {
-            StatementASTNode::LocalVariableAST(lvs @ LocalVariableAST { name: lv }) => {
+            StatementASTNode::LocalVariableAST(lvs) => {
+                let last_pos = local_variables.len() - 1;
+                local_variables[last_pos].declare(lvs, program_ast);
+                ctx.locals.extend(local_variables[last_pos].mangled_names());
+            }
+            StatementASTNode::ReturnAST(ReturnAST { value }) => {
+                let function_name = ctx
+                    .current_function
+                    .clone()
+                    .ok_or("return used outside of a function")?;
+                if let Some(value) = value {
+                    let value_operand =
+                        make_operand(value, program_ast, local_variables, result_code, ctx)?;
+                    result_code.push(Instruction::set(
+                        &function_result_variable(&function_name),
+                        &value_operand,
+                    ));
+                }
+                result_code.push(Instruction::set(
+                    "@counter",
+                    &return_address_variable(&function_name),
+                ));
+            }
+            StatementASTNode::ArrayDeclarationAST(array) => {
                 let last_pos = local_variables.len() - 1;
-                local_variables[last_pos].variables.push(lvs.clone());
+                local_variables[last_pos].arrays.push(array.clone());
+            }
+            StatementASTNode::IndexAssignmentAST(IndexAssignmentAST {
+                array_name,
+                index,
+                value,
+            }) => {
+                let array = find_array(array_name, program_ast, local_variables)
+                    .ok_or_else(|| format!("Array {} not defined", array_name))?;
+                let index_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+
+                local_variables.push(VariableScope::new(&index_mangle));
+                let value_operand =
+                    make_operand(value, program_ast, local_variables, result_code, ctx)?;
+                match &array.storage {
+                    ArrayStorageAST::Memory { .. } => {
+                        let (cell, address) = generate_array_address(
+                            &array,
+                            index,
+                            program_ast,
+                            local_variables,
+                            result_code,
+                            ctx,
+                        )?;
+                        result_code.push(Instruction::new(
+                            Opcode::Write,
+                            vec![
+                                Operand::value(&value_operand),
+                                Operand::value(&cell),
+                                Operand::value(&address),
+                            ],
+                        ));
+                    }
+                    ArrayStorageAST::Registers => {
+                        generate_register_array_access(
+                            &array,
+                            index,
+                            |element| Instruction::set(element, &value_operand),
+                            program_ast,
+                            local_variables,
+                            result_code,
+                            ctx,
+                        )?;
+                    }
+                }
+                local_variables.pop();
             }
             StatementASTNode::AssignmentAST(AssignmentAST {
                 target_var_name,
                 value,
             }) => {
-                match value {
-                    ExpressionASTNode::FunctionCallAST(fc) => {
-                        fc.generate(
+                if let Some((object, property)) = target_var_name.rsplit_once('.').filter(|_| {
+                    !is_declared_variable(target_var_name, program_ast, local_variables)
+                }) {
+                    //Setting building property like `turret1.enabled = 0`
+                    generate_control(
+                        object,
+                        property,
+                        std::slice::from_ref(value),
+                        program_ast,
+                        local_variables,
+                        result_code,
+                        ctx,
+                    )?;
+                    return Ok(());
+                }
+                if let Some(type_name) =
+                    struct_type_of(target_var_name, program_ast, local_variables)
+                {
+                    let source =
+                        expect_struct_value(value, &type_name, program_ast, local_variables)?;
+                    generate_struct_copy(
+                        target_var_name,
+                        source,
+                        &type_name,
+                        program_ast,
+                        local_variables,
+                        result_code,
+                    )?;
+                    return Ok(());
+                }
+                match value {
+                    ExpressionASTNode::FunctionCallAST(fc) => {
+                        fc.generate(
+                            program_ast,
+                            local_variables,
+                            target_var_name,
+                            result_code,
+                            ctx,
+                        )?;
+                    }
+                    ExpressionASTNode::StringLiteral(sl) => {
+                        result_code.push(Instruction::set(
+                            &mangle_variable(target_var_name, program_ast, local_variables)?,
+                            &string_literal_operand(sl, ctx),
+                        ));
+                    }
+                    ExpressionASTNode::NumberLiteral(nl) => {
+                        result_code.push(Instruction::set(
+                            &mangle_variable(target_var_name, program_ast, local_variables)?,
+                            &number_operand(*nl),
+                        ));
+                    }
+                    ExpressionASTNode::IfExpressionAST(IfExpressionAST {
+                        condition,
+                        then_value,
+                        else_value,
+                    }) => {
+                        let target_mangled =
+                            mangle_variable(target_var_name, program_ast, local_variables)?;
+
+                        let is_simple = |expr: &ExpressionASTNode| {
+                            matches!(
+                                expr,
+                                ExpressionASTNode::NumberLiteral(_)
+                                    | ExpressionASTNode::StringLiteral(_)
+                                    | ExpressionASTNode::VariableReference(_)
+                            )
+                        };
+                        if ctx.options.target >= TargetVersion::V8
+                            && is_simple(then_value)
+                            && is_simple(else_value)
+                            && !matches!(**condition, ExpressionASTNode::LogicalAST(_))
+                        {
+                            //Both values are free to evaluate, so select can be used
+                            let select_mangle = format!("_{}", ctx.uid);
+                            ctx.uid += 1;
+                            local_variables.push(VariableScope::new(&select_mangle));
+                            let (select_condition, lhs_operand, rhs_operand) = match &**condition {
+                                ExpressionASTNode::FunctionCallAST(FunctionCallAST {
+                                    function_name,
+                                    args,
+                                }) if args.len() == 2
+                                    && inverse_jump_condition(function_name).is_some() =>
+                                {
+                                    (
+                                        function_name.clone(),
+                                        make_operand(
+                                            &args[0],
+                                            program_ast,
+                                            local_variables,
+                                            result_code,
+                                            ctx,
+                                        )?,
+                                        make_operand(
+                                            &args[1],
+                                            program_ast,
+                                            local_variables,
+                                            result_code,
+                                            ctx,
+                                        )?,
+                                    )
+                                }
+                                _ => (
+                                    String::from("notEqual"),
+                                    make_operand(
+                                        condition,
+                                        program_ast,
+                                        local_variables,
+                                        result_code,
+                                        ctx,
+                                    )?,
+                                    String::from("0"),
+                                ),
+                            };
+                            let then_operand = make_operand(
+                                then_value,
+                                program_ast,
+                                local_variables,
+                                result_code,
+                                ctx,
+                            )?;
+                            let else_operand = make_operand(
+                                else_value,
+                                program_ast,
+                                local_variables,
+                                result_code,
+                                ctx,
+                            )?;
+                            result_code.push(Instruction::new(
+                                Opcode::Select,
+                                vec![
+                                    Operand::value(&target_mangled),
+                                    Operand::keyword(&select_condition),
+                                    Operand::value(&lhs_operand),
+                                    Operand::value(&rhs_operand),
+                                    Operand::value(&then_operand),
+                                    Operand::value(&else_operand),
+                                ],
+                            ));
+                            local_variables.pop();
+                        } else {
+                            let else_label = format!("if_expr_else_{}", ctx.uid);
+                            ctx.uid += 1;
+                            let end_label = format!("if_expr_end_{}", ctx.uid);
+                            ctx.uid += 1;
+
+                            generate_condition_jump(
+                                condition,
+                                false,
+                                &else_label,
+                                program_ast,
+                                local_variables,
+                                result_code,
+                                ctx,
+                            )?;
+                            let assign_then_statement =
+                                StatementASTNode::AssignmentAST(AssignmentAST {
+                                    target_var_name: target_var_name.clone(),
+                                    value: (**then_value).clone(),
+                                });
+                            assign_then_statement.generate(
+                                program_ast,
+                                local_variables,
+                                result_code,
+                                ctx,
+                            )?;
+                            result_code.push(Instruction::jump_always(&end_label));
+                            result_code.label(&else_label);
+                            let assign_else_statement =
+                                StatementASTNode::AssignmentAST(AssignmentAST {
+                                    target_var_name: target_var_name.clone(),
+                                    value: (**else_value).clone(),
+                                });
+                            assign_else_statement.generate(
+                                program_ast,
+                                local_variables,
+                                result_code,
+                                ctx,
+                            )?;
+                            result_code.label(&end_label);
+                        }
+                    }
+                    ExpressionASTNode::IndexAST(IndexAST { array_name, index }) => {
+                        let array = find_array(array_name, program_ast, local_variables)
+                            .ok_or_else(|| format!("Array {} not defined", array_name))?;
+                        let target_mangled =
+                            mangle_variable(target_var_name, program_ast, local_variables)?;
+                        let index_mangle = format!("_{}", ctx.uid);
+                        ctx.uid += 1;
+
+                        local_variables.push(VariableScope::new(&index_mangle));
+                        match &array.storage {
+                            ArrayStorageAST::Memory { .. } => {
+                                let (cell, address) = generate_array_address(
+                                    &array,
+                                    index,
+                                    program_ast,
+                                    local_variables,
+                                    result_code,
+                                    ctx,
+                                )?;
+                                result_code.push(Instruction::new(
+                                    Opcode::Read,
+                                    vec![
+                                        Operand::value(&target_mangled),
+                                        Operand::value(&cell),
+                                        Operand::value(&address),
+                                    ],
+                                ));
+                            }
+                            ArrayStorageAST::Registers => {
+                                generate_register_array_access(
+                                    &array,
+                                    index,
+                                    |element| Instruction::set(&target_mangled, element),
+                                    program_ast,
+                                    local_variables,
+                                    result_code,
+                                    ctx,
+                                )?;
+                            }
+                        }
+                        local_variables.pop();
+                    }
+                    ExpressionASTNode::LogicalAST(_) => {
+                        //Materialize 0 or 1 with jumps, so that the right side is evaluated only
+                        //if needed
+                        let false_label = format!("logical_false_{}", ctx.uid);
+                        ctx.uid += 1;
+                        let end_label = format!("logical_end_{}", ctx.uid);
+                        ctx.uid += 1;
+                        let target_mangled =
+                            mangle_variable(target_var_name, program_ast, local_variables)?;
+
+                        generate_condition_jump(
+                            value,
+                            false,
+                            &false_label,
+                            program_ast,
+                            local_variables,
+                            result_code,
+                            ctx,
+                        )?;
+                        result_code.push(Instruction::set(&target_mangled, "1"));
+                        result_code.push(Instruction::jump_always(&end_label));
+                        result_code.label(&false_label);
+                        result_code.push(Instruction::set(&target_mangled, "0"));
+                        result_code.label(&end_label);
+                    }
+                    ExpressionASTNode::VariableReference(vr) => {
+                        let target_mangled =
+                            mangle_variable(target_var_name, program_ast, local_variables)?;
+                        match property_access(vr, program_ast, local_variables, ctx)? {
+                            Some((object, property)) => {
+                                result_code.push(Instruction::new(
+                                    Opcode::Sensor,
+                                    vec![
+                                        Operand::value(&target_mangled),
+                                        Operand::value(&object),
+                                        Operand::value(&property),
+                                    ],
+                                ));
+                            }
+                            None => {
+                                result_code.push(Instruction::set(
+                                    &target_mangled,
+                                    &mangle_variable(vr, program_ast, local_variables)?,
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+            StatementASTNode::CompoundAssignmentAST(CompoundAssignmentAST {
+                target_var_name,
+                operation,
+                value,
+            }) => {
+                let value_operand =
+                    make_operand(value, program_ast, local_variables, result_code, ctx)?;
+                let target_mangled =
+                    mangle_variable(target_var_name, program_ast, local_variables)?;
+                result_code.push(Instruction::op(
+                    operation,
+                    &target_mangled,
+                    &target_mangled,
+                    &value_operand,
+                ));
+            }
+            StatementASTNode::ExpressionAST(expr) => match expr {
+                ExpressionASTNode::FunctionCallAST(fc) => {
+                    let fc_mangle = format!("_{}", ctx.uid);
+                    ctx.uid += 1;
+
+                    local_variables.push(VariableScope::new(&fc_mangle));
+                    let blackhole_declaration_statement =
+                        StatementASTNode::LocalVariableAST(LocalVariableAST {
+                            name: "blackhole".into(),
+                            type_name: None,
+                        });
+                    blackhole_declaration_statement.generate(
+                        program_ast,
+                        local_variables,
+                        result_code,
+                        ctx,
+                    )?;
+
+                    fc.generate(program_ast, local_variables, "blackhole", result_code, ctx)?;
+
+                    local_variables.pop();
+                }
+                ExpressionASTNode::LogicalAST(_) => {
+                    //Evaluated only for side effects of the operands
+                    let end_label = format!("logical_end_{}", ctx.uid);
+                    ctx.uid += 1;
+                    generate_condition_jump(
+                        expr,
+                        true,
+                        &end_label,
+                        program_ast,
+                        local_variables,
+                        result_code,
+                        ctx,
+                    )?;
+                    result_code.label(&end_label);
+                }
+                _ => {
+                    //Using string or number literal or variable reference as statement is noop
+                }
+            },
+            StatementASTNode::IfAST(IfAST {
+                condition,
+                then_block,
+                else_block,
+            }) => {
+                let else_label = format!("else_{}", ctx.uid);
+                ctx.uid += 1;
+                let if_end_label = format!("if_end_{}", ctx.uid);
+                ctx.uid += 1;
+                let then_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+                let else_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+
+                generate_condition_jump(
+                    condition,
+                    false,
+                    &else_label,
+                    program_ast,
+                    local_variables,
+                    result_code,
+                    ctx,
+                )?;
+
+                local_variables.push(VariableScope::new(&then_mangle));
+                for then_statement in then_block {
+                    then_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                local_variables.pop();
+                result_code.push(Instruction::jump_always(&if_end_label));
+
+                result_code.label(&else_label);
+                local_variables.push(VariableScope::new(&else_mangle));
+                for else_statement in else_block {
+                    else_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                local_variables.pop();
+                result_code.label(&if_end_label);
+            }
+            StatementASTNode::WhileAST(WhileAST {
+                label,
+                condition,
+                do_block,
+            }) => {
+                let while_begin_label = format!("while_begin_{}", ctx.uid);
+                ctx.uid += 1;
+                let while_end_label = format!("while_end_{}", ctx.uid);
+                ctx.uid += 1;
+                let while_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+
+                local_variables.push(VariableScope::new(&while_mangle));
+
+                let mut condition_check = Code::new();
+                generate_condition_jump(
+                    condition,
+                    false,
+                    &while_end_label,
+                    program_ast,
+                    local_variables,
+                    &mut condition_check,
+                    ctx,
+                )?;
+                let rotate = ctx
+                    .options
+                    .level
+                    .rotates_loop(condition_check.instruction_count());
+                if rotate {
+                    //Condition is checked once before the loop, and then at the bottom
+                    ctx.optimization_report.rotated_loops += 1;
+                    result_code.append(std::mem::take(&mut condition_check));
+                }
+                //Invariants are computed right before the first iteration
+                let loop_start = result_code.lines.len();
+                result_code.label(&while_begin_label);
+                result_code.append(condition_check);
+
+                let while_continue_label = if rotate {
+                    let while_continue_label = format!("while_continue_{}", ctx.uid);
+                    ctx.uid += 1;
+                    //Variables declared in the body mustn't shadow the ones in the condition
+                    let do_mangle = format!("_{}", ctx.uid);
+                    ctx.uid += 1;
+                    local_variables.push(VariableScope::new(&do_mangle));
+                    while_continue_label
+                } else {
+                    while_begin_label.clone()
+                };
+                ctx.loops.push(LoopLabels {
+                    label: label.clone(),
+                    continue_label: while_continue_label.clone(),
+                    break_label: while_end_label.clone(),
+                });
+                for do_statement in do_block {
+                    do_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                ctx.loops.pop();
+
+                if rotate {
+                    local_variables.pop();
+                    result_code.label(&while_continue_label);
+                    generate_condition_jump(
+                        condition,
+                        true,
+                        &while_begin_label,
+                        program_ast,
+                        local_variables,
+                        result_code,
+                        ctx,
+                    )?;
+                } else {
+                    result_code.push(Instruction::jump_always(&while_begin_label));
+                }
+
+                if ctx.options.passes.contains(&OptimizationPass::Licm) {
+                    ctx.optimization_report.hoisted_invariants +=
+                        hoist_loop_invariants(result_code, loop_start);
+                }
+                result_code.label(&while_end_label);
+
+                local_variables.pop();
+            }
+            StatementASTNode::LoopAST(LoopAST { label, do_block }) => {
+                let loop_begin_label = format!("loop_begin_{}", ctx.uid);
+                ctx.uid += 1;
+                let loop_end_label = format!("loop_end_{}", ctx.uid);
+                ctx.uid += 1;
+                let loop_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+
+                local_variables.push(VariableScope::new(&loop_mangle));
+                result_code.label(&loop_begin_label);
+
+                ctx.loops.push(LoopLabels {
+                    label: label.clone(),
+                    continue_label: loop_begin_label.clone(),
+                    break_label: loop_end_label.clone(),
+                });
+                for do_statement in do_block {
+                    do_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                ctx.loops.pop();
+
+                result_code.push(Instruction::jump_always(&loop_begin_label));
+                result_code.label(&loop_end_label);
+                local_variables.pop();
+            }
+            StatementASTNode::PureTickAST(PureTickAST { block }) => {
+                let pure_tick = ctx.uid;
+                ctx.uid += 1;
+                let block_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+
+                local_variables.push(VariableScope::new(&block_mangle));
+                let block_start = result_code.lines.len();
+                for statement in block {
+                    statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                //Nested blocks are marked first, and then become part of the enclosing one
+                for line in &mut result_code.lines[block_start..] {
+                    if let Line::Instruction(instruction) = line {
+                        if instruction.opcode == Opcode::Sensor {
+                            instruction.pure_tick = Some(pure_tick);
+                        }
+                    }
+                }
+                local_variables.pop();
+            }
+            StatementASTNode::DoWhileAST(DoWhileAST {
+                label,
+                do_block,
+                condition,
+            }) => {
+                let do_begin_label = format!("do_begin_{}", ctx.uid);
+                ctx.uid += 1;
+                let do_continue_label = format!("do_continue_{}", ctx.uid);
+                ctx.uid += 1;
+                let do_end_label = format!("do_end_{}", ctx.uid);
+                ctx.uid += 1;
+                let do_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+
+                result_code.label(&do_begin_label);
+
+                local_variables.push(VariableScope::new(&do_mangle));
+                ctx.loops.push(LoopLabels {
+                    label: label.clone(),
+                    continue_label: do_continue_label.clone(),
+                    break_label: do_end_label.clone(),
+                });
+                for do_statement in do_block {
+                    do_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                ctx.loops.pop();
+                local_variables.pop();
+
+                //Condition is checked at the bottom, so variables declared in the body aren't
+                //visible in it
+                result_code.label(&do_continue_label);
+                generate_condition_jump(
+                    condition,
+                    true,
+                    &do_begin_label,
+                    program_ast,
+                    local_variables,
+                    result_code,
+                    ctx,
+                )?;
+
+                result_code.label(&do_end_label);
+            }
+            StatementASTNode::ForAST(ForAST {
+                label,
+                counter_name,
+                iterable: ForIterableAST::Range { start, end, step },
+                do_block,
+            }) => {
+                let for_begin_label = format!("for_begin_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_continue_label = format!("for_continue_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_end_label = format!("for_end_{}", ctx.uid);
+                ctx.uid += 1;
+                let end_buf = format!("for_end_value_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+                let do_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+
+                //Range is evaluated only once, before the counter comes into scope
+                local_variables.push(VariableScope::new(&for_mangle));
+                let start_value = match start {
+                    ExpressionASTNode::NumberLiteral(_) => start.clone(),
+                    _ => ExpressionASTNode::VariableReference(make_tmp_variable(
+                        &Some(start.clone()),
+                        program_ast,
+                        local_variables,
+                        result_code,
+                        ctx,
+                    )?),
+                };
+                let end_value = match end {
+                    ExpressionASTNode::NumberLiteral(nl) => number_operand(*nl),
+                    _ => {
+                        let declare_end_statement =
+                            StatementASTNode::LocalVariableAST(LocalVariableAST {
+                                name: end_buf.clone(),
+                                type_name: None,
+                            });
+                        declare_end_statement.generate(
+                            program_ast,
+                            local_variables,
+                            result_code,
+                            ctx,
+                        )?;
+                        let assign_end_statement = StatementASTNode::AssignmentAST(AssignmentAST {
+                            target_var_name: end_buf.clone(),
+                            value: end.clone(),
+                        });
+                        assign_end_statement.generate(
+                            program_ast,
+                            local_variables,
+                            result_code,
+                            ctx,
+                        )?;
+                        mangle_variable(&end_buf, program_ast, local_variables)?
+                    }
+                };
+
+                //Counter is scoped to the loop
+                let declare_counter_statement =
+                    StatementASTNode::LocalVariableAST(LocalVariableAST {
+                        name: counter_name.clone(),
+                        type_name: None,
+                    });
+                declare_counter_statement.generate(
+                    program_ast,
+                    local_variables,
+                    result_code,
+                    ctx,
+                )?;
+
+                //Levels which don't unroll leave even loops over empty ranges alone
+                let unroll_limit = ctx.options.level.unroll_limit();
+                if let Some(iterations) = constant_iterations(start, end, *step)
+                    .filter(|iterations| unroll_limit > 0 && iterations.len() <= unroll_limit)
+                {
+                    //Body is repeated for each counter value, `continue` skips to the next copy
+                    ctx.optimization_report
+                        .unrolled_loops
+                        .push(iterations.len());
+                    for counter_value in iterations {
+                        let iteration_end_label = format!("for_next_{}", ctx.uid);
+                        ctx.uid += 1;
+                        let iteration_mangle = format!("_{}", ctx.uid);
+                        ctx.uid += 1;
+
+                        let assign_counter_statement =
+                            StatementASTNode::AssignmentAST(AssignmentAST {
+                                target_var_name: counter_name.clone(),
+                                value: ExpressionASTNode::NumberLiteral(counter_value),
+                            });
+                        assign_counter_statement.generate(
+                            program_ast,
+                            local_variables,
+                            result_code,
+                            ctx,
+                        )?;
+
+                        local_variables.push(VariableScope::new(&iteration_mangle));
+                        ctx.loops.push(LoopLabels {
+                            label: label.clone(),
+                            continue_label: iteration_end_label.clone(),
+                            break_label: for_end_label.clone(),
+                        });
+                        for do_statement in do_block {
+                            do_statement.generate(
+                                program_ast,
+                                local_variables,
+                                result_code,
+                                ctx,
+                            )?;
+                        }
+                        ctx.loops.pop();
+                        local_variables.pop();
+                        result_code.label(&iteration_end_label);
+                    }
+                    result_code.label(&for_end_label);
+                    local_variables.pop();
+                    return Ok(());
+                }
+
+                let assign_start_statement = StatementASTNode::AssignmentAST(AssignmentAST {
+                    target_var_name: counter_name.clone(),
+                    value: start_value,
+                });
+                assign_start_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                let counter_mangled = mangle_variable(counter_name, program_ast, local_variables)?;
+
+                let (continue_condition, exit_condition) = if *step > 0.0 {
+                    ("lessThan", "greaterThanEq")
+                } else {
+                    ("greaterThan", "lessThanEq")
+                };
+
+                //Initial check can be skipped if the range is known to be non-empty
+                let known_non_empty = match (start, end) {
+                    (ExpressionASTNode::NumberLiteral(s), ExpressionASTNode::NumberLiteral(e)) => {
+                        if *step > 0.0 {
+                            s < e
+                        } else {
+                            s > e
+                        }
+                    }
+                    _ => false,
+                };
+                if !known_non_empty {
+                    result_code.push(Instruction::jump(
+                        &for_end_label,
+                        exit_condition,
+                        &counter_mangled,
+                        &end_value,
+                    ));
+                }
+
+                result_code.label(&for_begin_label);
+
+                local_variables.push(VariableScope::new(&do_mangle));
+                ctx.loops.push(LoopLabels {
+                    label: label.clone(),
+                    continue_label: for_continue_label.clone(),
+                    break_label: for_end_label.clone(),
+                });
+                for do_statement in do_block {
+                    do_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                ctx.loops.pop();
+                local_variables.pop();
+
+                result_code.label(&for_continue_label);
+                result_code.push(Instruction::op(
+                    "add",
+                    &counter_mangled,
+                    &counter_mangled,
+                    &number_operand(*step),
+                ));
+                result_code.push(Instruction::jump(
+                    &for_begin_label,
+                    continue_condition,
+                    &counter_mangled,
+                    &end_value,
+                ));
+                result_code.label(&for_end_label);
+
+                local_variables.pop();
+            }
+            StatementASTNode::ForAST(ForAST {
+                label,
+                counter_name,
+                iterable: ForIterableAST::Units(unit_type),
+                do_block,
+            }) => {
+                let for_begin_label = format!("for_begin_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_continue_label = format!("for_continue_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_end_label = format!("for_end_{}", ctx.uid);
+                ctx.uid += 1;
+                let first_unit_buf = format!("for_first_unit_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+                let do_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+
+                local_variables.push(VariableScope::new(&for_mangle));
+                let unit_type_mangled = match unit_type {
+                    ExpressionASTNode::VariableReference(vr) => {
+                        mangle_variable(vr, program_ast, local_variables)?
+                    }
+                    _ => {
+                        let unit_type_tmp = make_tmp_variable(
+                            &Some(unit_type.clone()),
                             program_ast,
                             local_variables,
-                            target_var_name,
                             result_code,
-                            uid,
-                        );
-                    }
-                    ExpressionASTNode::StringLiteral(sl) => {
-                        //TODO: escape string properly
-                        result_code.push_str(&format!(
-                            "set {} \"{}\"\n",
-                            mangle_variable(
-                                target_var_name,
-                                &program_ast.variables,
-                                local_variables
-                            )
-                            .unwrap(),
-                            sl
-                        ));
-                    }
-                    ExpressionASTNode::NumberLiteral(nl) => {
-                        result_code.push_str(&format!(
-                            "set {} {}\n",
-                            mangle_variable(
-                                target_var_name,
-                                &program_ast.variables,
-                                local_variables
-                            )
-                            .unwrap(),
-                            nl
-                        ));
-                    }
-                    ExpressionASTNode::VariableReference(vr) => {
-                        result_code.push_str(&format!(
-                            "set {} {}\n",
-                            mangle_variable(
-                                target_var_name,
-                                &program_ast.variables,
-                                local_variables
-                            )
-                            .unwrap(),
-                            mangle_variable(vr, &program_ast.variables, local_variables).unwrap()
-                        ));
+                            ctx,
+                        )?;
+                        mangle_variable(&unit_type_tmp, program_ast, local_variables)?
                     }
+                };
+                for name in [&first_unit_buf, counter_name] {
+                    let declare_statement = StatementASTNode::LocalVariableAST(LocalVariableAST {
+                        name: name.clone(),
+                        type_name: None,
+                    });
+                    declare_statement.generate(program_ast, local_variables, result_code, ctx)?;
                 }
-            }
-            StatementASTNode::ExpressionAST(expr) => match expr {
-                ExpressionASTNode::FunctionCallAST(fc) => {
-                    let fc_mangle = format!("_{}", uid);
-                    *uid += 1;
+                let first_unit_mangled =
+                    mangle_variable(&first_unit_buf, program_ast, local_variables)?;
+                let counter_mangled = mangle_variable(counter_name, program_ast, local_variables)?;
 
-                    local_variables.push(VariableScope::new(&fc_mangle));
-                    let blackhole_declaration_statement =
-                        StatementASTNode::LocalVariableAST(LocalVariableAST {
-                            name: "blackhole".into(),
-                        });
-                    blackhole_declaration_statement.generate(
-                        program_ast,
-                        local_variables,
-                        result_code,
-                        uid,
-                    );
+                //Iterate until the first bound unit comes around again
+                result_code.push(Instruction::new(
+                    Opcode::UBind,
+                    vec![Operand::value(&unit_type_mangled)],
+                ));
+                result_code.push(Instruction::set(&first_unit_mangled, "@unit"));
+                result_code.push(Instruction::jump(
+                    &for_end_label,
+                    "strictEqual",
+                    &first_unit_mangled,
+                    "null",
+                ));
 
-                    fc.generate(program_ast, local_variables, "blackhole", result_code, uid);
+                result_code.label(&for_begin_label);
+                result_code.push(Instruction::set(&counter_mangled, "@unit"));
 
-                    local_variables.pop();
-                }
-                _ => {
-                    //Using string or number literal or variable reference as statement is noop
-                }
-            },
-            StatementASTNode::IfAST(IfAST {
-                condition,
-                then_block,
-                else_block,
-            }) => {
-                let else_label = format!("else_{}", uid);
-                *uid += 1;
-                let if_end_label = format!("if_end_{}", uid);
-                *uid += 1;
-                let cond_var = format!("cond_{}", uid);
-                *uid += 1;
-                let cond_mangle = format!("_{}", uid);
-                *uid += 1;
-                let then_mangle = format!("_{}", uid);
-                *uid += 1;
-                let else_mangle = format!("_{}", uid);
-                *uid += 1;
-
-                local_variables.push(VariableScope::new(&cond_mangle));
-                let cond_var_statement = StatementASTNode::LocalVariableAST(LocalVariableAST {
-                    name: cond_var.clone(),
-                });
-                cond_var_statement.generate(program_ast, local_variables, result_code, uid);
-                let assign_condition_statement = StatementASTNode::AssignmentAST(AssignmentAST {
-                    target_var_name: cond_var.clone(),
-                    value: condition.clone(),
+                local_variables.push(VariableScope::new(&do_mangle));
+                ctx.loops.push(LoopLabels {
+                    label: label.clone(),
+                    continue_label: for_continue_label.clone(),
+                    break_label: for_end_label.clone(),
                 });
-                assign_condition_statement.generate(program_ast, local_variables, result_code, uid);
-                result_code.push_str(&format!(
-                    "jump {} equal {} 0\n",
-                    else_label,
-                    mangle_variable(&cond_var, &program_ast.variables, local_variables).unwrap()
-                ));
-                local_variables.pop();
-
-                local_variables.push(VariableScope::new(&then_mangle));
-                for then_statement in then_block {
-                    then_statement.generate(program_ast, local_variables, result_code, uid);
+                for do_statement in do_block {
+                    do_statement.generate(program_ast, local_variables, result_code, ctx)?;
                 }
+                ctx.loops.pop();
                 local_variables.pop();
-                result_code.push_str(&format!("jump {} always\n", if_end_label));
 
-                result_code.push_str(&else_label);
-                result_code.push_str(":\n");
-                local_variables.push(VariableScope::new(&else_mangle));
-                for else_statement in else_block {
-                    else_statement.generate(program_ast, local_variables, result_code, uid);
-                }
+                result_code.label(&for_continue_label);
+                result_code.push(Instruction::new(
+                    Opcode::UBind,
+                    vec![Operand::value(&unit_type_mangled)],
+                ));
+                result_code.push(Instruction::jump(
+                    &for_end_label,
+                    "strictEqual",
+                    "@unit",
+                    "null",
+                ));
+                result_code.push(Instruction::jump(
+                    &for_begin_label,
+                    "notEqual",
+                    "@unit",
+                    &first_unit_mangled,
+                ));
+                result_code.label(&for_end_label);
+
                 local_variables.pop();
-                result_code.push_str(&if_end_label);
-                result_code.push_str(":\n");
             }
-            StatementASTNode::WhileAST(WhileAST {
-                condition,
+            StatementASTNode::ForAST(ForAST {
+                label,
+                counter_name,
+                iterable: ForIterableAST::Links,
                 do_block,
             }) => {
-                let while_begin_label = format!("while_begin_{}", uid);
-                *uid += 1;
-                let while_end_label = format!("while_end_{}", uid);
-                *uid += 1;
-                let condition_buf = format!("cond_{}", uid);
-                *uid += 1;
-                let while_mangle = format!("_{}", uid);
-                *uid += 1;
+                let for_begin_label = format!("for_begin_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_continue_label = format!("for_continue_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_end_label = format!("for_end_{}", ctx.uid);
+                ctx.uid += 1;
+                let link_index_buf = format!("for_link_index_{}", ctx.uid);
+                ctx.uid += 1;
+                let for_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+                let do_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
 
-                local_variables.push(VariableScope::new(&while_mangle));
-                let declare_cond_buf_statement =
-                    StatementASTNode::LocalVariableAST(LocalVariableAST {
-                        name: condition_buf.clone(),
+                local_variables.push(VariableScope::new(&for_mangle));
+                for name in [&link_index_buf, counter_name] {
+                    let declare_statement = StatementASTNode::LocalVariableAST(LocalVariableAST {
+                        name: name.clone(),
+                        type_name: None,
                     });
-                declare_cond_buf_statement.generate(program_ast, local_variables, result_code, uid);
+                    declare_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                let link_index_mangled =
+                    mangle_variable(&link_index_buf, program_ast, local_variables)?;
+                let counter_mangled = mangle_variable(counter_name, program_ast, local_variables)?;
 
-                result_code.push_str(&while_begin_label);
-                result_code.push_str(":\n");
+                result_code.push(Instruction::set(&link_index_mangled, "0"));
+                result_code.push(Instruction::jump(
+                    &for_end_label,
+                    "greaterThanEq",
+                    &link_index_mangled,
+                    "@links",
+                ));
+
+                result_code.label(&for_begin_label);
+                result_code.push(Instruction::new(
+                    Opcode::GetLink,
+                    vec![
+                        Operand::value(&counter_mangled),
+                        Operand::value(&link_index_mangled),
+                    ],
+                ));
 
-                let assign_condition_statement = StatementASTNode::AssignmentAST(AssignmentAST {
-                    target_var_name: condition_buf.clone(),
-                    value: condition.clone(),
+                local_variables.push(VariableScope::new(&do_mangle));
+                ctx.loops.push(LoopLabels {
+                    label: label.clone(),
+                    continue_label: for_continue_label.clone(),
+                    break_label: for_end_label.clone(),
                 });
-                assign_condition_statement.generate(program_ast, local_variables, result_code, uid);
+                for do_statement in do_block {
+                    do_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                }
+                ctx.loops.pop();
+                local_variables.pop();
 
-                result_code.push_str(&format!(
-                    "jump {} equal {} 0\n",
-                    while_end_label,
-                    mangle_variable(&condition_buf, &program_ast.variables, local_variables)
-                        .unwrap()
+                result_code.label(&for_continue_label);
+                result_code.push(Instruction::op(
+                    "add",
+                    &link_index_mangled,
+                    &link_index_mangled,
+                    "1",
                 ));
+                result_code.push(Instruction::jump(
+                    &for_begin_label,
+                    "lessThan",
+                    &link_index_mangled,
+                    "@links",
+                ));
+                result_code.label(&for_end_label);
 
-                for do_statement in do_block {
-                    do_statement.generate(program_ast, local_variables, result_code, uid);
+                local_variables.pop();
+            }
+            StatementASTNode::BreakAST(BreakAST { label }) => {
+                let break_label = &ctx.find_loop(label, "break")?.break_label;
+                result_code.push(Instruction::jump_always(break_label));
+            }
+            StatementASTNode::ContinueAST(ContinueAST { label }) => {
+                let continue_label = &ctx.find_loop(label, "continue")?.continue_label;
+                result_code.push(Instruction::jump_always(continue_label));
+            }
+            StatementASTNode::MatchAST(MatchAST { value, arms }) => {
+                let match_end_label = format!("match_end_{}", ctx.uid);
+                ctx.uid += 1;
+                let value_var = format!("match_value_{}", ctx.uid);
+                ctx.uid += 1;
+                let match_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
+                let mut arm_labels = Vec::<String>::new();
+                for _ in arms {
+                    arm_labels.push(format!("match_arm_{}", ctx.uid));
+                    ctx.uid += 1;
                 }
 
-                result_code.push_str(&format!("jump {} always\n", while_begin_label));
+                local_variables.push(VariableScope::new(&match_mangle));
+                let declare_value_statement =
+                    StatementASTNode::LocalVariableAST(LocalVariableAST {
+                        name: value_var.clone(),
+                        type_name: None,
+                    });
+                declare_value_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                let assign_value_statement = StatementASTNode::AssignmentAST(AssignmentAST {
+                    target_var_name: value_var.clone(),
+                    value: value.clone(),
+                });
+                assign_value_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                let value_mangled = mangle_variable(&value_var, program_ast, local_variables)?;
+
+                //Values matched before the first wildcard arm, the parser rejects duplicates
+                let wildcard_arm = arms.iter().position(|arm| {
+                    arm.patterns
+                        .iter()
+                        .any(|pattern| matches!(pattern, MatchPatternAST::Wildcard))
+                });
+                let default_label = match wildcard_arm {
+                    Some(arm_index) => arm_labels[arm_index].clone(),
+                    None => match_end_label.clone(),
+                };
+                let mut cases = Vec::<(f64, usize)>::new();
+                for (arm_index, arm) in arms
+                    .iter()
+                    .enumerate()
+                    .take(wildcard_arm.unwrap_or(arms.len()))
+                {
+                    for pattern in &arm.patterns {
+                        if let MatchPatternAST::NumberLiteral(case_value) = pattern {
+                            cases.push((*case_value, arm_index));
+                        }
+                    }
+                }
 
-                result_code.push_str(&while_end_label);
-                result_code.push_str(":\n");
+                if let Some((min, max)) = match_jump_table_range(&cases, ctx.options.level) {
+                    ctx.optimization_report.jump_tables += 1;
+                    //Dense integer cases, jump through @counter
+                    let index_var = format!("match_index_{}", ctx.uid);
+                    ctx.uid += 1;
+                    let declare_index_statement =
+                        StatementASTNode::LocalVariableAST(LocalVariableAST {
+                            name: index_var.clone(),
+                            type_name: None,
+                        });
+                    declare_index_statement.generate(
+                        program_ast,
+                        local_variables,
+                        result_code,
+                        ctx,
+                    )?;
+                    let index_mangled = mangle_variable(&index_var, program_ast, local_variables)?;
 
+                    result_code.push(Instruction::op(
+                        "floor",
+                        &index_mangled,
+                        &value_mangled,
+                        "0",
+                    ));
+                    result_code.push(Instruction::jump(
+                        &default_label,
+                        "notEqual",
+                        &index_mangled,
+                        &value_mangled,
+                    ));
+                    result_code.push(Instruction::jump(
+                        &default_label,
+                        "lessThan",
+                        &index_mangled,
+                        &min.to_string(),
+                    ));
+                    result_code.push(Instruction::jump(
+                        &default_label,
+                        "greaterThan",
+                        &index_mangled,
+                        &max.to_string(),
+                    ));
+                    if min != 0 {
+                        result_code.push(Instruction::op(
+                            "sub",
+                            &index_mangled,
+                            &index_mangled,
+                            &min.to_string(),
+                        ));
+                    }
+                    result_code.push(Instruction::op(
+                        "add",
+                        "@counter",
+                        "@counter",
+                        &index_mangled,
+                    ));
+                    for case_value in min..=max {
+                        let target_label = match cases.iter().find(|(v, _)| *v == case_value as f64)
+                        {
+                            Some((_, arm_index)) => &arm_labels[*arm_index],
+                            None => &default_label,
+                        };
+                        result_code.push(Instruction::jump_always(target_label));
+                    }
+                } else {
+                    //Compare-and-jump chain
+                    ctx.optimization_report.compare_chains += 1;
+                    for (case_value, arm_index) in &cases {
+                        result_code.push(Instruction::jump(
+                            &arm_labels[*arm_index],
+                            "equal",
+                            &value_mangled,
+                            &number_operand(*case_value),
+                        ));
+                    }
+                    result_code.push(Instruction::jump_always(&default_label));
+                }
                 local_variables.pop();
+
+                for (arm_index, arm) in arms.iter().enumerate() {
+                    result_code.label(&arm_labels[arm_index]);
+
+                    let arm_mangle = format!("_{}", ctx.uid);
+                    ctx.uid += 1;
+                    local_variables.push(VariableScope::new(&arm_mangle));
+                    for arm_statement in &arm.block {
+                        arm_statement.generate(program_ast, local_variables, result_code, ctx)?;
+                    }
+                    local_variables.pop();
+
+                    if arm_index + 1 < arms.len() {
+                        result_code.push(Instruction::jump_always(&match_end_label));
+                    }
+                }
+
+                result_code.label(&match_end_label);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns jump condition which is true exactly when the given one is false
+fn inverse_jump_condition(condition: &str) -> Option<&'static str> {
+    match condition {
+        "equal" => Some("notEqual"),
+        "notEqual" => Some("equal"),
+        "lessThan" => Some("greaterThanEq"),
+        "lessThanEq" => Some("greaterThan"),
+        "greaterThan" => Some("lessThanEq"),
+        "greaterThanEq" => Some("lessThan"),
+        _ => None,
+    }
+}
+
+/// Generates code jumping to `target_label` if the condition evaluates to `jump_if`, and
+/// falling through otherwise. Logical operators are short-circuiting.
+fn generate_condition_jump(
+    condition: &ExpressionASTNode,
+    jump_if: bool,
+    target_label: &str,
+    program_ast: &ProgramAST,
+    local_variables: &mut Vec<VariableScope>,
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) -> Result<(), String> {
+    match condition {
+        ExpressionASTNode::LogicalAST(LogicalAST { operator, lhs, rhs }) => {
+            //`a && b` jumps if true only when both are true, `a || b` jumps if false only when
+            //both are false, otherwise the first operand decides alone
+            let jump_on_each = matches!(
+                (operator, jump_if),
+                (LogicalOperator::And, false) | (LogicalOperator::Or, true)
+            );
+            if jump_on_each {
+                generate_condition_jump(
+                    lhs,
+                    jump_if,
+                    target_label,
+                    program_ast,
+                    local_variables,
+                    result_code,
+                    ctx,
+                )?;
+                generate_condition_jump(
+                    rhs,
+                    jump_if,
+                    target_label,
+                    program_ast,
+                    local_variables,
+                    result_code,
+                    ctx,
+                )?;
+            } else {
+                let skip_label = format!("logical_skip_{}", ctx.uid);
+                ctx.uid += 1;
+                generate_condition_jump(
+                    lhs,
+                    !jump_if,
+                    &skip_label,
+                    program_ast,
+                    local_variables,
+                    result_code,
+                    ctx,
+                )?;
+                generate_condition_jump(
+                    rhs,
+                    jump_if,
+                    target_label,
+                    program_ast,
+                    local_variables,
+                    result_code,
+                    ctx,
+                )?;
+                result_code.label(&skip_label);
+            }
+        }
+        ExpressionASTNode::NumberLiteral(nl) => {
+            if (*nl != 0.0) == jump_if {
+                result_code.push(Instruction::jump_always(target_label));
+            }
+        }
+        ExpressionASTNode::FunctionCallAST(FunctionCallAST {
+            function_name,
+            args,
+        }) if args.len() == 2 && inverse_jump_condition(function_name).is_some() => {
+            //Comparison can be used as jump condition directly
+            let jump_condition = if jump_if {
+                function_name.as_str()
+            } else {
+                inverse_jump_condition(function_name).unwrap()
+            };
+            let cond_mangle = format!("_{}", ctx.uid);
+            ctx.uid += 1;
+            local_variables.push(VariableScope::new(&cond_mangle));
+            let lhs_operand =
+                make_operand(&args[0], program_ast, local_variables, result_code, ctx)?;
+            let rhs_operand =
+                make_operand(&args[1], program_ast, local_variables, result_code, ctx)?;
+            result_code.push(Instruction::jump(
+                target_label,
+                jump_condition,
+                &lhs_operand,
+                &rhs_operand,
+            ));
+            local_variables.pop();
+        }
+        _ => {
+            let cond_mangle = format!("_{}", ctx.uid);
+            ctx.uid += 1;
+            local_variables.push(VariableScope::new(&cond_mangle));
+            let operand = make_operand(condition, program_ast, local_variables, result_code, ctx)?;
+            result_code.push(Instruction::jump(
+                target_label,
+                if jump_if { "notEqual" } else { "equal" },
+                &operand,
+                "0",
+            ));
+            local_variables.pop();
+        }
+    }
+    Ok(())
+}
+
+/// Counter values of `for` loop over range with constant bounds, or None if they aren't known
+fn constant_iterations(
+    start: &ExpressionASTNode,
+    end: &ExpressionASTNode,
+    step: f64,
+) -> Option<Vec<f64>> {
+    let (ExpressionASTNode::NumberLiteral(start), ExpressionASTNode::NumberLiteral(end)) =
+        (start, end)
+    else {
+        return None;
+    };
+    let mut iterations = Vec::new();
+    let mut counter = *start;
+    while (step > 0.0 && counter < *end) || (step < 0.0 && counter > *end) {
+        //Unrolling too many iterations is never worth it anyway
+        if iterations.len() > 1000 {
+            return None;
+        }
+        iterations.push(counter);
+        counter += step;
+    }
+    Some(iterations)
+}
+
+/// Largest function body, in instructions, copied into every call site at `-Ospeed`
+const INLINE_THRESHOLD: usize = 8;
+
+/// Replaces calls to `inline` functions, and the functions the optimization level finds cheap
+/// enough, with a copy of their body. Inlined functions are removed from `functions_codes`.
+fn inline_functions(
+    program_ast: &ProgramAST,
+    main_code: &mut Code,
+    functions_codes: &mut Vec<(&str, Code)>,
+    ctx: &mut GeneratorContext,
+) -> Result<(), String> {
+    let names: Vec<&str> = functions_codes.iter().map(|(name, _)| *name).collect();
+    for function_name in names {
+        let function_ast = &program_ast.functions[function_name];
+        let position = functions_codes
+            .iter()
+            .position(|(name, _)| *name == function_name)
+            .unwrap();
+        let body = &functions_codes[position].1;
+        let return_address = return_address_variable(function_name);
+        let calls = call_sites(main_code, function_name).len()
+            + functions_codes
+                .iter()
+                .map(|(_, code)| call_sites(code, function_name).len())
+                .sum::<usize>();
+        if calls == 0 {
+            continue;
+        }
+        let recursive = !call_sites(body, function_name).is_empty();
+        let size = inlined_size(body, &return_address);
+
+        let decision = match function_ast.style {
+            FunctionStyle::Inline if recursive => {
+                return Err(format!("Inline function {} calls itself", function_name))
+            }
+            FunctionStyle::Inline => Ok("declared inline"),
+            FunctionStyle::Normal if !ctx.options.passes.contains(&OptimizationPass::Inlining) => {
+                continue
+            }
+            FunctionStyle::Normal if function_ast.noinline => Err("marked #[noinline]"),
+            FunctionStyle::Normal if recursive => Err("calls itself"),
+            FunctionStyle::Normal if ctx.options.level.inlines(size, calls) => {
+                Ok("cheaper than the calls")
             }
+            FunctionStyle::Normal => Err("copies would cost more than the calls"),
+        };
+        let report = &mut ctx.optimization_report;
+        report.inlining_decisions.push(format!(
+            "{} (size {}, calls {}): {}, {}",
+            function_name,
+            size,
+            calls,
+            if decision.is_ok() { "inlined" } else { "kept" },
+            decision.unwrap_or_else(|reason| reason)
+        ));
+        if decision.is_err() {
+            continue;
+        }
+
+        let (_, body) = functions_codes.remove(position);
+        let result = function_result_variable(function_name);
+        for code in
+            std::iter::once(&mut *main_code).chain(functions_codes.iter_mut().map(|(_, code)| code))
+        {
+            let temporaries = inline_calls(
+                code,
+                function_name,
+                &return_address,
+                &result,
+                &body,
+                &mut ctx.uid,
+            );
+            ctx.locals.extend(temporaries);
         }
+        ctx.optimization_report.inlined_functions += 1;
+    }
+    Ok(())
+}
+
+/// Minimal number of distinct cases for which a `@counter` jump table is used
+const MATCH_JUMP_TABLE_MIN_CASES: usize = 4;
+
+/// Returns the value range covered by the jump table, if the cases are integers dense enough
+/// for the table to be worth its constant overhead at the optimization level.
+fn match_jump_table_range(cases: &[(f64, usize)], level: OptimizationLevel) -> Option<(i64, i64)> {
+    if cases.iter().any(|(v, _)| v.fract() != 0.0 || v.abs() > 1e9) {
+        return None;
+    }
+    let min = cases.iter().map(|(v, _)| *v as i64).min()?;
+    let max = cases.iter().map(|(v, _)| *v as i64).max()?;
+    if level.prefers_jump_table(cases.len(), (max - min + 1) as usize, min == 0) {
+        Some((min, max))
+    } else {
+        None
     }
 }
 
@@ -283,63 +2693,180 @@ fn make_tmp_variable(
     value: &Option<ExpressionASTNode>,
     program_ast: &ProgramAST,
     local_variables: &mut Vec<VariableScope>,
-    result_code: &mut String,
-    uid: &mut usize,
-) -> String {
-    let tmp_name = format!("tmp_{}", uid);
-    *uid += 1;
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) -> Result<String, String> {
+    let tmp_name = format!("tmp_{}", ctx.uid);
+    ctx.uid += 1;
 
     let declare_tmp_statement = StatementASTNode::LocalVariableAST(LocalVariableAST {
         name: tmp_name.clone(),
+        type_name: None,
     });
-    declare_tmp_statement.generate(program_ast, local_variables, result_code, uid);
-    println!(
-        "Declaring temporary variable {}, after mangling {}",
-        tmp_name,
-        mangle_variable(&tmp_name, &program_ast.variables, local_variables).unwrap()
-    );
-    println!("Backtrace: {}", std::backtrace::Backtrace::capture());
+    declare_tmp_statement.generate(program_ast, local_variables, result_code, ctx)?;
 
     if let Some(value_expr) = value {
-        println!("And assigning value {:?} to it", &value_expr);
         let assignment_statement = StatementASTNode::AssignmentAST(AssignmentAST {
             target_var_name: tmp_name.clone(),
             value: value_expr.clone(),
         });
-        assignment_statement.generate(program_ast, local_variables, result_code, uid);
+        assignment_statement.generate(program_ast, local_variables, result_code, ctx)?;
+    }
+
+    Ok(tmp_name)
+}
+
+/// Formats the number the way mlog parses it. Packed colors are tiny denormal numbers, so they're
+/// written as `%rrggbbaa` color literals, and other numbers too long to write in full use
+/// exponent notation.
+fn number_operand(number: f64) -> String {
+    let bits = number.to_bits();
+    if number.is_subnormal() && bits <= u32::MAX as u64 {
+        format!("%{:08x}", bits)
+    } else {
+        let decimal = number.to_string();
+        if decimal.len() > 20 {
+            format!("{:e}", number)
+        } else {
+            decimal
+        }
+    }
+}
+
+/// Text longer than this doesn't fit into the print buffer
+const MAX_PRINT_LENGTH: usize = 400;
+/// Text longer than this doesn't fit into a message block
+const MAX_MESSAGE_LENGTH: usize = 220;
+
+/// Formats the string as mlog literal. mlog has no way to escape `"`, so it's replaced with `'`.
+/// Newlines are written as `\n`, which mlog turns back into newlines, and color markup like
+/// `[red]` is kept as is.
+fn string_literal_operand(string: &str, ctx: &mut GeneratorContext) -> String {
+    let preview: String = string.chars().take(20).collect();
+    let length = string.chars().count();
+    if length > MAX_PRINT_LENGTH {
+        ctx.warnings.push(format!(
+            "String \"{}...\" has {} characters, more than print buffer holds ({})",
+            preview, length, MAX_PRINT_LENGTH
+        ));
+    } else if length > MAX_MESSAGE_LENGTH {
+        ctx.warnings.push(format!(
+            "String \"{}...\" has {} characters, more than message block shows ({})",
+            preview, length, MAX_MESSAGE_LENGTH
+        ));
+    }
+    if string.contains('"') {
+        ctx.warnings.push(format!(
+            "String \"{}\" contains '\"', which mlog can't represent, replacing it with '\''",
+            preview
+        ));
     }
 
-    tmp_name
+    let mut literal = String::from("\"");
+    for ch in string.chars() {
+        match ch {
+            '"' => literal.push('\''),
+            '\n' => literal.push_str("\\n"),
+            '\r' => {}
+            other => literal.push(other),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Returns operand usable directly in an instruction, evaluating the expression into temporary
+/// variable only if it isn't a literal or variable reference
+fn make_operand(
+    value: &ExpressionASTNode,
+    program_ast: &ProgramAST,
+    local_variables: &mut Vec<VariableScope>,
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) -> Result<String, String> {
+    match value {
+        ExpressionASTNode::NumberLiteral(nl) => Ok(number_operand(*nl)),
+        ExpressionASTNode::StringLiteral(sl) => Ok(string_literal_operand(sl, ctx)),
+        ExpressionASTNode::VariableReference(vr)
+            if property_access(vr, program_ast, local_variables, ctx)?.is_none() =>
+        {
+            if struct_type_of(vr, program_ast, local_variables).is_some() {
+                return Err(format!("Struct {} can't be used as a single value", vr));
+            }
+            mangle_variable(vr, program_ast, local_variables)
+        }
+        _ => {
+            let tmp = make_tmp_variable(
+                &Some(value.clone()),
+                program_ast,
+                local_variables,
+                result_code,
+                ctx,
+            )?;
+            mangle_variable(&tmp, program_ast, local_variables)
+        }
+    }
 }
 
 fn make_tmp_variables<const COUNT: usize>(
     values: &[Option<ExpressionASTNode>; COUNT],
     program_ast: &ProgramAST,
     local_variables: &mut Vec<VariableScope>,
-    result_code: &mut String,
-    uid: &mut usize,
-) -> [String; COUNT] {
+    result_code: &mut Code,
+    ctx: &mut GeneratorContext,
+) -> Result<[String; COUNT], String> {
     const EMPTY_STRING: String = String::new();
     let mut result: [String; COUNT] = [EMPTY_STRING; COUNT];
     for i in 0..COUNT {
-        result[i] = make_tmp_variable(&values[i], program_ast, local_variables, result_code, uid);
+        result[i] = make_tmp_variable(&values[i], program_ast, local_variables, result_code, ctx)?;
+    }
+    Ok(result)
+}
+
+/// Checks the number of arguments passed to builtin function
+fn expect_argument_count(
+    function_name: &str,
+    args: &[ExpressionASTNode],
+    count: usize,
+) -> Result<(), String> {
+    if args.len() == count {
+        Ok(())
+    } else {
+        Err(format!(
+            "Function {} takes {} arguments, but {} were given",
+            function_name,
+            count,
+            args.len()
+        ))
     }
-    result
 }
 
+type BuiltinFunctionGenerator = Box<
+    dyn Fn(
+            &[ExpressionASTNode],
+            &ProgramAST,
+            &mut Vec<VariableScope>,
+            &str,
+            &mut Code,
+            &mut GeneratorContext,
+        ) -> Result<(), String>
+        + Send
+        + Sync,
+>;
+
 lazy_static::lazy_static! (
     static ref BINARY_OPS: Vec<&'static str> = {
-        vec!["add", "sub", "mul", "div", "equal"] //TODO: Add rest
+        vec![
+            "add", "sub", "mul", "div", "equal", "notEqual", "lessThan", "lessThanEq",
+            "greaterThan", "greaterThanEq", "strictEqual"
+        ] //TODO: Add rest
     };
 
-    static ref BUILTIN_FUNCTIONS: std::sync::Mutex<
-        std::collections::BTreeMap<&'static str, Box<dyn FnMut(
-            &[ExpressionASTNode], &ProgramAST, &mut Vec<VariableScope>, &str, &mut String, &mut usize
-        ) + Send + Sync>>
-    > = {
-        let mut m: std::collections::BTreeMap<&'static str, Box<dyn FnMut(
-            &[ExpressionASTNode], &ProgramAST, &mut Vec<VariableScope>, &str, &mut String, &mut usize
-        ) + Send + Sync>> = std::collections::BTreeMap::new();
+    //Not behind a lock, since builtins generate their arguments, which may call other builtins
+    static ref BUILTIN_FUNCTIONS:
+        std::collections::BTreeMap<&'static str, BuiltinFunctionGenerator> = {
+        let mut m: std::collections::BTreeMap<&'static str, BuiltinFunctionGenerator> =
+            std::collections::BTreeMap::new();
 
         for binary_op in BINARY_OPS.iter() {
             m.insert(binary_op, Box::new(
@@ -348,24 +2875,17 @@ lazy_static::lazy_static! (
                     program_ast: &ProgramAST,
                     local_variables: &mut Vec<VariableScope>,
                     target_variable: &str,
-                    result_code: &mut String,
-                    uid: &mut usize
-                | {
-                    println!("Binary operation {} called with arguments {:?}", binary_op, args);
+                    result_code: &mut Code,
+                    ctx: &mut GeneratorContext
+                | -> Result<(), String> {
+                    expect_argument_count(binary_op, args, 2)?;
                     let tmps: [String; 2] = make_tmp_variables(
                         &[Some(args[0].clone()), Some(args[1].clone())],
-                        program_ast, local_variables, result_code, uid
-                    );
-                    println!("Tmps: {:?}", tmps);
-
-                    result_code.push_str(&format!(
-                        "op {} {} {} {}\n",
-                        binary_op,
-                        mangle_variable(target_variable, &program_ast.variables, local_variables)
-                            .unwrap(),
-                        mangle_variable(&tmps[0], &program_ast.variables, local_variables).unwrap(),
-                        mangle_variable(&tmps[1], &program_ast.variables, local_variables).unwrap()
-                    ));
+                        program_ast, local_variables, result_code, ctx
+                    )?;
+
+                    result_code.push(Instruction::op(binary_op, &mangle_variable(target_variable, program_ast, local_variables)?, &mangle_variable(&tmps[0], program_ast, local_variables)?, &mangle_variable(&tmps[1], program_ast, local_variables)?));
+                    Ok(())
                 }
             ));
         }
@@ -376,51 +2896,43 @@ lazy_static::lazy_static! (
                 program_ast: &ProgramAST,
                 local_variables: &mut Vec<VariableScope>,
                 target_variable: &str,
-                result_code: &mut String,
-                uid: &mut usize
-            | {
-                result_code.push_str(&format!(
-                    "radar {} {} {} {} {} {} {}\n",
-                    //1st filter
-                    if let ExpressionASTNode::StringLiteral(arg) = &args[0] {
-                        arg
+                result_code: &mut Code,
+                _ctx: &mut GeneratorContext
+            | -> Result<(), String> {
+                expect_argument_count("radar", args, 6)?;
+                let filter = |i: usize, ordinal: &str| {
+                    if let ExpressionASTNode::StringLiteral(arg) = &args[i] {
+                        Ok(Operand::keyword(arg))
                     } else {
-                        panic!("1st argument to radar function must be string")
-                    },
+                        Err(format!("{} argument to radar function must be string", ordinal))
+                    }
+                };
+                result_code.push(Instruction::new(Opcode::Radar, vec![
+                    //1st filter
+                    filter(0, "1st")?,
                     //2nd filter
-                    if let ExpressionASTNode::StringLiteral(arg) = &args[1] {
-                        arg
-                    } else {
-                        panic!("2nd argument to radar function must be string")
-                    },
+                    filter(1, "2nd")?,
                     //3rd filter
-                    if let ExpressionASTNode::StringLiteral(arg) = &args[2] {
-                        arg
-                    } else {
-                        panic!("3rd argument to radar function must be string")
-                    },
+                    filter(2, "3rd")?,
                     //sort criterion
-                    if let ExpressionASTNode::StringLiteral(arg) = &args[3] {
-                        arg
-                    } else {
-                        panic!("4th argument to radar function must be string")
-                    },
+                    filter(3, "4th")?,
                     //object which will be used for detection
-                    mangle_variable(
+                    Operand::value(&mangle_variable(
                         if let ExpressionASTNode::VariableReference(arg) = &args[4] {
-                            &arg
+                            arg
                         } else {
-                            panic!("5th argument to radar function must be variable reference")
-                        }, &program_ast.variables, local_variables).unwrap(),
+                            return Err(String::from("5th argument to radar function must be variable reference"));
+                        }, program_ast, local_variables)?),
                     //order
                     if let ExpressionASTNode::NumberLiteral(arg) = args[5] {
-                        arg
+                        Operand::value(&arg.to_string())
                     } else {
-                        panic!("6th argument to radar function must be number")
+                        return Err(String::from("6th argument to radar function must be number"));
                     },
                     //output variable
-                    mangle_variable(target_variable, &program_ast.variables, local_variables).unwrap()
-                ));
+                    Operand::value(&mangle_variable(target_variable, program_ast, local_variables)?),
+                ]));
+                Ok(())
             }
         ));
 
@@ -429,19 +2941,18 @@ lazy_static::lazy_static! (
                 args: &[ExpressionASTNode],
                 program_ast: &ProgramAST,
                 local_variables: &mut Vec<VariableScope>,
-                target_variable: &str,
-                result_code: &mut String,
-                uid: &mut usize
-            | {
-                result_code.push_str(&format!(
-                    "ubind {}\n",
-                    mangle_variable(
+                _target_variable: &str,
+                result_code: &mut Code,
+                _ctx: &mut GeneratorContext
+            | -> Result<(), String> {
+                expect_argument_count("ubind", args, 1)?;
+                result_code.push(Instruction::new(Opcode::UBind, vec![Operand::value(&mangle_variable(
                         if let ExpressionASTNode::VariableReference(arg) = &args[0] {
-                            &arg
+                            arg
                         } else {
-                            panic!("1st argument to ubind function must be variable reference");
-                        }, &program_ast.variables, local_variables).unwrap()
-                ));
+                            return Err(String::from("1st argument to ubind function must be variable reference"));
+                        }, program_ast, local_variables)?)]));
+                Ok(())
             }
         ));
 
@@ -450,20 +2961,25 @@ lazy_static::lazy_static! (
                 args: &[ExpressionASTNode],
                 program_ast: &ProgramAST,
                 local_variables: &mut Vec<VariableScope>,
-                target_variable: &str,
-                result_code: &mut String,
-                uid: &mut usize
-            | {
+                _target_variable: &str,
+                result_code: &mut Code,
+                ctx: &mut GeneratorContext
+            | -> Result<(), String> {
+                expect_argument_count("ucontrolMove", args, 2)?;
                 let tmps: [String; 2] = make_tmp_variables(
                     &[Some(args[0].clone()), Some(args[1].clone())],
-                    program_ast, local_variables, result_code, uid
-                );
+                    program_ast, local_variables, result_code, ctx
+                )?;
 
-                result_code.push_str(&format!(
-                    "ucontrol move {} {} 0 0 0\n",
-                    mangle_variable(&tmps[0], &program_ast.variables, local_variables).unwrap(),
-                    mangle_variable(&tmps[1], &program_ast.variables, local_variables).unwrap()
-                ));
+                result_code.push(Instruction::new(Opcode::UControl, vec![
+                    Operand::keyword("move"),
+                    Operand::value(&mangle_variable(&tmps[0], program_ast, local_variables)?),
+                    Operand::value(&mangle_variable(&tmps[1], program_ast, local_variables)?),
+                    Operand::value("0"),
+                    Operand::value("0"),
+                    Operand::value("0"),
+                ]));
+                Ok(())
             }
         ));
 
@@ -473,25 +2989,129 @@ lazy_static::lazy_static! (
                 program_ast: &ProgramAST,
                 local_variables: &mut Vec<VariableScope>,
                 target_variable: &str,
-                result_code: &mut String,
-                uid: &mut usize
-            | {
+                result_code: &mut Code,
+                ctx: &mut GeneratorContext
+            | -> Result<(), String> {
+                expect_argument_count("ucontrolWithin", args, 3)?;
                 let tmps: [String; 3] = make_tmp_variables(
                     &[Some(args[0].clone()), Some(args[1].clone()), Some(args[2].clone())],
-                    program_ast, local_variables, result_code, uid
-                );
-
-                result_code.push_str(&format!(
-                    "ucontrol within {} {} {} {} 0\n",
-                    mangle_variable(&tmps[0], &program_ast.variables, local_variables).unwrap(),
-                    mangle_variable(&tmps[1], &program_ast.variables, local_variables).unwrap(),
-                    mangle_variable(&tmps[2], &program_ast.variables, local_variables).unwrap(),
-                    mangle_variable(target_variable, &program_ast.variables, local_variables).unwrap()
-                ));
+                    program_ast, local_variables, result_code, ctx
+                )?;
+
+                result_code.push(Instruction::new(Opcode::UControl, vec![
+                    Operand::keyword("within"),
+                    Operand::value(&mangle_variable(&tmps[0], program_ast, local_variables)?),
+                    Operand::value(&mangle_variable(&tmps[1], program_ast, local_variables)?),
+                    Operand::value(&mangle_variable(&tmps[2], program_ast, local_variables)?),
+                    Operand::value(&mangle_variable(target_variable, program_ast, local_variables)?),
+                    Operand::value("0"),
+                ]));
+                Ok(())
+            }
+        ));
+
+        m.insert("print", Box::new(
+            |
+                args: &[ExpressionASTNode],
+                program_ast: &ProgramAST,
+                local_variables: &mut Vec<VariableScope>,
+                _target_variable: &str,
+                result_code: &mut Code,
+                ctx: &mut GeneratorContext
+            | -> Result<(), String> {
+                for arg in args {
+                    let arg_operand = make_operand(arg, program_ast, local_variables, result_code, ctx)?;
+                    result_code.push(Instruction::new(Opcode::Print, vec![Operand::value(&arg_operand)]));
+                }
+                Ok(())
+            }
+        ));
+
+        m.insert("printflush", Box::new(
+            |
+                args: &[ExpressionASTNode],
+                program_ast: &ProgramAST,
+                local_variables: &mut Vec<VariableScope>,
+                _target_variable: &str,
+                result_code: &mut Code,
+                _ctx: &mut GeneratorContext
+            | -> Result<(), String> {
+                result_code.push(Instruction::new(Opcode::PrintFlush, vec![Operand::value(&if let Some(ExpressionASTNode::VariableReference(arg)) = args.first() {
+                        object_operand(arg, program_ast, local_variables)?
+                    } else {
+                        return Err(String::from("1st argument to printflush function must be message block"));
+                    })]));
+                Ok(())
+            }
+        ));
+
+        //Name of enum variant, looked up through `@counter` jump table
+        m.insert("variantName", Box::new(
+            |
+                args: &[ExpressionASTNode],
+                program_ast: &ProgramAST,
+                local_variables: &mut Vec<VariableScope>,
+                target_variable: &str,
+                result_code: &mut Code,
+                ctx: &mut GeneratorContext
+            | -> Result<(), String> {
+                let enum_ast = match args.first() {
+                    Some(ExpressionASTNode::VariableReference(enum_name)) => program_ast
+                        .enums
+                        .get(enum_name)
+                        .ok_or_else(|| format!("Enum {} not defined", enum_name))?,
+                    _ => return Err(String::from("1st argument to variantName function must be enum name")),
+                };
+                let value_operand = make_operand(
+                    args.get(1).ok_or("variantName function takes enum name and value")?,
+                    program_ast, local_variables, result_code, ctx
+                )?;
+                let target_mangled =
+                    mangle_variable(target_variable, program_ast, local_variables)?;
+                let unknown_label = format!("variant_name_unknown_{}", ctx.uid);
+                ctx.uid += 1;
+                let end_label = format!("variant_name_end_{}", ctx.uid);
+                ctx.uid += 1;
+
+                result_code.push(Instruction::jump(&unknown_label, "lessThan", &value_operand, "0"));
+                result_code.push(Instruction::jump(&unknown_label, "greaterThanEq", &value_operand, &enum_ast.variants.len().to_string()));
+                let offset_tmp = make_tmp_variable(&None, program_ast, local_variables, result_code, ctx)?;
+                let offset_mangled =
+                    mangle_variable(&offset_tmp, program_ast, local_variables)?;
+                result_code.push(Instruction::op("floor", &offset_mangled, &value_operand, "0"));
+                result_code.push(Instruction::jump(&unknown_label, "notEqual", &offset_mangled, &value_operand));
+                result_code.push(Instruction::op("mul", &offset_mangled, &offset_mangled, "2"));
+                result_code.push(Instruction::op("add", "@counter", "@counter", &offset_mangled));
+                for variant in &enum_ast.variants {
+                    result_code.push(Instruction::set(&target_mangled, &format!("\"{}\"", variant)));
+                    result_code.push(Instruction::jump_always(&end_label));
+                }
+                //Values which aren't variants are left as numbers
+                result_code.label(&unknown_label);
+                result_code.push(Instruction::set(&target_mangled, &value_operand));
+                result_code.label(&end_label);
+                Ok(())
             }
         ));
 
-        std::sync::Mutex::new(m)
+        for (function_name, opcode) in [("writeStruct", Opcode::Write), ("readStruct", Opcode::Read)] {
+            m.insert(function_name, Box::new(
+                move |
+                    args: &[ExpressionASTNode],
+                    program_ast: &ProgramAST,
+                    local_variables: &mut Vec<VariableScope>,
+                    _target_variable: &str,
+                    result_code: &mut Code,
+                    ctx: &mut GeneratorContext
+                | -> Result<(), String> {
+                    generate_struct_memory_transfer(
+                        opcode, args, program_ast, local_variables, result_code, ctx
+                    )
+                }
+            ));
+        }
+
+        m
     };
 );
 
@@ -501,54 +3121,102 @@ impl FunctionCallAST {
         program_ast: &ProgramAST,
         local_variables: &mut Vec<VariableScope>,
         target_variable: &str,
-        result_code: &mut String,
-        uid: &mut usize,
-    ) {
-        println!(
-            "Calling function {} with arguments {:?} and saving result to variable {}",
-            &self.function_name, self.args, target_variable
-        );
-        println!("Local variables:\n{:?}", local_variables);
-        let mut builtin_functions_locked = BUILTIN_FUNCTIONS.lock().unwrap();
+        result_code: &mut Code,
+        ctx: &mut GeneratorContext,
+    ) -> Result<(), String> {
         match self.function_name.as_str() {
-            builtin_fn if builtin_functions_locked.get(builtin_fn).is_some() => {
-                let local_mangle = format!("_{}", uid);
-                *uid += 1;
+            builtin_fn if BUILTIN_FUNCTIONS.contains_key(builtin_fn) => {
+                let local_mangle = format!("_{}", ctx.uid);
+                ctx.uid += 1;
                 local_variables.push(VariableScope::new(&local_mangle));
 
-                let builtin_fn_generator_fn = builtin_functions_locked.get_mut(builtin_fn).unwrap();
-                println!("Calling builtin function {}", builtin_fn);
+                let builtin_fn_generator_fn = &BUILTIN_FUNCTIONS[builtin_fn];
                 (*builtin_fn_generator_fn)(
                     &self.args,
                     program_ast,
                     local_variables,
                     target_variable,
                     result_code,
-                    uid,
-                );
+                    ctx,
+                )?;
 
                 local_variables.pop();
             }
+            method if method.contains('.') && !program_ast.functions.contains_key(method) => {
+                //Building method like `turret1.shoot(x, y, 1)`
+                let (object, command) = method.rsplit_once('.').unwrap();
+                generate_control(
+                    object,
+                    command,
+                    &self.args,
+                    program_ast,
+                    local_variables,
+                    result_code,
+                    ctx,
+                )?;
+            }
             function_name => {
                 let function_ast = program_ast
                     .functions
                     .get(function_name)
-                    .expect(&format!("Function {} not defined", function_name));
+                    .ok_or_else(|| format!("Function {} not defined", function_name))?;
 
-                match &function_ast.style {
-                    FunctionStyle::Normal => {
-                        let ret_addr_buf = format!("ret_addr_{}", function_name);
-                        let result_buf = format!("{}_result", function_name);
+                //Calls to inline functions are replaced with their body afterwards
+                let ret_addr_buf = return_address_variable(function_name);
+                let result_buf = function_result_variable(function_name);
 
-                        result_code.push_str(&format!(
-                            "op add {} @counter 1\njump {} always\n",
-                            ret_addr_buf, function_name
-                        ));
-                        result_code.push_str(&format!("set {} {}\n", target_variable, result_buf));
+                if self.args.len() != function_ast.params.len() {
+                    return Err(format!(
+                        "Function {} takes {} arguments, but {} were given",
+                        function_name,
+                        function_ast.params.len(),
+                        self.args.len()
+                    ));
+                }
+                //All arguments are evaluated before any parameter is set, since they may
+                //call the same function
+                let param_mangle = function_param_mangle(function_name);
+                let mut param_assignments = Vec::<(String, String)>::new();
+                for (param, arg) in function_ast.params.iter().zip(&self.args) {
+                    match param
+                        .type_name
+                        .as_ref()
+                        .filter(|type_name| program_ast.structs.contains_key(*type_name))
+                    {
+                        Some(type_name) => {
+                            let source =
+                                expect_struct_value(arg, type_name, program_ast, local_variables)?;
+                            for field in program_ast.struct_fields(type_name).unwrap() {
+                                param_assignments.push((
+                                    format!("{}.{}{}", param.name, field, param_mangle),
+                                    mangle_variable(
+                                        &format!("{}.{}", source, field),
+                                        program_ast,
+                                        local_variables,
+                                    )?,
+                                ));
+                            }
+                        }
+                        None => {
+                            let arg_operand =
+                                make_operand(arg, program_ast, local_variables, result_code, ctx)?;
+                            param_assignments
+                                .push((format!("{}{}", param.name, param_mangle), arg_operand));
+                        }
                     }
-                    FunctionStyle::Inline => todo!(),
                 }
+                for (param_mangled, arg_operand) in param_assignments {
+                    result_code.push(Instruction::set(&param_mangled, &arg_operand));
+                }
+
+                result_code.push(Instruction::op("add", &ret_addr_buf, "@counter", "1"));
+                result_code.push(Instruction::jump_always(function_name));
+                result_code.push(Instruction::set(
+                    &mangle_variable(target_variable, program_ast, local_variables)?,
+                    &result_buf,
+                ));
             }
         }
+        Ok(())
     }
 }
@@ -0,0 +1,132 @@
+use crate::mlog_ir::*;
+use crate::mlog_optimizer::is_temporary;
+
+/// Lines of the jumps calling the function
+pub fn call_sites(code: &Code, function_name: &str) -> Vec<usize> {
+    (0..code.lines.len())
+        .filter(|&i| {
+            is_call(&code.lines, i)
+                && code.lines[i]
+                    .as_instruction()
+                    .and_then(Instruction::jump_target)
+                    == Some(function_name)
+        })
+        .collect()
+}
+
+/// Whether the instruction returns through the return address variable
+fn is_return(instruction: &Instruction, return_address: &str) -> bool {
+    match (instruction.opcode, instruction.args.as_slice()) {
+        (Opcode::Set, [counter, Operand::Variable(address)]) => {
+            counter.is_variable("@counter") && address == return_address
+        }
+        _ => false,
+    }
+}
+
+/// Lines of the returns at the end of the body, which only have to fall through to the code
+/// after the call once the body is inlined
+fn trailing_returns(body: &Code, return_address: &str) -> Vec<bool> {
+    let mut trailing = vec![false; body.lines.len()];
+    for (i, line) in body.lines.iter().enumerate().rev() {
+        match line {
+            Line::Label(_) => {}
+            Line::Instruction(instruction) if is_return(instruction, return_address) => {
+                trailing[i] = true;
+            }
+            Line::Instruction(_) => break,
+        }
+    }
+    trailing
+}
+
+/// Estimated number of instructions a copy of the function body takes once optimized. Copies from
+/// and into temporaries are mostly optimized away, and the returns at the end aren't copied.
+pub fn inlined_size(body: &Code, return_address: &str) -> usize {
+    let trailing = trailing_returns(body, return_address);
+    body.lines
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !trailing[*i])
+        .filter_map(|(_, line)| line.as_instruction())
+        .filter(|instruction| {
+            instruction.opcode != Opcode::Set
+                || !instruction
+                    .args
+                    .iter()
+                    .filter_map(Operand::as_variable)
+                    .any(is_temporary)
+        })
+        .count()
+}
+
+/// Replaces every call to the function with a copy of its body, whose returns jump to the end of
+/// the copy. Labels of each copy get a unique suffix, and so does the result variable, which
+/// becomes a temporary read only by the copy of the result right after the call. Returns the
+/// temporaries.
+pub fn inline_calls(
+    code: &mut Code,
+    function_name: &str,
+    return_address: &str,
+    result_variable: &str,
+    body: &Code,
+    uid: &mut usize,
+) -> Vec<String> {
+    let sites = call_sites(code, function_name);
+    let trailing = trailing_returns(body, return_address);
+    let mut temporaries = Vec::new();
+    //From the end, so that the lines of the remaining calls don't move
+    for &site in sites.iter().rev() {
+        let suffix = format!("_inline{}", uid);
+        *uid += 1;
+        let end_label = format!("{}_return{}", function_name, suffix);
+        let result = format!("tmp_{}{}", result_variable, suffix);
+        let labels: Vec<&str> = body
+            .lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Label(label) => Some(label.as_str()),
+                Line::Instruction(_) => None,
+            })
+            .collect();
+
+        let mut copy = Vec::<Line>::new();
+        for (i, line) in body.lines.iter().enumerate() {
+            match line {
+                Line::Label(label) => copy.push(Line::Label(format!("{}{}", label, suffix))),
+                Line::Instruction(_) if trailing[i] => {}
+                Line::Instruction(instruction) if is_return(instruction, return_address) => {
+                    copy.push(Line::Instruction(Instruction::jump_always(&end_label)));
+                }
+                Line::Instruction(instruction) => {
+                    let mut instruction = instruction.clone();
+                    for arg in &mut instruction.args {
+                        match arg {
+                            Operand::Label(label) if labels.contains(&label.as_str()) => {
+                                *label = format!("{}{}", label, suffix);
+                            }
+                            Operand::Variable(variable) if variable == result_variable => {
+                                *variable = result.clone();
+                            }
+                            _ => {}
+                        }
+                    }
+                    copy.push(Line::Instruction(instruction));
+                }
+            }
+        }
+        copy.push(Line::Label(end_label));
+        if let Some(Line::Instruction(copy_of_result)) = code.lines.get_mut(site + 1) {
+            let outputs = copy_of_result.outputs().len();
+            for arg in copy_of_result.args.iter_mut().skip(outputs) {
+                if arg.is_variable(result_variable) {
+                    *arg = Operand::Variable(result.clone());
+                }
+            }
+        }
+        //The return address `op add` and the jump
+        code.lines.splice(site - 1..=site, copy);
+        temporaries.push(result);
+    }
+    temporaries
+}
@@ -0,0 +1,595 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// mlog instructions the generator lowers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Opcode {
+    Read,
+    Write,
+    Print,
+    PrintFlush,
+    GetLink,
+    Control,
+    Radar,
+    Sensor,
+    Set,
+    Op,
+    Select,
+    Stop,
+    End,
+    Jump,
+    UBind,
+    UControl,
+}
+
+impl Opcode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::Read => "read",
+            Opcode::Write => "write",
+            Opcode::Print => "print",
+            Opcode::PrintFlush => "printflush",
+            Opcode::GetLink => "getlink",
+            Opcode::Control => "control",
+            Opcode::Radar => "radar",
+            Opcode::Sensor => "sensor",
+            Opcode::Set => "set",
+            Opcode::Op => "op",
+            Opcode::Select => "select",
+            Opcode::Stop => "stop",
+            Opcode::End => "end",
+            Opcode::Jump => "jump",
+            Opcode::UBind => "ubind",
+            Opcode::UControl => "ucontrol",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operand {
+    /// Variable, including built-in `@` variables and constants
+    Variable(String),
+    /// Number or string literal, or `null`
+    Literal(String),
+    /// Jump target
+    Label(String),
+    /// Argument selecting the variant of the instruction, like `add` in `op add` or jump condition
+    Keyword(String),
+}
+
+impl Operand {
+    /// Operand of a value as the generator formats it, which is either a literal or a variable
+    pub fn value(operand: &str) -> Self {
+        let is_literal = operand
+            .starts_with(|ch: char| ch.is_ascii_digit() || ch == '"' || ch == '%')
+            || (operand.starts_with('-') && operand.len() > 1)
+            || operand == "null";
+        if is_literal {
+            Operand::Literal(operand.into())
+        } else {
+            Operand::Variable(operand.into())
+        }
+    }
+
+    pub fn keyword(keyword: &str) -> Self {
+        Operand::Keyword(keyword.into())
+    }
+
+    pub fn as_variable(&self) -> Option<&str> {
+        match self {
+            Operand::Variable(variable) => Some(variable),
+            _ => None,
+        }
+    }
+
+    pub fn is_variable(&self, name: &str) -> bool {
+        self.as_variable() == Some(name)
+    }
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Variable(text)
+            | Operand::Literal(text)
+            | Operand::Label(text)
+            | Operand::Keyword(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub opcode: Opcode,
+    pub args: Vec<Operand>,
+    /// `@pure_tick` block the instruction was generated in, within which `sensor` reads of the same
+    /// property give the same value
+    pub pure_tick: Option<usize>,
+}
+
+impl Instruction {
+    pub fn new(opcode: Opcode, args: Vec<Operand>) -> Self {
+        Self {
+            opcode,
+            args,
+            pure_tick: None,
+        }
+    }
+
+    pub fn set(target: &str, value: &str) -> Self {
+        Self::new(
+            Opcode::Set,
+            vec![Operand::value(target), Operand::value(value)],
+        )
+    }
+
+    pub fn op(operation: &str, target: &str, lhs: &str, rhs: &str) -> Self {
+        Self::new(
+            Opcode::Op,
+            vec![
+                Operand::keyword(operation),
+                Operand::value(target),
+                Operand::value(lhs),
+                Operand::value(rhs),
+            ],
+        )
+    }
+
+    pub fn jump(label: &str, condition: &str, lhs: &str, rhs: &str) -> Self {
+        Self::new(
+            Opcode::Jump,
+            vec![
+                Operand::Label(label.into()),
+                Operand::keyword(condition),
+                Operand::value(lhs),
+                Operand::value(rhs),
+            ],
+        )
+    }
+
+    pub fn jump_always(label: &str) -> Self {
+        Self::new(
+            Opcode::Jump,
+            vec![Operand::Label(label.into()), Operand::keyword("always")],
+        )
+    }
+
+    /// Indices of arguments the instruction writes to
+    pub fn outputs(&self) -> Vec<usize> {
+        let subcommand = match self.args.first() {
+            Some(Operand::Keyword(keyword)) => keyword.as_str(),
+            _ => "",
+        };
+        match (self.opcode, subcommand) {
+            (Opcode::Set | Opcode::Read | Opcode::Sensor | Opcode::GetLink | Opcode::Select, _) => {
+                vec![0]
+            }
+            (Opcode::Op, _) => vec![1],
+            (Opcode::Radar, _) => vec![6],
+            (Opcode::UControl, "within") => vec![4],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Variables written by the instruction, including implicit ones
+    pub fn written_variables(&self) -> Vec<&str> {
+        let mut written: Vec<&str> = self
+            .outputs()
+            .into_iter()
+            .filter_map(|i| self.args[i].as_variable())
+            .collect();
+        if self.opcode == Opcode::UBind {
+            written.push("@unit");
+        }
+        written
+    }
+
+    /// Variables read by the instruction, with their argument indices
+    pub fn inputs(&self) -> impl Iterator<Item = (usize, &str)> {
+        let outputs = self.outputs();
+        self.args
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| !outputs.contains(i))
+            .filter_map(|(i, arg)| arg.as_variable().map(|variable| (i, variable)))
+    }
+
+    pub fn writes(&self, variable: &str) -> bool {
+        self.written_variables().contains(&variable)
+    }
+
+    /// Whether the instruction only computes its outputs, so it can be removed if they are unused
+    pub fn is_pure(&self) -> bool {
+        matches!(
+            self.opcode,
+            Opcode::Set
+                | Opcode::Op
+                | Opcode::Read
+                | Opcode::Sensor
+                | Opcode::GetLink
+                | Opcode::Select
+        ) && !self.writes("@counter")
+    }
+
+    /// Label the instruction jumps to
+    pub fn jump_target(&self) -> Option<&str> {
+        match (self.opcode, self.args.first()) {
+            (Opcode::Jump, Some(Operand::Label(label))) => Some(label),
+            _ => None,
+        }
+    }
+
+    pub fn is_unconditional_jump(&self) -> bool {
+        self.opcode == Opcode::Jump
+            && matches!(self.args.get(1), Some(Operand::Keyword(condition)) if condition == "always")
+    }
+
+    /// Variable the instruction stores return address into, which is the instruction after the
+    /// following jump
+    pub fn return_address(&self) -> Option<&str> {
+        match (self.opcode, self.args.as_slice()) {
+            (Opcode::Op, [Operand::Keyword(operation), Operand::Variable(target), counter, _])
+                if operation == "add" && counter.is_variable("@counter") =>
+            {
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction jumps into `@counter` jump table right after it
+    pub fn is_jump_table(&self) -> bool {
+        self.return_address() == Some("@counter")
+    }
+
+    /// Whether execution never continues with the next instruction, not counting calls, which
+    /// return to it
+    pub fn is_terminator(&self) -> bool {
+        self.is_unconditional_jump()
+            || matches!(self.opcode, Opcode::End | Opcode::Stop)
+            || self.writes("@counter")
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.opcode.name())?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Line of mlog code, which is either a label or an instruction
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line {
+    Label(String),
+    Instruction(Instruction),
+}
+
+impl Line {
+    pub fn as_instruction(&self) -> Option<&Instruction> {
+        match self {
+            Line::Instruction(instruction) => Some(instruction),
+            Line::Label(_) => None,
+        }
+    }
+}
+
+/// Generated code, printed as mlog text
+#[derive(Clone, Debug, Default)]
+pub struct Code {
+    pub lines: Vec<Line>,
+}
+
+impl Code {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, instruction: Instruction) {
+        self.lines.push(Line::Instruction(instruction));
+    }
+
+    pub fn label(&mut self, label: &str) {
+        self.lines.push(Line::Label(label.into()));
+    }
+
+    pub fn append(&mut self, code: Code) {
+        self.lines.extend(code.lines);
+    }
+
+    /// Number of instructions from the given line on, labels excluded
+    pub fn instruction_count_from(&self, line: usize) -> usize {
+        self.lines[line..]
+            .iter()
+            .filter(|line| matches!(line, Line::Instruction(_)))
+            .count()
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.instruction_count_from(0)
+    }
+
+    /// Variables the code uses, built-in `@` variables excluded
+    pub fn variables(&self) -> BTreeSet<&str> {
+        self.lines
+            .iter()
+            .filter_map(Line::as_instruction)
+            .flat_map(|instruction| instruction.args.iter().filter_map(Operand::as_variable))
+            .filter(|variable| !variable.starts_with('@'))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Label(label) => writeln!(f, "{}:", label)?,
+                Line::Instruction(instruction) => writeln!(f, "{}", instruction)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Code {
+    /// Parses mlog text in the form the generator emits it, for tests of the passes over the code
+    pub fn parse(text: &str) -> Self {
+        const OPCODES: [Opcode; 16] = [
+            Opcode::Read,
+            Opcode::Write,
+            Opcode::Print,
+            Opcode::PrintFlush,
+            Opcode::GetLink,
+            Opcode::Control,
+            Opcode::Radar,
+            Opcode::Sensor,
+            Opcode::Set,
+            Opcode::Op,
+            Opcode::Select,
+            Opcode::Stop,
+            Opcode::End,
+            Opcode::Jump,
+            Opcode::UBind,
+            Opcode::UControl,
+        ];
+        let mut code = Code::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            if let Some(label) = line.strip_suffix(':') {
+                code.label(label);
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let name = words.next().unwrap();
+            let opcode = OPCODES
+                .into_iter()
+                .find(|opcode| opcode.name() == name)
+                .unwrap_or_else(|| panic!("Unknown instruction {}", name));
+            let args = words
+                .enumerate()
+                .map(|(i, word)| match (opcode, i) {
+                    (Opcode::Jump, 0) => Operand::Label(word.into()),
+                    (Opcode::Jump | Opcode::Select, 1)
+                    | (Opcode::Op | Opcode::Control | Opcode::UControl, 0)
+                    | (Opcode::Radar, 0..=3) => Operand::keyword(word),
+                    _ => Operand::value(word),
+                })
+                .collect();
+            code.push(Instruction::new(opcode, args));
+        }
+        code
+    }
+}
+
+/// Instructions which must stay where they are, because their positions are computed at runtime:
+/// jumps into `@counter` jump tables with the table entries up to the next label, and return
+/// address computations with their call jumps
+pub fn pinned_lines(lines: &[Line]) -> Vec<bool> {
+    let mut pinned = vec![false; lines.len()];
+    let mut in_table = false;
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            Line::Label(_) => in_table = false,
+            Line::Instruction(instruction) => {
+                pinned[i] |= in_table;
+                if instruction.is_jump_table() {
+                    pinned[i] = true;
+                    in_table = true;
+                } else if instruction.return_address().is_some() {
+                    pinned[i] = true;
+                    if let Some(next) = pinned.get_mut(i + 1) {
+                        *next = true;
+                    }
+                }
+            }
+        }
+    }
+    pinned
+}
+
+/// Whether the line is a jump to a function, which returns to the next instruction
+pub fn is_call(lines: &[Line], i: usize) -> bool {
+    i > 0
+        && lines[i]
+            .as_instruction()
+            .is_some_and(Instruction::is_unconditional_jump)
+        && lines[i - 1]
+            .as_instruction()
+            .and_then(Instruction::return_address)
+            .is_some_and(|address| address != "@counter")
+}
+
+/// Straight-line code, entered only at the start and left only at the end
+#[derive(Debug)]
+pub struct BasicBlock {
+    /// Range of lines, starting with the labels of the block
+    pub lines: std::ops::Range<usize>,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+}
+
+/// Control-flow graph of the whole program. Calls are edges to the function, and its returns are
+/// edges to every instruction following a call of it, or of a function tail-calling it. `end` and
+/// the end of code lead back to the start.
+#[derive(Debug)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl ControlFlowGraph {
+    pub fn new(lines: &[Line]) -> Self {
+        if lines.is_empty() {
+            return Self { blocks: Vec::new() };
+        }
+        let pinned = pinned_lines(lines);
+        let mut starts = vec![0];
+        //Table entries take one instruction, or two when the first one isn't a jump and is
+        //followed by a jump out of the table, so only entries following a jump can be jumped to
+        let mut table_entries = vec![false; lines.len()];
+        let mut in_table = false;
+        for (i, line) in lines.iter().enumerate() {
+            let starts_block = match line {
+                Line::Label(_) => {
+                    in_table = false;
+                    i > 0 && !matches!(lines[i - 1], Line::Label(_))
+                }
+                Line::Instruction(instruction) => {
+                    let follows_jump = i > 0
+                        && lines[i - 1].as_instruction().is_some_and(|previous| {
+                            previous.jump_target().is_some() || previous.is_terminator()
+                        });
+                    table_entries[i] = in_table && pinned[i] && follows_jump;
+                    if instruction.is_jump_table() {
+                        in_table = true;
+                    }
+                    follows_jump
+                }
+            };
+            if starts_block && *starts.last().unwrap() != i {
+                starts.push(i);
+            }
+        }
+
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(b, start)| BasicBlock {
+                lines: *start..starts.get(b + 1).copied().unwrap_or(lines.len()),
+                successors: Vec::new(),
+                predecessors: Vec::new(),
+            })
+            .collect();
+
+        let mut label_blocks = BTreeMap::<&str, usize>::new();
+        //Blocks following calls, by return address variable
+        let mut return_points = BTreeMap::<&str, Vec<usize>>::new();
+        for (b, block) in blocks.iter().enumerate() {
+            for i in block.lines.clone() {
+                if let Line::Label(label) = &lines[i] {
+                    label_blocks.insert(label, b);
+                }
+            }
+            let last = block.lines.end - 1;
+            if is_call(lines, last) {
+                let address = lines[last - 1]
+                    .as_instruction()
+                    .and_then(Instruction::return_address)
+                    .unwrap();
+                let return_point = if b + 1 < blocks.len() { b + 1 } else { 0 };
+                return_points.entry(address).or_default().push(return_point);
+            }
+        }
+
+        //Tail calls pass the return address of the caller on to the called function
+        let copies: Vec<(&str, &str)> = lines
+            .iter()
+            .filter_map(Line::as_instruction)
+            .filter_map(
+                |instruction| match (instruction.opcode, instruction.args.as_slice()) {
+                    (Opcode::Set, [Operand::Variable(target), Operand::Variable(source)]) => {
+                        Some((target.as_str(), source.as_str()))
+                    }
+                    _ => None,
+                },
+            )
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (target, source) in &copies {
+                let Some(points) = return_points.get(source).cloned() else {
+                    continue;
+                };
+                let target_points = return_points.entry(target).or_default();
+                for point in points {
+                    if !target_points.contains(&point) {
+                        target_points.push(point);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let all_return_points: Vec<usize> = return_points.values().flatten().copied().collect();
+        for b in 0..blocks.len() {
+            let last = blocks[b].lines.end - 1;
+            let next = if b + 1 < blocks.len() { b + 1 } else { 0 };
+            let successors = match lines[last].as_instruction() {
+                None => vec![next],
+                Some(instruction) => {
+                    if let Some(target) = instruction.jump_target() {
+                        let target_block = label_blocks[target];
+                        if instruction.is_unconditional_jump() {
+                            vec![target_block]
+                        } else {
+                            vec![target_block, next]
+                        }
+                    } else if instruction.opcode == Opcode::End {
+                        vec![0]
+                    } else if instruction.opcode == Opcode::Stop {
+                        Vec::new()
+                    } else if instruction.is_jump_table() {
+                        (b + 1..blocks.len())
+                            .take_while(|entry| pinned[blocks[*entry].lines.start])
+                            .filter(|entry| table_entries[blocks[*entry].lines.start])
+                            .collect()
+                    } else if instruction.writes("@counter") {
+                        //Return to wherever the return address points to
+                        match instruction.args.get(1).and_then(Operand::as_variable) {
+                            Some(address) if instruction.opcode == Opcode::Set => return_points
+                                .get(address)
+                                .cloned()
+                                .unwrap_or_else(|| all_return_points.clone()),
+                            _ => all_return_points.clone(),
+                        }
+                    } else {
+                        vec![next]
+                    }
+                }
+            };
+            for successor in &successors {
+                blocks[*successor].predecessors.push(b);
+            }
+            blocks[b].successors = successors;
+        }
+
+        Self { blocks }
+    }
+
+    /// Blocks reachable from the start of the program
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![0];
+        while let Some(b) = stack.pop() {
+            if b >= self.blocks.len() || reachable[b] {
+                continue;
+            }
+            reachable[b] = true;
+            stack.extend(&self.blocks[b].successors);
+        }
+        reachable
+    }
+}
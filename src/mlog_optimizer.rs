@@ -0,0 +1,1019 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::mlog_ir::*;
+
+/// Whether the variable is a temporary introduced by the generator, which isn't visible to the
+/// user and can be renamed or removed
+pub fn is_temporary(variable: &str) -> bool {
+    variable.starts_with("tmp_") || variable.starts_with("blackhole_")
+}
+
+/// Whether execution can continue with the next instruction
+fn falls_through(lines: &[Line], i: usize) -> bool {
+    match &lines[i] {
+        Line::Label(_) => true,
+        Line::Instruction(instruction) => is_call(lines, i) || !instruction.is_terminator(),
+    }
+}
+
+/// Removes labels no jump refers to
+fn remove_unreferenced_labels(lines: &mut Vec<Line>) -> bool {
+    let referenced: BTreeSet<String> = lines
+        .iter()
+        .filter_map(Line::as_instruction)
+        .filter_map(|instruction| instruction.jump_target().map(String::from))
+        .collect();
+    let len = lines.len();
+    lines.retain(|line| match line {
+        Line::Label(label) => referenced.contains(label),
+        Line::Instruction(_) => true,
+    });
+    lines.len() != len
+}
+
+/// Removes instructions of basic blocks which can't be reached from the start of the program.
+/// Their labels are left to [`remove_unreferenced_labels`].
+fn remove_unreachable(lines: &mut Vec<Line>) -> bool {
+    let pinned = pinned_lines(lines);
+    let cfg = ControlFlowGraph::new(lines);
+    let mut keep = vec![true; lines.len()];
+    for (block, reachable) in cfg.blocks.iter().zip(cfg.reachable()) {
+        if reachable {
+            continue;
+        }
+        for i in block.lines.clone() {
+            keep[i] = matches!(lines[i], Line::Label(_)) || pinned[i];
+        }
+    }
+    retain_lines(lines, &keep)
+}
+
+/// Removes jumps to a label right after them
+fn remove_jumps_to_next(lines: &mut Vec<Line>) -> bool {
+    let pinned = pinned_lines(lines);
+    let mut keep = vec![true; lines.len()];
+    for i in 0..lines.len() {
+        let Some(target) = lines[i].as_instruction().and_then(Instruction::jump_target) else {
+            continue;
+        };
+        if pinned[i] {
+            continue;
+        }
+        let jumps_to_next = lines[i + 1..]
+            .iter()
+            .map_while(|line| match line {
+                Line::Label(label) => Some(label),
+                Line::Instruction(_) => None,
+            })
+            .any(|label| label == target);
+        if jumps_to_next {
+            keep[i] = false;
+        }
+    }
+    retain_lines(lines, &keep)
+}
+
+/// Value of the condition of a jump comparing two number literals
+fn constant_condition(instruction: &Instruction) -> Option<bool> {
+    let [_, Operand::Keyword(condition), Operand::Literal(lhs), Operand::Literal(rhs)] =
+        instruction.args.as_slice()
+    else {
+        return None;
+    };
+    let (lhs, rhs) = (lhs.parse::<f64>().ok()?, rhs.parse::<f64>().ok()?);
+    match condition.as_str() {
+        "equal" | "strictEqual" => Some(lhs == rhs),
+        "notEqual" => Some(lhs != rhs),
+        "lessThan" => Some(lhs < rhs),
+        "lessThanEq" => Some(lhs <= rhs),
+        "greaterThan" => Some(lhs > rhs),
+        "greaterThanEq" => Some(lhs >= rhs),
+        _ => None,
+    }
+}
+
+/// Turns jumps with constant conditions into unconditional jumps or removes them
+fn fold_constant_jumps(lines: &mut Vec<Line>) -> bool {
+    let pinned = pinned_lines(lines);
+    let mut keep = vec![true; lines.len()];
+    let mut changed = false;
+    for (i, line) in lines.iter_mut().enumerate() {
+        let Line::Instruction(instruction) = line else {
+            continue;
+        };
+        if instruction.opcode != Opcode::Jump || pinned[i] {
+            continue;
+        }
+        match constant_condition(instruction) {
+            Some(true) => {
+                instruction.args.truncate(1);
+                instruction.args.push(Operand::keyword("always"));
+                changed = true;
+            }
+            Some(false) => keep[i] = false,
+            None => {}
+        }
+    }
+    retain_lines(lines, &keep) || changed
+}
+
+/// Retargets jumps to labels followed by an unconditional jump straight to its target
+fn thread_jumps(lines: &mut [Line]) -> bool {
+    //Label and the target of the unconditional jump it's followed by
+    let mut forwards = BTreeMap::<String, String>::new();
+    let mut labels = Vec::<&str>::new();
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            Line::Label(label) => labels.push(label),
+            Line::Instruction(instruction) => {
+                if instruction.is_unconditional_jump() && !is_call(lines, i) {
+                    let target = instruction.jump_target().unwrap();
+                    for label in &labels {
+                        if *label != target {
+                            forwards.insert(label.to_string(), target.into());
+                        }
+                    }
+                }
+                labels.clear();
+            }
+        }
+    }
+
+    let mut changed = false;
+    for line in lines.iter_mut() {
+        let Line::Instruction(instruction) = line else {
+            continue;
+        };
+        let Some(target) = instruction.jump_target() else {
+            continue;
+        };
+        let mut final_target = target.to_string();
+        let mut visited = BTreeSet::<String>::new();
+        while let Some(next_target) = forwards.get(&final_target) {
+            //Jumps in a cycle never reach any instruction, so they're left alone
+            if !visited.insert(final_target.clone()) {
+                break;
+            }
+            final_target = next_target.clone();
+        }
+        if final_target != target {
+            instruction.args[0] = Operand::Label(final_target);
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn read_counts(lines: &[Line]) -> BTreeMap<&str, usize> {
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for instruction in lines.iter().filter_map(Line::as_instruction) {
+        for (_, arg) in instruction.inputs() {
+            *counts.entry(arg).or_default() += 1;
+        }
+    }
+    counts
+}
+
+fn write_counts(lines: &[Line]) -> BTreeMap<&str, usize> {
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for instruction in lines.iter().filter_map(Line::as_instruction) {
+        for variable in instruction.written_variables() {
+            *counts.entry(variable).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Removes instructions which only write temporaries that are never read
+fn remove_dead_stores(lines: &mut Vec<Line>) -> bool {
+    let pinned = pinned_lines(lines);
+    let reads = read_counts(lines);
+    let keep: Vec<bool> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| match line {
+            Line::Instruction(instruction) if instruction.is_pure() && !pinned[i] => !instruction
+                .written_variables()
+                .iter()
+                .all(|variable| is_temporary(variable) && !reads.contains_key(variable)),
+            _ => true,
+        })
+        .collect();
+    retain_lines(lines, &keep)
+}
+
+/// Removes instructions which only compute values of the given variables. Returns the number of
+/// instructions removed.
+pub fn remove_stores(code: &mut Code, variables: &BTreeSet<String>) -> usize {
+    let instruction_count = code.instruction_count();
+    let pinned = pinned_lines(&code.lines);
+    let keep: Vec<bool> = code
+        .lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| match line {
+            Line::Instruction(instruction) if instruction.is_pure() && !pinned[i] => !instruction
+                .written_variables()
+                .iter()
+                .all(|variable| variables.contains(*variable)),
+            _ => true,
+        })
+        .collect();
+    retain_lines(&mut code.lines, &keep);
+    instruction_count - code.instruction_count()
+}
+
+/// Removes `set tmp x` by replacing reads of the temporary with `x`, when they all follow in the
+/// same straight-line code and `x` doesn't change in between. Also removes `set x x`.
+fn forward_copies(lines: &mut Vec<Line>) -> bool {
+    let pinned = pinned_lines(lines);
+    let mut keep = vec![true; lines.len()];
+    let mut changed = false;
+    for i in 0..lines.len() {
+        let Line::Instruction(copy) = &lines[i] else {
+            continue;
+        };
+        if copy.opcode != Opcode::Set || pinned[i] {
+            continue;
+        }
+        let Some(temporary) = copy.args[0].as_variable().map(String::from) else {
+            continue;
+        };
+        let value = copy.args[1].clone();
+        if value.is_variable(&temporary) && temporary != "@counter" {
+            keep[i] = false;
+            continue;
+        }
+        if !is_temporary(&temporary)
+            || write_counts(lines).get(temporary.as_str()) != Some(&1)
+            || value.is_variable("@counter")
+        {
+            continue;
+        }
+        let total_reads = read_counts(lines)
+            .get(temporary.as_str())
+            .copied()
+            .unwrap_or(0);
+
+        //Reads of the temporary, up to the end of straight-line code
+        let mut uses = Vec::<usize>::new();
+        let mut reads = 0;
+        for (j, line) in lines.iter().enumerate().skip(i + 1) {
+            let Line::Instruction(instruction) = line else {
+                break;
+            };
+            let reads_here = instruction
+                .inputs()
+                .filter(|(_, arg)| **arg == temporary)
+                .count();
+            if reads_here > 0 {
+                uses.push(j);
+                reads += reads_here;
+            }
+            if reads == total_reads
+                || instruction.jump_target().is_some()
+                || !falls_through(lines, j)
+                || value
+                    .as_variable()
+                    .is_some_and(|variable| instruction.writes(variable))
+            {
+                break;
+            }
+        }
+        //Built-in variables may change over time, so they're only moved by one instruction
+        let is_adjacent = uses == [i + 1];
+        let is_builtin = value
+            .as_variable()
+            .is_some_and(|variable| variable.starts_with('@'));
+        if reads != total_reads || uses.is_empty() || (is_builtin && !is_adjacent) {
+            continue;
+        }
+        for j in uses {
+            let Line::Instruction(instruction) = &mut lines[j] else {
+                unreachable!();
+            };
+            let outputs = instruction.outputs();
+            for (k, arg) in instruction.args.iter_mut().enumerate() {
+                if !outputs.contains(&k) && arg.is_variable(&temporary) {
+                    *arg = value.clone();
+                }
+            }
+        }
+        keep[i] = false;
+        changed = true;
+        //Positions of the following instructions stay valid, because removal is deferred
+    }
+    retain_lines(lines, &keep) || changed
+}
+
+/// Replaces `op add tmp a b` followed by `set x tmp` with `op add x a b`, when that's the only
+/// read of the temporary
+fn coalesce_result_copies(lines: &mut Vec<Line>) -> bool {
+    let pinned = pinned_lines(lines);
+    let mut keep = vec![true; lines.len()];
+    let mut changed = false;
+    for i in 1..lines.len() {
+        let (Line::Instruction(definition), Line::Instruction(copy)) = (&lines[i - 1], &lines[i])
+        else {
+            continue;
+        };
+        if copy.opcode != Opcode::Set || pinned[i] || !keep[i - 1] {
+            continue;
+        }
+        let target = copy.args[0].clone();
+        let Some(temporary) = copy.args[1].as_variable().map(String::from) else {
+            continue;
+        };
+        if !is_temporary(&temporary)
+            || target.is_variable("@counter")
+            || target.is_variable(&temporary)
+        {
+            continue;
+        }
+        let Some(output) = definition
+            .outputs()
+            .into_iter()
+            .find(|k| definition.args[*k].is_variable(&temporary))
+        else {
+            continue;
+        };
+        if write_counts(lines).get(temporary.as_str()) != Some(&1)
+            || read_counts(lines).get(temporary.as_str()) != Some(&1)
+        {
+            continue;
+        }
+        let Line::Instruction(definition) = &mut lines[i - 1] else {
+            unreachable!();
+        };
+        definition.args[output] = target;
+        keep[i] = false;
+        changed = true;
+    }
+    retain_lines(lines, &keep) || changed
+}
+
+fn retain_lines(lines: &mut Vec<Line>, keep: &[bool]) -> bool {
+    let len = lines.len();
+    let mut keep = keep.iter();
+    lines.retain(|_| *keep.next().unwrap());
+    lines.len() != len
+}
+
+/// Simplifies generated code until no more patterns match. Returns the number of instructions
+/// removed.
+pub fn peephole(code: &mut Code) -> usize {
+    let instruction_count = code.instruction_count();
+    let lines = &mut code.lines;
+    loop {
+        let mut changed = false;
+        changed |= coalesce_result_copies(lines);
+        changed |= forward_copies(lines);
+        changed |= remove_dead_stores(lines);
+        changed |= fold_constant_jumps(lines);
+        changed |= thread_jumps(lines);
+        changed |= remove_jumps_to_next(lines);
+        changed |= remove_unreachable(lines);
+        changed |= remove_unreferenced_labels(lines);
+        if !changed {
+            break;
+        }
+    }
+    instruction_count - code.instruction_count()
+}
+
+/// Replaces calls right before a return from the calling function with a jump, which passes the
+/// return address of the caller on, so that the called function returns straight to it. Tail
+/// calls of the function itself become plain jumps. `results` are the variables functions return
+/// their values in. They are only read right after the call, so when the caller returns the value
+/// of the called function, both can use the same variable. Returns the number of replaced calls.
+pub fn convert_tail_calls(code: &mut Code, results: &BTreeSet<String>) -> usize {
+    let lines = &mut code.lines;
+    let mut converted = 0;
+    for i in 0..lines.len() {
+        if !is_call(lines, i) {
+            continue;
+        }
+        let mut next = lines[i + 1..]
+            .iter()
+            .filter_map(Line::as_instruction)
+            .map(|next| (next.opcode, next.args.as_slice()));
+        let (caller_address, result_copy) = match (next.next(), next.next()) {
+            (Some((Opcode::Set, [counter, Operand::Variable(address)])), _)
+                if counter.is_variable("@counter") =>
+            {
+                (address.clone(), None)
+            }
+            (
+                Some((Opcode::Set, [Operand::Variable(target), Operand::Variable(source)])),
+                Some((Opcode::Set, [counter, Operand::Variable(address)])),
+            ) if counter.is_variable("@counter")
+                && results.contains(target)
+                && results.contains(source) =>
+            {
+                (address.clone(), Some((target.clone(), source.clone())))
+            }
+            _ => continue,
+        };
+        let callee_address = lines[i - 1]
+            .as_instruction()
+            .and_then(Instruction::return_address)
+            .unwrap()
+            .to_string();
+        //`set x x` is removed by the peephole optimizer
+        lines[i - 1] = Line::Instruction(Instruction::set(&callee_address, &caller_address));
+        if let Some((target, source)) = result_copy {
+            for line in lines.iter_mut() {
+                let Line::Instruction(instruction) = line else {
+                    continue;
+                };
+                for arg in &mut instruction.args {
+                    if arg.is_variable(&target) {
+                        *arg = Operand::Variable(source.clone());
+                    }
+                }
+            }
+        }
+        converted += 1;
+    }
+    converted
+}
+
+/// Moves `op` instructions computing the same value in every iteration of the loop, which spans
+/// the lines from `loop_start` to the end of the code, in front of the loop, along with the copies
+/// of their operands. Only temporaries are moved, since they are written once, and loops with calls
+/// are left alone, since the called function may write anything. Returns the number of moved
+/// operations.
+pub fn hoist_loop_invariants(code: &mut Code, loop_start: usize) -> usize {
+    let lines = &code.lines[loop_start..];
+    if (0..lines.len()).any(|i| is_call(lines, i)) {
+        return 0;
+    }
+    let pinned = pinned_lines(lines);
+    let writes = write_counts(lines);
+    let mut invariant = BTreeSet::<&str>::new();
+    let mut hoisted = vec![false; lines.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, line) in lines.iter().enumerate() {
+            let Line::Instruction(instruction) = line else {
+                continue;
+            };
+            if hoisted[i] || pinned[i] || instruction.args[0] == Operand::keyword("rand") {
+                continue;
+            }
+            //Operands of `op` are copied into temporaries first
+            let Some(target) = (match instruction.opcode {
+                Opcode::Op => instruction.args[1].as_variable(),
+                Opcode::Set => instruction.args[0].as_variable(),
+                _ => None,
+            }) else {
+                continue;
+            };
+            if !is_temporary(target) || writes.get(target) != Some(&1) {
+                continue;
+            }
+            if instruction.inputs().all(|(_, variable)| {
+                !variable.starts_with('@')
+                    && (!writes.contains_key(variable) || invariant.contains(variable))
+            }) {
+                hoisted[i] = true;
+                invariant.insert(target);
+                changed = true;
+            }
+        }
+    }
+
+    //Copies are only worth moving along with the operation using them
+    let hoisted_operations: Vec<&Instruction> = lines
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| hoisted[*i])
+        .filter_map(|(_, line)| line.as_instruction())
+        .filter(|instruction| instruction.opcode == Opcode::Op)
+        .collect();
+    let operands: BTreeSet<&str> = hoisted_operations
+        .iter()
+        .flat_map(|instruction| instruction.inputs().map(|(_, variable)| variable))
+        .collect();
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(copy) = line
+            .as_instruction()
+            .filter(|instruction| hoisted[i] && instruction.opcode == Opcode::Set)
+        {
+            hoisted[i] = copy.args[0]
+                .as_variable()
+                .is_some_and(|target| operands.contains(target));
+        }
+    }
+    let count = hoisted_operations.len();
+
+    let mut kept = Vec::<Line>::new();
+    for (line, hoisted) in code.lines.split_off(loop_start).into_iter().zip(hoisted) {
+        if hoisted {
+            code.lines.push(line);
+        } else {
+            kept.push(line);
+        }
+    }
+    code.lines.extend(kept);
+    count
+}
+
+/// Operations whose operands can be swapped
+const COMMUTATIVE_OPERATIONS: [&str; 11] = [
+    "add",
+    "mul",
+    "equal",
+    "notEqual",
+    "strictEqual",
+    "land",
+    "or",
+    "and",
+    "xor",
+    "max",
+    "min",
+];
+
+/// Value numbers of variables and literals within a basic block. Variables get a new number
+/// whenever they are written, so operands with equal numbers hold equal values.
+#[derive(Default)]
+struct ValueNumbers {
+    variables: BTreeMap<String, usize>,
+    literals: BTreeMap<String, usize>,
+    count: usize,
+}
+
+impl ValueNumbers {
+    fn fresh(&mut self) -> usize {
+        self.count += 1;
+        self.count
+    }
+
+    /// Built-in variables like `@time` change on their own, so they only keep their number within
+    /// `@pure_tick` blocks
+    fn of(&mut self, operand: &Operand, pure_tick: bool) -> usize {
+        let (numbers, name) = match operand {
+            Operand::Variable(variable)
+                if variable.starts_with('@') && (!pure_tick || variable == "@counter") =>
+            {
+                return self.fresh();
+            }
+            Operand::Variable(variable) => (&self.variables, variable),
+            Operand::Literal(literal) => (&self.literals, literal),
+            Operand::Label(_) | Operand::Keyword(_) => return self.fresh(),
+        };
+        if let Some(number) = numbers.get(name) {
+            return *number;
+        }
+        let number = self.fresh();
+        match operand {
+            Operand::Literal(_) => self.literals.insert(name.clone(), number),
+            _ => self.variables.insert(name.clone(), number),
+        };
+        number
+    }
+}
+
+/// Expression computed by the instruction, if it gives the same result for the same operands:
+/// the operation, `@pure_tick` block for `sensor`, and value numbers of the operands
+type Expression = (String, Option<usize>, Vec<usize>);
+
+fn expression(instruction: &Instruction, numbers: &mut ValueNumbers) -> Option<Expression> {
+    match (instruction.opcode, instruction.args.as_slice()) {
+        (Opcode::Op, [Operand::Keyword(operation), _, lhs, rhs]) if operation != "rand" => {
+            let mut operands = vec![numbers.of(lhs, false), numbers.of(rhs, false)];
+            if COMMUTATIVE_OPERATIONS.contains(&operation.as_str()) {
+                operands.sort();
+            }
+            Some((operation.clone(), None, operands))
+        }
+        (Opcode::Sensor, [_, object, property]) if instruction.pure_tick.is_some() => {
+            let operands = vec![numbers.of(object, true), numbers.of(property, true)];
+            Some(("sensor".into(), instruction.pure_tick, operands))
+        }
+        _ => None,
+    }
+}
+
+/// Line ranges of basic blocks
+fn block_ranges(lines: &[Line]) -> Vec<std::ops::Range<usize>> {
+    ControlFlowGraph::new(lines)
+        .blocks
+        .into_iter()
+        .map(|block| block.lines)
+        .collect()
+}
+
+/// Replaces pure `op` instructions computing a value already held by a variable with a copy of
+/// it, within basic blocks. `sensor` reads are reused the same way inside `@pure_tick` blocks.
+/// Returns the number of instructions replaced.
+pub fn eliminate_common_subexpressions(code: &mut Code) -> usize {
+    let lines = &mut code.lines;
+    let pinned = pinned_lines(lines);
+    let mut replaced = 0;
+    for block in block_ranges(lines) {
+        let mut numbers = ValueNumbers::default();
+        //Value number of the expression result, with the variable it was stored in
+        let mut available = BTreeMap::<Expression, (usize, String)>::new();
+        for i in block {
+            let Line::Instruction(instruction) = &lines[i] else {
+                continue;
+            };
+            let target = instruction
+                .outputs()
+                .first()
+                .and_then(|output| instruction.args[*output].as_variable())
+                .filter(|target| !target.starts_with('@'))
+                .map(String::from);
+            let expression = expression(instruction, &mut numbers);
+            match (target, expression) {
+                (Some(target), Some(expression)) if !pinned[i] => {
+                    match available.get(&expression) {
+                        Some((value, holder)) if numbers.variables.get(holder) == Some(value) => {
+                            let value = *value;
+                            lines[i] = Line::Instruction(Instruction::set(&target, holder));
+                            numbers.variables.insert(target, value);
+                            replaced += 1;
+                        }
+                        _ => {
+                            let value = numbers.fresh();
+                            numbers.variables.insert(target.clone(), value);
+                            available.insert(expression, (value, target));
+                        }
+                    }
+                }
+                (Some(target), None) if instruction.opcode == Opcode::Set => {
+                    let value = numbers.of(&instruction.args[1], false);
+                    numbers.variables.insert(target, value);
+                }
+                _ => {
+                    for variable in instruction.written_variables() {
+                        let value = numbers.fresh();
+                        numbers.variables.insert(variable.into(), value);
+                    }
+                }
+            }
+        }
+    }
+    replaced
+}
+
+/// Replaces reads of variables copied with `set` by the copied value, within basic blocks.
+/// Returns the number of operands replaced.
+pub fn propagate_copies(code: &mut Code) -> usize {
+    let lines = &mut code.lines;
+    let mut replaced = 0;
+    for block in block_ranges(lines) {
+        let mut copies = BTreeMap::<String, Operand>::new();
+        for line in &mut lines[block] {
+            let Line::Instruction(instruction) = line else {
+                continue;
+            };
+            let outputs = instruction.outputs();
+            for (k, arg) in instruction.args.iter_mut().enumerate() {
+                if outputs.contains(&k) {
+                    continue;
+                }
+                if let Some(value) = arg.as_variable().and_then(|variable| copies.get(variable)) {
+                    *arg = value.clone();
+                    replaced += 1;
+                }
+            }
+            for written in instruction.written_variables() {
+                copies.remove(written);
+                copies.retain(|_, value| !value.is_variable(written));
+            }
+            //Built-in variables may change before the copy is read
+            if let (Opcode::Set, [Operand::Variable(target), value]) =
+                (instruction.opcode, instruction.args.as_slice())
+            {
+                let is_builtin = value
+                    .as_variable()
+                    .is_some_and(|variable| variable.starts_with('@'));
+                if !target.starts_with('@') && !is_builtin && !value.is_variable(target) {
+                    copies.insert(target.clone(), value.clone());
+                }
+            }
+        }
+    }
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mlog_emulator::{Emulator, Processor, Value};
+
+    /// Marks `sensor` instructions as read within the given `@pure_tick` block
+    fn in_pure_tick(code: &mut Code, block: usize) {
+        for line in &mut code.lines {
+            if let Line::Instruction(instruction) = line {
+                if instruction.opcode == Opcode::Sensor {
+                    instruction.pure_tick = Some(block);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cse_reuses_operation_with_same_operands() {
+        let mut code = Code::parse(
+            "
+            op add x a b
+            op add y b a
+            print y
+            ",
+        );
+        assert_eq!(eliminate_common_subexpressions(&mut code), 1);
+        assert_eq!(code.to_string(), "op add x a b\nset y x\nprint y\n");
+    }
+
+    #[test]
+    fn cse_keeps_operation_after_operand_redefinition() {
+        let mut code = Code::parse(
+            "
+            op add x a b
+            set a 5
+            op add y a b
+            print x
+            print y
+            ",
+        );
+        let original = code.to_string();
+        assert_eq!(eliminate_common_subexpressions(&mut code), 0);
+        assert_eq!(code.to_string(), original);
+    }
+
+    #[test]
+    fn cse_keeps_operation_after_holder_redefinition() {
+        let mut code = Code::parse(
+            "
+            op mul x a 2
+            set x 0
+            op mul y a 2
+            print y
+            ",
+        );
+        assert_eq!(eliminate_common_subexpressions(&mut code), 0);
+    }
+
+    #[test]
+    fn cse_never_merges_rand() {
+        let mut code = Code::parse(
+            "
+            op rand x 10 0
+            op rand y 10 0
+            print x
+            print y
+            ",
+        );
+        assert_eq!(eliminate_common_subexpressions(&mut code), 0);
+    }
+
+    #[test]
+    fn cse_keeps_operation_on_builtin_variable() {
+        //`@time` may change between the two operations
+        let mut code = Code::parse(
+            "
+            op add x @time 1
+            op add y @time 1
+            print x
+            print y
+            ",
+        );
+        assert_eq!(eliminate_common_subexpressions(&mut code), 0);
+    }
+
+    #[test]
+    fn cse_merges_sensor_only_inside_pure_tick() {
+        let text = "
+            sensor x @unit @health
+            sensor y @unit @health
+            print x
+            print y
+            ";
+        let mut code = Code::parse(text);
+        assert_eq!(eliminate_common_subexpressions(&mut code), 0);
+
+        let mut code = Code::parse(text);
+        in_pure_tick(&mut code, 0);
+        assert_eq!(eliminate_common_subexpressions(&mut code), 1);
+        assert_eq!(
+            code.to_string(),
+            "sensor x @unit @health\nset y x\nprint x\nprint y\n"
+        );
+    }
+
+    #[test]
+    fn cse_keeps_sensor_of_other_pure_tick_block() {
+        let mut code = Code::parse(
+            "
+            sensor x @unit @health
+            sensor y @unit @health
+            print x
+            print y
+            ",
+        );
+        in_pure_tick(&mut code, 0);
+        if let Line::Instruction(instruction) = &mut code.lines[1] {
+            instruction.pure_tick = Some(1);
+        }
+        assert_eq!(eliminate_common_subexpressions(&mut code), 0);
+    }
+
+    #[test]
+    fn copy_propagation_replaces_reads_of_copy() {
+        let mut code = Code::parse(
+            "
+            set t a
+            op add x t 1
+            print t
+            ",
+        );
+        assert_eq!(propagate_copies(&mut code), 2);
+        assert_eq!(code.to_string(), "set t a\nop add x a 1\nprint a\n");
+    }
+
+    #[test]
+    fn copy_is_killed_by_write_to_its_source() {
+        let mut code = Code::parse(
+            "
+            set t a
+            set a 5
+            print t
+            ",
+        );
+        assert_eq!(propagate_copies(&mut code), 0);
+        assert_eq!(code.to_string(), "set t a\nset a 5\nprint t\n");
+    }
+
+    #[test]
+    fn copy_is_killed_by_write_to_its_target() {
+        let mut code = Code::parse(
+            "
+            set t a
+            op add t t 1
+            print t
+            ",
+        );
+        assert_eq!(propagate_copies(&mut code), 1);
+        assert_eq!(code.to_string(), "set t a\nop add t a 1\nprint t\n");
+    }
+
+    #[test]
+    fn copy_of_builtin_variable_is_not_propagated() {
+        let mut code = Code::parse(
+            "
+            set t @time
+            print t
+            ",
+        );
+        assert_eq!(propagate_copies(&mut code), 0);
+    }
+
+    #[test]
+    fn tail_calls_pass_the_return_address_on() {
+        //`f` tail-calls `g`, which tail-calls `h`, so `h` returns straight to `main`
+        let mut code = Code::parse(
+            "
+            op add ret_addr_f @counter 1
+            jump f always
+            end
+            f:
+            op add ret_addr_g @counter 1
+            jump g always
+            set @counter ret_addr_f
+            g:
+            op add ret_addr_h @counter 1
+            jump h always
+            set @counter ret_addr_g
+            h:
+            print 1
+            set @counter ret_addr_h
+            ",
+        );
+        assert_eq!(convert_tail_calls(&mut code, &BTreeSet::new()), 2);
+        //The return points of `h` are found through the copies, so `end` stays reachable
+        peephole(&mut code);
+        assert_eq!(
+            code.to_string(),
+            "op add ret_addr_f @counter 1\njump f always\nend\nf:\n\
+             set ret_addr_g ret_addr_f\nset ret_addr_h ret_addr_g\nprint 1\n\
+             set @counter ret_addr_h\n"
+        );
+        let mut emulator = Emulator::new(&code.to_string(), Processor::Micro).unwrap();
+        emulator.run(3).unwrap();
+        assert_eq!(emulator.variable("ret_addr_h"), Value::number(2.0));
+        assert_eq!(emulator.executed(), 6);
+    }
+
+    #[test]
+    fn returned_result_of_tail_call_shares_its_variable() {
+        //`f` returns what `g` returns, so callers of `f` read `g_result`
+        let mut code = Code::parse(
+            "
+            op add ret_addr_f @counter 1
+            jump f always
+            print f_result
+            end
+            f:
+            jump g_call notEqual x 0
+            set f_result 0
+            set @counter ret_addr_f
+            g_call:
+            op add ret_addr_g @counter 1
+            jump g always
+            set f_result g_result
+            set @counter ret_addr_f
+            g:
+            set g_result 1
+            set @counter ret_addr_g
+            ",
+        );
+        let results = ["f_result", "g_result"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(convert_tail_calls(&mut code, &results), 1);
+        peephole(&mut code);
+        assert_eq!(
+            code.to_string(),
+            "op add ret_addr_f @counter 1\njump f always\nprint g_result\nend\nf:\n\
+             jump g_call notEqual x 0\nset g_result 0\nset @counter ret_addr_f\ng_call:\n\
+             set ret_addr_g ret_addr_f\nset g_result 1\nset @counter ret_addr_g\n"
+        );
+    }
+
+    #[test]
+    fn calls_not_followed_by_return_are_kept() {
+        let mut code = Code::parse(
+            "
+            op add ret_addr_f @counter 1
+            jump f always
+            print 1
+            end
+            f:
+            set @counter ret_addr_f
+            ",
+        );
+        assert_eq!(convert_tail_calls(&mut code, &BTreeSet::new()), 0);
+    }
+
+    /// Loop over the lines from 1 on, computing `a * 2` in every iteration
+    fn loop_with(body: &str) -> Code {
+        Code::parse(&format!(
+            "
+            set a 3
+            loop:
+            set tmp_1 a
+            op mul tmp_2 tmp_1 2
+            print tmp_2
+            {}
+            jump loop always
+            ",
+            body
+        ))
+    }
+
+    #[test]
+    fn invariant_operation_is_hoisted_with_its_operand_copy() {
+        let mut code = loop_with("");
+        assert_eq!(hoist_loop_invariants(&mut code, 1), 1);
+        assert_eq!(
+            code.to_string(),
+            "set a 3\nset tmp_1 a\nop mul tmp_2 tmp_1 2\nloop:\nprint tmp_2\njump loop always\n"
+        );
+    }
+
+    #[test]
+    fn loop_with_call_is_not_hoisted_from() {
+        let mut code = loop_with("op add ret_addr_f @counter 1\njump f always");
+        let original = code.to_string();
+        assert_eq!(hoist_loop_invariants(&mut code, 1), 0);
+        assert_eq!(code.to_string(), original);
+    }
+
+    #[test]
+    fn operation_on_variable_written_in_loop_is_not_hoisted() {
+        let mut code = loop_with("op add a a 1");
+        assert_eq!(hoist_loop_invariants(&mut code, 1), 0);
+    }
+
+    #[test]
+    fn rand_and_builtin_inputs_are_not_hoisted() {
+        let mut code = Code::parse(
+            "
+            loop:
+            op rand tmp_1 10 0
+            op add tmp_2 @time 1
+            print tmp_1
+            print tmp_2
+            jump loop always
+            ",
+        );
+        let original = code.to_string();
+        assert_eq!(hoist_loop_invariants(&mut code, 0), 0);
+        assert_eq!(code.to_string(), original);
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::mlog_ir::*;
+
+/// Prefix of the names variables are allocated to
+const REGISTER_PREFIX: &str = "reg_";
+
+/// Variables related to each variable
+type Graph<'a> = BTreeMap<&'a str, BTreeSet<&'a str>>;
+
+/// Updates variables live before the instruction, given the ones live after it
+fn transfer<'a>(
+    instruction: &'a Instruction,
+    live: &mut BTreeSet<&'a str>,
+    tracked: &BTreeSet<&str>,
+) {
+    for variable in instruction.written_variables() {
+        live.remove(variable);
+    }
+    for (_, variable) in instruction.inputs() {
+        if tracked.contains(variable) {
+            live.insert(variable);
+        }
+    }
+}
+
+/// Tracked variables live at the start of each basic block, which may be read before they are
+/// written again
+fn live_in<'a>(
+    lines: &'a [Line],
+    cfg: &ControlFlowGraph,
+    tracked: &BTreeSet<&str>,
+) -> Vec<BTreeSet<&'a str>> {
+    let mut live_in = vec![BTreeSet::<&str>::new(); cfg.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (b, block) in cfg.blocks.iter().enumerate().rev() {
+            let mut live: BTreeSet<&str> = block
+                .successors
+                .iter()
+                .flat_map(|successor| live_in[*successor].iter().copied())
+                .collect();
+            for instruction in lines[block.lines.clone()]
+                .iter()
+                .rev()
+                .filter_map(Line::as_instruction)
+            {
+                transfer(instruction, &mut live, tracked);
+            }
+            if live != live_in[b] {
+                live_in[b] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+/// Pairs of tracked variables which are live at the same time, so they can't share a name, and
+/// pairs of variables copied into each other, which are better off sharing it
+fn interference<'a>(
+    lines: &'a [Line],
+    cfg: &ControlFlowGraph,
+    live_in: &[BTreeSet<&'a str>],
+    tracked: &BTreeSet<&str>,
+) -> (Graph<'a>, Graph<'a>) {
+    let mut interferes = Graph::new();
+    let mut copies = Graph::new();
+    for block in &cfg.blocks {
+        let mut live: BTreeSet<&str> = block
+            .successors
+            .iter()
+            .flat_map(|successor| live_in[*successor].iter().copied())
+            .collect();
+        for instruction in lines[block.lines.clone()]
+            .iter()
+            .rev()
+            .filter_map(Line::as_instruction)
+        {
+            //The source of a copy holds the same value, so it doesn't interfere with the target
+            let copied = match (instruction.opcode, instruction.args.as_slice()) {
+                (Opcode::Set, [Operand::Variable(target), Operand::Variable(source)])
+                    if tracked.contains(target.as_str()) && tracked.contains(source.as_str()) =>
+                {
+                    copies.entry(target).or_default().insert(source);
+                    copies.entry(source).or_default().insert(target);
+                    Some(source.as_str())
+                }
+                _ => None,
+            };
+            for written in instruction.written_variables() {
+                if !tracked.contains(written) {
+                    continue;
+                }
+                for other in &live {
+                    if *other != written && Some(*other) != copied {
+                        interferes.entry(written).or_default().insert(other);
+                        interferes.entry(other).or_default().insert(written);
+                    }
+                }
+            }
+            transfer(instruction, &mut live, tracked);
+        }
+    }
+    (interferes, copies)
+}
+
+/// Lines which only write tracked variables that aren't read before they are written again
+fn dead_stores(
+    lines: &[Line],
+    cfg: &ControlFlowGraph,
+    live_in: &[BTreeSet<&str>],
+    tracked: &BTreeSet<&str>,
+) -> Vec<bool> {
+    let pinned = pinned_lines(lines);
+    let mut dead = vec![false; lines.len()];
+    for block in &cfg.blocks {
+        let mut live: BTreeSet<&str> = block
+            .successors
+            .iter()
+            .flat_map(|successor| live_in[*successor].iter().copied())
+            .collect();
+        for i in block.lines.clone().rev() {
+            let Line::Instruction(instruction) = &lines[i] else {
+                continue;
+            };
+            let written = instruction.written_variables();
+            if instruction.is_pure()
+                && !pinned[i]
+                && written
+                    .iter()
+                    .all(|variable| tracked.contains(variable) && !live.contains(variable))
+            {
+                dead[i] = true;
+                continue;
+            }
+            transfer(instruction, &mut live, tracked);
+        }
+    }
+    dead
+}
+
+/// Renames local variables and temporaries to a small pool of names, reusing a name once the
+/// variable holding it is no longer needed. `locals` are the variables which aren't visible
+/// outside of the code, everything else keeps its name. Stores which are never read and copies
+/// between variables sharing a name are removed. Returns the number of variables renamed and the
+/// number of names they got.
+pub fn allocate_registers(code: &mut Code, locals: &BTreeSet<String>) -> (usize, usize) {
+    let lines = &code.lines;
+    let cfg = ControlFlowGraph::new(lines);
+    let mut tracked: BTreeSet<&str> = lines
+        .iter()
+        .filter_map(Line::as_instruction)
+        .flat_map(|instruction| instruction.args.iter().filter_map(Operand::as_variable))
+        .filter(|variable| locals.contains(*variable))
+        .collect();
+    let live_in = live_in(lines, &cfg, &tracked);
+    //Variables read before they are written rely on their initial or previous value
+    if let Some(entry) = live_in.first() {
+        for variable in entry {
+            tracked.remove(variable);
+        }
+    }
+    let (interferes, copies) = interference(lines, &cfg, &live_in, &tracked);
+    let dead = dead_stores(lines, &cfg, &live_in, &tracked);
+
+    //Variables in the order they appear in, so that the names follow the code
+    let mut order = Vec::<&str>::new();
+    for variable in lines
+        .iter()
+        .filter_map(Line::as_instruction)
+        .flat_map(|instruction| instruction.args.iter().filter_map(Operand::as_variable))
+    {
+        if tracked.contains(variable) && !order.contains(&variable) {
+            order.push(variable);
+        }
+    }
+
+    let no_neighbours = BTreeSet::new();
+    let mut colors = BTreeMap::<&str, usize>::new();
+    for variable in &order {
+        let neighbours = interferes.get(variable).unwrap_or(&no_neighbours);
+        let taken: BTreeSet<usize> = neighbours
+            .iter()
+            .filter_map(|neighbour| colors.get(neighbour).copied())
+            .collect();
+        let preferred = copies
+            .get(variable)
+            .into_iter()
+            .flatten()
+            .filter_map(|partner| colors.get(partner).copied())
+            .find(|color| !taken.contains(color));
+        let color =
+            preferred.unwrap_or_else(|| (0..).find(|color| !taken.contains(color)).unwrap());
+        colors.insert(variable, color);
+    }
+
+    //Names which don't clash with the variables left as they are
+    let existing = code.variables();
+    let mut names = Vec::<String>::new();
+    let color_count = colors.values().map(|color| color + 1).max().unwrap_or(0);
+    let mut index = 0;
+    while names.len() < color_count {
+        let name = format!("{}{}", REGISTER_PREFIX, index);
+        index += 1;
+        if !existing.contains(name.as_str()) || tracked.contains(name.as_str()) {
+            names.push(name);
+        }
+    }
+    let renames: BTreeMap<String, String> = colors
+        .into_iter()
+        .map(|(variable, color)| (variable.to_string(), names[color].clone()))
+        .collect();
+
+    let mut dead = dead.into_iter();
+    code.lines.retain(|_| !dead.next().unwrap());
+    for line in &mut code.lines {
+        let Line::Instruction(instruction) = line else {
+            continue;
+        };
+        for arg in &mut instruction.args {
+            if let Some(name) = arg.as_variable().and_then(|variable| renames.get(variable)) {
+                *arg = Operand::Variable(name.clone());
+            }
+        }
+    }
+    code.lines.retain(|line| {
+        !matches!(
+            line.as_instruction().map(|instruction| (instruction.opcode, instruction.args.as_slice())),
+            Some((Opcode::Set, [Operand::Variable(target), Operand::Variable(source)]))
+                if target == source && names.contains(target)
+        )
+    });
+    (renames.len(), color_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locals(variables: &[&str]) -> BTreeSet<String> {
+        variables
+            .iter()
+            .map(|variable| variable.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn variables_live_across_call_get_different_names() {
+        //`a` is read after the call returns, so it's live while `f` writes `b`
+        let mut code = Code::parse(
+            "
+            set a 1
+            op add ret_addr_f @counter 1
+            jump f always
+            print a
+            end
+            f:
+            set b 2
+            print b
+            set @counter ret_addr_f
+            ",
+        );
+        assert_eq!(allocate_registers(&mut code, &locals(&["a", "b"])), (2, 2));
+        assert_eq!(
+            code.to_string(),
+            "set reg_0 1\nop add ret_addr_f @counter 1\njump f always\nprint reg_0\nend\nf:\n\
+             set reg_1 2\nprint reg_1\nset @counter ret_addr_f\n"
+        );
+    }
+
+    #[test]
+    fn variables_live_at_entry_keep_their_names() {
+        //`x` is read before it's written, so it relies on the value from the previous run
+        let mut code = Code::parse(
+            "
+            print x
+            set x 1
+            set t 2
+            print t
+            end
+            ",
+        );
+        assert_eq!(allocate_registers(&mut code, &locals(&["x", "t"])), (1, 1));
+        assert_eq!(
+            code.to_string(),
+            "print x\nset x 1\nset reg_0 2\nprint reg_0\nend\n"
+        );
+    }
+
+    #[test]
+    fn copies_prefer_the_name_of_the_copied_variable() {
+        //`b` could take the name `c` had, but sharing the name of `a` makes the copy a no-op, so it's
+        //removed
+        let mut code = Code::parse(
+            "
+            set c 1
+            set a 2
+            print c
+            set b a
+            print b
+            end
+            ",
+        );
+        assert_eq!(
+            allocate_registers(&mut code, &locals(&["a", "b", "c"])),
+            (3, 2)
+        );
+        assert_eq!(
+            code.to_string(),
+            "set reg_0 1\nset reg_1 2\nprint reg_0\nprint reg_1\nend\n"
+        );
+    }
+
+    #[test]
+    fn stores_overwritten_before_read_are_removed() {
+        //The first store to `k` is overwritten, and `x` is never read
+        let mut code = Code::parse(
+            "
+            set k 1
+            set k 2
+            print k
+            set x k
+            end
+            ",
+        );
+        assert_eq!(allocate_registers(&mut code, &locals(&["k", "x"])), (2, 1));
+        assert_eq!(code.to_string(), "set reg_0 2\nprint reg_0\nend\n");
+    }
+
+    #[test]
+    fn names_skip_user_variables_with_register_names() {
+        let mut code = Code::parse(
+            "
+            set t 1
+            print t
+            print reg_0
+            end
+            ",
+        );
+        assert_eq!(allocate_registers(&mut code, &locals(&["t"])), (1, 1));
+        assert_eq!(
+            code.to_string(),
+            "set reg_1 1\nprint reg_1\nprint reg_0\nend\n"
+        );
+    }
+}
@@ -1,29 +1,248 @@
 use std::collections::BTreeMap;
 
+use crate::mlog_constants::is_link_name;
 use crate::tokenizer::*;
 
 #[derive(Debug)]
 pub struct ProgramAST {
     pub(crate) variables: BTreeMap<String, GlobalVariableAST>,
     pub(crate) functions: BTreeMap<String, FunctionAST>,
+    pub(crate) arrays: BTreeMap<String, ArrayDeclarationAST>,
+    pub(crate) structs: BTreeMap<String, StructAST>,
+    pub(crate) enums: BTreeMap<String, EnumAST>,
+    /// Linked buildings by the name used in code, which is either the alias or the link name
+    pub(crate) links: BTreeMap<String, LinkAST>,
 }
 impl ProgramAST {
     fn new() -> Self {
         Self {
             variables: BTreeMap::new(),
             functions: BTreeMap::new(),
+            arrays: BTreeMap::new(),
+            structs: BTreeMap::new(),
+            enums: BTreeMap::new(),
+            links: BTreeMap::new(),
         }
     }
+
+    /// Integer value of `Enum.Variant` path. Returns None if the path doesn't start with an enum
+    /// name.
+    fn enum_variant_value(&self, path: &str) -> Option<Result<f64, String>> {
+        let (enum_name, variant_name) = path.split_once('.')?;
+        let enum_ast = self.enums.get(enum_name)?;
+        Some(
+            enum_ast
+                .variants
+                .iter()
+                .position(|variant| variant == variant_name)
+                .map(|value| value as f64)
+                .ok_or_else(|| format!("Enum {} has no variant {}", enum_name, variant_name)),
+        )
+    }
+
+    /// Replaces enum variants with their values and checks that matches on enums cover all
+    /// variants, and that no value is matched twice
+    fn resolve_enum_variants(&mut self) -> Result<(), String> {
+        let mut functions = std::mem::take(&mut self.functions);
+        let mut result = Ok(());
+        for function_ast in functions.values_mut() {
+            result = visit_statements_mut(&mut function_ast.statements, &mut |statement| {
+                if let StatementASTNode::MatchAST(match_ast) = statement {
+                    self.resolve_match_patterns(match_ast)?;
+                }
+                for expression in statement.expressions_mut() {
+                    visit_expression_mut(expression, &mut |expression| {
+                        if let ExpressionASTNode::VariableReference(vr) = expression {
+                            if let Some(value) = self.enum_variant_value(vr) {
+                                *expression = ExpressionASTNode::NumberLiteral(value?);
+                            }
+                        }
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            });
+            if result.is_err() {
+                break;
+            }
+        }
+        self.functions = functions;
+        result
+    }
+
+    fn resolve_match_patterns(&self, match_ast: &mut MatchAST) -> Result<(), String> {
+        let mut matched_enum: Option<String> = None;
+        let mut covered_variants = Vec::<String>::new();
+        let mut matched_values = Vec::<f64>::new();
+        let mut has_wildcard = false;
+        for arm in &mut match_ast.arms {
+            for pattern in &mut arm.patterns {
+                let pattern_value = match pattern {
+                    MatchPatternAST::EnumVariant(path) => self
+                        .enum_variant_value(path)
+                        .map(|value| value.map(|value| (value, path.clone()))),
+                    MatchPatternAST::NumberLiteral(value) => Some(Ok((*value, value.to_string()))),
+                    MatchPatternAST::Wildcard => None,
+                };
+                if let Some((value, pattern_name)) = pattern_value.transpose()? {
+                    if matched_values.contains(&value) {
+                        return Err(format!(
+                            "Match pattern {} repeats a value matched earlier",
+                            pattern_name
+                        ));
+                    }
+                    matched_values.push(value);
+                }
+                match pattern {
+                    MatchPatternAST::EnumVariant(path) => {
+                        let value = self
+                            .enum_variant_value(path)
+                            .unwrap_or_else(|| Err(format!("{} is not an enum variant", path)))?;
+                        let (enum_name, variant_name) = path.split_once('.').unwrap();
+                        if let Some(other_enum) =
+                            matched_enum.as_ref().filter(|other| *other != enum_name)
+                        {
+                            return Err(format!(
+                                "Match mixes variants of enums {} and {}",
+                                other_enum, enum_name
+                            ));
+                        }
+                        matched_enum = Some(enum_name.into());
+                        covered_variants.push(variant_name.into());
+                        *pattern = MatchPatternAST::NumberLiteral(value);
+                    }
+                    MatchPatternAST::Wildcard => has_wildcard = true,
+                    MatchPatternAST::NumberLiteral(_) => {}
+                }
+            }
+        }
+
+        if let Some(enum_name) = matched_enum {
+            let missing_variants: Vec<&str> = self.enums[&enum_name]
+                .variants
+                .iter()
+                .filter(|variant| !covered_variants.contains(variant))
+                .map(String::as_str)
+                .collect();
+            if !has_wildcard && !missing_variants.is_empty() {
+                return Err(format!(
+                    "Match on enum {} doesn't cover variants {}",
+                    enum_name,
+                    missing_variants.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// All members of the struct, nested ones included (e.g. `pos` followed by `pos.x`), with
+    /// their types. Returns None if the type isn't a struct.
+    pub(crate) fn struct_members(&self, type_name: &str) -> Option<Vec<(String, Option<String>)>> {
+        let struct_ast = self.structs.get(type_name)?;
+        let mut members = Vec::new();
+        for field in &struct_ast.fields {
+            members.push((field.name.clone(), field.type_name.clone()));
+            if let Some(nested_members) = field
+                .type_name
+                .as_ref()
+                .and_then(|field_type| self.struct_members(field_type))
+            {
+                for (nested_name, nested_type) in nested_members {
+                    members.push((format!("{}.{}", field.name, nested_name), nested_type));
+                }
+            }
+        }
+        Some(members)
+    }
+
+    /// Scalar members of the struct, which hold its values
+    pub(crate) fn struct_fields(&self, type_name: &str) -> Option<Vec<String>> {
+        self.struct_members(type_name).map(|members| {
+            members
+                .into_iter()
+                .filter(|(_, member_type)| {
+                    !member_type
+                        .as_ref()
+                        .is_some_and(|member_type| self.structs.contains_key(member_type))
+                })
+                .map(|(member_name, _)| member_name)
+                .collect()
+        })
+    }
+
+    /// Struct containing itself couldn't be expanded into variables
+    fn check_struct_recursion(
+        &self,
+        type_name: &str,
+        path: &mut Vec<String>,
+    ) -> Result<(), String> {
+        if path.iter().any(|visited| visited == type_name) {
+            return Err(format!(
+                "Struct {} contains itself through {}",
+                type_name,
+                path.join(" -> ")
+            ));
+        }
+        if let Some(struct_ast) = self.structs.get(type_name) {
+            path.push(type_name.into());
+            for field in &struct_ast.fields {
+                if let Some(field_type) = &field.type_name {
+                    self.check_struct_recursion(field_type, path)?;
+                }
+            }
+            path.pop();
+        }
+        Ok(())
+    }
 }
 
 enum ProgramASTNode {
     GlobalVariableAST(GlobalVariableAST),
+    ArrayDeclarationAST(ArrayDeclarationAST),
+    StructAST(StructAST),
+    EnumAST(EnumAST),
+    LinkAST(LinkAST),
     FunctionAST(FunctionAST),
 }
 
 #[derive(Debug)]
 pub struct GlobalVariableAST {
     name: String,
+    pub(crate) type_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct StructFieldAST {
+    pub(crate) name: String,
+    pub(crate) type_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct StructAST {
+    name: String,
+    pub(crate) fields: Vec<StructFieldAST>,
+}
+
+/// Building linked to the processor, optionally referred to by an alias
+#[derive(Clone, Debug)]
+pub struct LinkAST {
+    pub(crate) link_name: String,
+    pub(crate) alias: Option<String>,
+    pub(crate) type_name: Option<String>,
+}
+
+impl LinkAST {
+    /// Name the link is referred to by in code
+    pub(crate) fn name(&self) -> &str {
+        self.alias.as_ref().unwrap_or(&self.link_name)
+    }
+}
+
+/// Enum whose variants are numbered from 0 in order of definition
+#[derive(Debug)]
+pub struct EnumAST {
+    name: String,
+    pub(crate) variants: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -34,14 +253,33 @@ pub(crate) enum FunctionStyle {
 
 #[derive(Debug)]
 pub struct FunctionAST {
-    name: String,
+    pub(crate) name: String,
+    pub(crate) params: Vec<LocalVariableAST>,
     pub(crate) statements: Vec<StatementASTNode>,
     pub(crate) style: FunctionStyle,
+    /// Set by `#[noinline]`, keeps the function from being inlined automatically
+    pub(crate) noinline: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct LocalVariableAST {
     pub(crate) name: String,
+    pub(crate) type_name: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum ArrayStorageAST {
+    /// Consecutive slots of memory cell or bank, starting at given offset
+    Memory { cell: String, offset: usize },
+    /// One variable per element, indexed through `@counter` jump table
+    Registers,
+}
+
+#[derive(Clone, Debug)]
+pub struct ArrayDeclarationAST {
+    pub(crate) name: String,
+    pub(crate) length: usize,
+    pub(crate) storage: ArrayStorageAST,
 }
 
 #[derive(Debug)]
@@ -50,6 +288,21 @@ pub struct AssignmentAST {
     pub(crate) value: ExpressionASTNode,
 }
 
+#[derive(Debug)]
+pub struct IndexAssignmentAST {
+    pub(crate) array_name: String,
+    pub(crate) index: ExpressionASTNode,
+    pub(crate) value: ExpressionASTNode,
+}
+
+#[derive(Debug)]
+pub struct CompoundAssignmentAST {
+    pub(crate) target_var_name: String,
+    /// Name of mlog `op` operation
+    pub(crate) operation: String,
+    pub(crate) value: ExpressionASTNode,
+}
+
 #[derive(Debug)]
 pub struct IfAST {
     pub(crate) condition: ExpressionASTNode,
@@ -59,17 +312,105 @@ pub struct IfAST {
 
 #[derive(Debug)]
 pub struct WhileAST {
+    pub(crate) label: Option<String>,
+    pub(crate) condition: ExpressionASTNode,
+    pub(crate) do_block: Vec<StatementASTNode>,
+}
+
+#[derive(Debug)]
+pub struct LoopAST {
+    pub(crate) label: Option<String>,
+    pub(crate) do_block: Vec<StatementASTNode>,
+}
+
+#[derive(Debug)]
+pub struct DoWhileAST {
+    pub(crate) label: Option<String>,
+    pub(crate) do_block: Vec<StatementASTNode>,
     pub(crate) condition: ExpressionASTNode,
+}
+
+#[derive(Debug)]
+pub enum ForIterableAST {
+    /// `a..b step k`
+    Range {
+        start: ExpressionASTNode,
+        end: ExpressionASTNode,
+        step: f64,
+    },
+    /// `units(@type)`, every unit of given type bound with `ubind`
+    Units(ExpressionASTNode),
+    /// `links()`, every building linked to the processor
+    Links,
+}
+
+#[derive(Debug)]
+pub struct ForAST {
+    pub(crate) label: Option<String>,
+    pub(crate) counter_name: String,
+    pub(crate) iterable: ForIterableAST,
     pub(crate) do_block: Vec<StatementASTNode>,
 }
 
+#[derive(Debug)]
+pub struct BreakAST {
+    pub(crate) label: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ContinueAST {
+    pub(crate) label: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum MatchPatternAST {
+    NumberLiteral(f64),
+    /// `Enum.Variant`, replaced with its value once the whole program is parsed
+    EnumVariant(String),
+    Wildcard,
+}
+
+#[derive(Debug)]
+pub struct MatchArmAST {
+    pub(crate) patterns: Vec<MatchPatternAST>,
+    pub(crate) block: Vec<StatementASTNode>,
+}
+
+#[derive(Debug)]
+pub struct MatchAST {
+    pub(crate) value: ExpressionASTNode,
+    pub(crate) arms: Vec<MatchArmAST>,
+}
+
+#[derive(Debug)]
+pub struct ReturnAST {
+    pub(crate) value: Option<ExpressionASTNode>,
+}
+
+/// `@pure_tick { ... }` block, whose `sensor` reads are assumed not to change while it runs
+#[derive(Debug)]
+pub struct PureTickAST {
+    pub(crate) block: Vec<StatementASTNode>,
+}
+
 #[derive(Debug)]
 pub enum StatementASTNode {
     LocalVariableAST(LocalVariableAST),
+    ArrayDeclarationAST(ArrayDeclarationAST),
     AssignmentAST(AssignmentAST),
+    IndexAssignmentAST(IndexAssignmentAST),
+    CompoundAssignmentAST(CompoundAssignmentAST),
     ExpressionAST(ExpressionASTNode),
     IfAST(IfAST),
     WhileAST(WhileAST),
+    LoopAST(LoopAST),
+    DoWhileAST(DoWhileAST),
+    ForAST(ForAST),
+    BreakAST(BreakAST),
+    ContinueAST(ContinueAST),
+    MatchAST(MatchAST),
+    ReturnAST(ReturnAST),
+    PureTickAST(PureTickAST),
 }
 
 #[derive(Clone, Debug)]
@@ -78,9 +419,38 @@ pub struct FunctionCallAST {
     pub(crate) args: Vec<ExpressionASTNode>,
 }
 
+#[derive(Clone, Debug)]
+pub enum LogicalOperator {
+    And,
+    Or,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogicalAST {
+    pub(crate) operator: LogicalOperator,
+    pub(crate) lhs: Box<ExpressionASTNode>,
+    pub(crate) rhs: Box<ExpressionASTNode>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IndexAST {
+    pub(crate) array_name: String,
+    pub(crate) index: Box<ExpressionASTNode>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IfExpressionAST {
+    pub(crate) condition: Box<ExpressionASTNode>,
+    pub(crate) then_value: Box<ExpressionASTNode>,
+    pub(crate) else_value: Box<ExpressionASTNode>,
+}
+
 #[derive(Clone, Debug)]
 pub enum ExpressionASTNode {
     FunctionCallAST(FunctionCallAST),
+    LogicalAST(LogicalAST),
+    IfExpressionAST(IfExpressionAST),
+    IndexAST(IndexAST),
     StringLiteral(String),
     NumberLiteral(f64),
     VariableReference(String),
@@ -92,26 +462,297 @@ pub fn parse_program(tokens: &[Token]) -> Result<ProgramAST, String> {
     let mut program_ast = ProgramAST::new();
 
     while pos < tokens.len() {
-        let parsing_result = parse_global_variable(tokens, &mut pos)
-            .and_then(|x| Ok(ProgramASTNode::GlobalVariableAST(x)))
-            .or_else(|_| {
-                parse_function(tokens, &mut pos).and_then(|x| Ok(ProgramASTNode::FunctionAST(x)))
-            });
+        //Declaration is recognized by its first token, so that errors of its parser aren't lost
+        let parsing_result = match &tokens[pos] {
+            Token::Keyword(Keyword::Let) if is_array_declaration(tokens, pos) => {
+                parse_array_declaration(tokens, &mut pos).map(ProgramASTNode::ArrayDeclarationAST)
+            }
+            Token::Keyword(Keyword::Let) => {
+                parse_global_variable(tokens, &mut pos).map(ProgramASTNode::GlobalVariableAST)
+            }
+            Token::Keyword(Keyword::Struct) => {
+                parse_struct(tokens, &mut pos).map(ProgramASTNode::StructAST)
+            }
+            Token::Keyword(Keyword::Enum) => {
+                parse_enum(tokens, &mut pos).map(ProgramASTNode::EnumAST)
+            }
+            Token::Keyword(Keyword::Link) => {
+                parse_link(tokens, &mut pos).map(ProgramASTNode::LinkAST)
+            }
+            Token::Keyword(Keyword::Hash | Keyword::Inline | Keyword::Fn) => {
+                parse_function(tokens, &mut pos).map(ProgramASTNode::FunctionAST)
+            }
+            other => Err(format!(
+                "Expected function, global variable, array, struct, enum or link declaration, but got \"{:?}\"",
+                other
+            )),
+        };
         match parsing_result {
             Ok(ProgramASTNode::GlobalVariableAST(global_var)) => {
                 program_ast
                     .variables
                     .insert(global_var.name.clone(), global_var);
             }
+            Ok(ProgramASTNode::ArrayDeclarationAST(array)) => {
+                if program_ast.arrays.contains_key(&array.name) {
+                    return Err(format!("Array {} is declared twice", array.name));
+                }
+                program_ast.arrays.insert(array.name.clone(), array);
+            }
+            Ok(ProgramASTNode::StructAST(struct_ast)) => {
+                if program_ast.structs.contains_key(&struct_ast.name) {
+                    return Err(format!("Struct {} is defined twice", struct_ast.name));
+                }
+                program_ast
+                    .structs
+                    .insert(struct_ast.name.clone(), struct_ast);
+            }
+            Ok(ProgramASTNode::EnumAST(enum_ast)) => {
+                if program_ast.enums.contains_key(&enum_ast.name) {
+                    return Err(format!("Enum {} is defined twice", enum_ast.name));
+                }
+                program_ast.enums.insert(enum_ast.name.clone(), enum_ast);
+            }
+            Ok(ProgramASTNode::LinkAST(link)) => {
+                if program_ast.links.contains_key(link.name()) {
+                    return Err(format!("Link {} is declared twice", link.name()));
+                }
+                program_ast.links.insert(link.name().into(), link);
+            }
             Ok(ProgramASTNode::FunctionAST(func)) => {
                 program_ast.functions.insert(func.name.clone(), func);
             }
             Err(err) => return Err(err),
         }
     }
+
+    for link_name in program_ast.links.keys() {
+        if program_ast.variables.contains_key(link_name) {
+            return Err(format!(
+                "{} is declared both as a link and as a global variable",
+                link_name
+            ));
+        }
+    }
+    for array_name in program_ast.arrays.keys() {
+        if program_ast.variables.contains_key(array_name) {
+            return Err(format!(
+                "{} is declared both as an array and as a global variable",
+                array_name
+            ));
+        }
+        if program_ast.links.contains_key(array_name) {
+            return Err(format!(
+                "{} is declared both as an array and as a link",
+                array_name
+            ));
+        }
+    }
+    for enum_name in program_ast.enums.keys() {
+        if program_ast.structs.contains_key(enum_name) {
+            return Err(format!(
+                "{} is defined both as an enum and as a struct",
+                enum_name
+            ));
+        }
+        if program_ast.variables.contains_key(enum_name) {
+            return Err(format!(
+                "{} is declared both as an enum and as a global variable",
+                enum_name
+            ));
+        }
+    }
+
+    program_ast.resolve_enum_variants()?;
+
+    for struct_name in program_ast.structs.keys() {
+        program_ast.check_struct_recursion(struct_name, &mut Vec::new())?;
+    }
+
+    //Members of global structs are globals too
+    let mut struct_member_globals = Vec::new();
+    for global_var in program_ast.variables.values() {
+        if let Some(members) = global_var
+            .type_name
+            .as_ref()
+            .and_then(|type_name| program_ast.struct_members(type_name))
+        {
+            for (member_name, member_type) in members {
+                struct_member_globals.push(GlobalVariableAST {
+                    name: format!("{}.{}", global_var.name, member_name),
+                    type_name: member_type,
+                });
+            }
+        }
+    }
+    for global_var in struct_member_globals {
+        program_ast
+            .variables
+            .insert(global_var.name.clone(), global_var);
+    }
+
     Ok(program_ast)
 }
 
+/// Parses optional `: Type` annotation
+fn parse_type_annotation(tokens: &[Token], pos: &mut usize) -> Option<String> {
+    match tokens.get(*pos..*pos + 2) {
+        Some([Token::Keyword(Keyword::Colon), Token::Identifier(type_name)]) => {
+            *pos += 2;
+            Some(type_name.clone())
+        }
+        _ => None,
+    }
+}
+
+/// Parses `struct Name { field: type, ... }`
+fn parse_struct(tokens: &[Token], pos: &mut usize) -> Result<StructAST, String> {
+    let pos_orig = *pos;
+    let name = match tokens.get(*pos..*pos + 3) {
+        Some(
+            [Token::Keyword(Keyword::Struct), Token::Identifier(name), Token::Keyword(Keyword::LeftCurly)],
+        ) => name.clone(),
+        _ => return Err(String::from("Invalid struct definition")),
+    };
+    *pos += 3;
+
+    let mut fields = Vec::<StructFieldAST>::new();
+    loop {
+        match tokens.get(*pos) {
+            Some(Token::Keyword(Keyword::RightCurly)) => {
+                *pos += 1;
+                break;
+            }
+            Some(Token::Identifier(field_name)) => {
+                *pos += 1;
+                let type_name = parse_type_annotation(tokens, pos);
+                if fields.iter().any(|field| &field.name == field_name) {
+                    *pos = pos_orig;
+                    return Err(format!(
+                        "Field {} of struct {} is defined twice",
+                        field_name, name
+                    ));
+                }
+                fields.push(StructFieldAST {
+                    name: field_name.clone(),
+                    type_name,
+                });
+                match tokens.get(*pos) {
+                    Some(Token::Keyword(Keyword::Comma)) => *pos += 1,
+                    Some(Token::Keyword(Keyword::RightCurly)) => {}
+                    other => {
+                        *pos = pos_orig;
+                        return Err(format!(
+                            "Expected either \"}}\" or \",\" in struct {}, but got \"{:?}\"",
+                            name, other
+                        ));
+                    }
+                }
+            }
+            other => {
+                *pos = pos_orig;
+                return Err(format!(
+                    "Expected field name in struct {}, but got \"{:?}\"",
+                    name, other
+                ));
+            }
+        }
+    }
+
+    Ok(StructAST { name, fields })
+}
+
+/// Parses `enum Name { Variant, ... }`
+fn parse_enum(tokens: &[Token], pos: &mut usize) -> Result<EnumAST, String> {
+    let pos_orig = *pos;
+    let name = match tokens.get(*pos..*pos + 3) {
+        Some(
+            [Token::Keyword(Keyword::Enum), Token::Identifier(name), Token::Keyword(Keyword::LeftCurly)],
+        ) => name.clone(),
+        _ => return Err(String::from("Invalid enum definition")),
+    };
+    *pos += 3;
+
+    let mut variants = Vec::<String>::new();
+    loop {
+        match tokens.get(*pos) {
+            Some(Token::Keyword(Keyword::RightCurly)) => {
+                *pos += 1;
+                break;
+            }
+            Some(Token::Identifier(variant_name)) if !variant_name.contains('.') => {
+                *pos += 1;
+                if variants.contains(variant_name) {
+                    *pos = pos_orig;
+                    return Err(format!(
+                        "Variant {} of enum {} is defined twice",
+                        variant_name, name
+                    ));
+                }
+                variants.push(variant_name.clone());
+                match tokens.get(*pos) {
+                    Some(Token::Keyword(Keyword::Comma)) => *pos += 1,
+                    Some(Token::Keyword(Keyword::RightCurly)) => {}
+                    other => {
+                        *pos = pos_orig;
+                        return Err(format!(
+                            "Expected either \"}}\" or \",\" in enum {}, but got \"{:?}\"",
+                            name, other
+                        ));
+                    }
+                }
+            }
+            other => {
+                *pos = pos_orig;
+                return Err(format!(
+                    "Expected variant name in enum {}, but got \"{:?}\"",
+                    name, other
+                ));
+            }
+        }
+    }
+
+    Ok(EnumAST { name, variants })
+}
+
+/// Parses `link name`, optionally followed by `as alias` and `: Type`
+fn parse_link(tokens: &[Token], pos: &mut usize) -> Result<LinkAST, String> {
+    let link_name = match tokens.get(*pos..*pos + 2) {
+        Some([Token::Keyword(Keyword::Link), Token::Identifier(link_name)]) => link_name.clone(),
+        _ => return Err(String::from("Invalid link declaration")),
+    };
+    if !is_link_name(&link_name) {
+        return Err(format!(
+            "{} is not a link name, which is block name followed by a number, like switch1",
+            link_name
+        ));
+    }
+    *pos += 2;
+    let alias = match (tokens.get(*pos), tokens.get(*pos + 1)) {
+        (Some(Token::Keyword(Keyword::As)), Some(Token::Identifier(alias)))
+            if !alias.contains('.') =>
+        {
+            *pos += 2;
+            Some(alias.clone())
+        }
+        (Some(Token::Keyword(Keyword::As)), other) => {
+            *pos -= 2;
+            return Err(format!(
+                "Expected alias of link {} after \"as\", but got \"{:?}\"",
+                link_name, other
+            ));
+        }
+        _ => None,
+    };
+    let type_name = parse_type_annotation(tokens, pos);
+
+    Ok(LinkAST {
+        link_name,
+        alias,
+        type_name,
+    })
+}
+
 fn parse_global_variable(tokens: &[Token], pos: &mut usize) -> Result<GlobalVariableAST, String> {
     if tokens.len() > *pos + 1 {
         match (&tokens[*pos], &tokens[*pos + 1]) {
@@ -119,6 +760,7 @@ fn parse_global_variable(tokens: &[Token], pos: &mut usize) -> Result<GlobalVari
                 *pos += 2;
                 Ok(GlobalVariableAST {
                     name: var_name.clone(),
+                    type_name: parse_type_annotation(tokens, pos),
                 })
             }
             _ => Err(String::from("Invalid global variable definition")),
@@ -128,34 +770,142 @@ fn parse_global_variable(tokens: &[Token], pos: &mut usize) -> Result<GlobalVari
     }
 }
 
+/// Whether the tokens at the position start with `let name: [`, which can only be an array
+fn is_array_declaration(tokens: &[Token], pos: usize) -> bool {
+    matches!(
+        tokens.get(pos..pos + 4),
+        Some([
+            Token::Keyword(Keyword::Let),
+            Token::Identifier(_),
+            Token::Keyword(Keyword::Colon),
+            Token::Keyword(Keyword::LeftBracket)
+        ])
+    )
+}
+
+/// Parses `let name: [num; length]`, optionally followed by `@ cell[offset]`
+fn parse_array_declaration(
+    tokens: &[Token],
+    pos: &mut usize,
+) -> Result<ArrayDeclarationAST, String> {
+    let pos_orig = *pos;
+    let (name, length) = match tokens.get(*pos..*pos + 8) {
+        Some(
+            [Token::Keyword(Keyword::Let), Token::Identifier(name), Token::Keyword(Keyword::Colon), Token::Keyword(Keyword::LeftBracket), Token::Identifier(element_type), Token::Keyword(Keyword::Semicolon), Token::Number(length), Token::Keyword(Keyword::RightBracket)],
+        ) => {
+            if element_type != "num" {
+                return Err(format!(
+                    "Unsupported array element type \"{}\"",
+                    element_type
+                ));
+            }
+            if length.fract() != 0.0 || *length < 1.0 {
+                return Err(format!("Invalid array length {}", length));
+            }
+            (name.clone(), *length as usize)
+        }
+        _ => return Err(String::from("Invalid array declaration")),
+    };
+    *pos += 8;
+
+    //Storage
+    let storage = match tokens.get(*pos..*pos + 2) {
+        Some([Token::Keyword(Keyword::At), Token::Identifier(cell)]) => {
+            *pos += 2;
+            let mut offset = 0;
+            if let Some(
+                [Token::Keyword(Keyword::LeftBracket), Token::Number(offset_num), Token::Keyword(Keyword::RightBracket)],
+            ) = tokens.get(*pos..*pos + 3)
+            {
+                if offset_num.fract() != 0.0 || *offset_num < 0.0 {
+                    *pos = pos_orig;
+                    return Err(format!("Invalid array offset {}", offset_num));
+                }
+                offset = *offset_num as usize;
+                *pos += 3;
+            }
+            ArrayStorageAST::Memory {
+                cell: cell.clone(),
+                offset,
+            }
+        }
+        _ => ArrayStorageAST::Registers,
+    };
+
+    Ok(ArrayDeclarationAST {
+        name,
+        length,
+        storage,
+    })
+}
+
+/// Name of attribute like `#[noinline]`, if there is one at the position
+fn parse_attribute<'a>(tokens: &'a [Token], pos: &mut usize) -> Option<&'a str> {
+    match (
+        tokens.get(*pos),
+        tokens.get(*pos + 1),
+        tokens.get(*pos + 2),
+        tokens.get(*pos + 3),
+    ) {
+        (
+            Some(Token::Keyword(Keyword::Hash)),
+            Some(Token::Keyword(Keyword::LeftBracket)),
+            Some(Token::Identifier(attribute)),
+            Some(Token::Keyword(Keyword::RightBracket)),
+        ) => {
+            *pos += 4;
+            Some(attribute)
+        }
+        _ => None,
+    }
+}
+
 fn parse_function(tokens: &[Token], pos: &mut usize) -> Result<FunctionAST, String> {
     let pos_orig = *pos;
+    let mut noinline = false;
+    while let Some(attribute) = parse_attribute(tokens, pos) {
+        match attribute {
+            "noinline" => noinline = true,
+            other => {
+                *pos = pos_orig;
+                return Err(format!("Unknown function attribute \"{}\"", other));
+            }
+        }
+    }
     let mut style = FunctionStyle::Normal;
-    if matches!(&tokens[*pos], Token::Keyword(Keyword::Inline)) {
+    if matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::Inline))) {
         *pos += 1;
         style = FunctionStyle::Inline;
+        if noinline {
+            *pos = pos_orig;
+            return Err(String::from(
+                "Inline function can't have the #[noinline] attribute",
+            ));
+        }
     }
-    match (&tokens[*pos], &tokens[*pos + 1], &tokens[*pos + 2]) {
-        (
-            Token::Keyword(Keyword::Fn),
-            Token::Identifier(fn_name),
-            Token::Keyword(Keyword::LeftParenthese),
+    match tokens.get(*pos..*pos + 3) {
+        Some(
+            [Token::Keyword(Keyword::Fn), Token::Identifier(fn_name), Token::Keyword(Keyword::LeftParenthese)],
         ) => {
             *pos += 3;
-            let mut params = Vec::<String>::new();
+            let mut params = Vec::<LocalVariableAST>::new();
 
             //Parameters
             loop {
-                match &tokens[*pos] {
-                    Token::Identifier(param_name) => {
-                        params.push(param_name.clone());
-                        match &tokens[*pos + 1] {
-                            Token::Keyword(Keyword::RightParenthese) => {
-                                *pos += 2;
+                match tokens.get(*pos) {
+                    Some(Token::Identifier(param_name)) => {
+                        *pos += 1;
+                        params.push(LocalVariableAST {
+                            name: param_name.clone(),
+                            type_name: parse_type_annotation(tokens, pos),
+                        });
+                        match tokens.get(*pos) {
+                            Some(Token::Keyword(Keyword::RightParenthese)) => {
+                                *pos += 1;
                                 break;
                             }
-                            Token::Keyword(Keyword::Comma) => {
-                                *pos += 2;
+                            Some(Token::Keyword(Keyword::Comma)) => {
+                                *pos += 1;
                             }
                             other => {
                                 *pos = pos_orig;
@@ -166,7 +916,7 @@ fn parse_function(tokens: &[Token], pos: &mut usize) -> Result<FunctionAST, Stri
                             }
                         }
                     }
-                    Token::Keyword(Keyword::RightParenthese) => {
+                    Some(Token::Keyword(Keyword::RightParenthese)) => {
                         *pos += 1;
                         break;
                     }
@@ -184,12 +934,14 @@ fn parse_function(tokens: &[Token], pos: &mut usize) -> Result<FunctionAST, Stri
             match parse_statement_block(tokens, pos) {
                 Ok(statements) => Ok(FunctionAST {
                     name: fn_name.clone(),
+                    params,
                     statements,
                     style,
+                    noinline,
                 }),
                 Err(err) => {
                     *pos = pos_orig;
-                    return Err(err);
+                    Err(err)
                 }
             }
         }
@@ -205,14 +957,21 @@ fn parse_statement_block(
     pos: &mut usize,
 ) -> Result<Vec<StatementASTNode>, String> {
     let pos_orig = *pos;
-    match tokens[*pos] {
-        Token::Keyword(Keyword::LeftCurly) => {
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::LeftCurly)) => {
             *pos += 1;
             let mut statements = Vec::<StatementASTNode>::new();
             loop {
-                if matches!(tokens[*pos], Token::Keyword(Keyword::RightCurly)) {
-                    *pos += 1;
-                    break;
+                match tokens.get(*pos) {
+                    Some(Token::Keyword(Keyword::RightCurly)) => {
+                        *pos += 1;
+                        break;
+                    }
+                    None => {
+                        *pos = pos_orig;
+                        return Err(String::from("Expected \"}\", but got end of input"));
+                    }
+                    _ => {}
                 }
                 let statement_parse_result = parse_statement(tokens, pos);
                 match statement_parse_result {
@@ -231,24 +990,85 @@ fn parse_statement_block(
     }
 }
 
+/// Parses statement recognized by its first tokens, so that errors of its parser aren't lost
 fn parse_statement(tokens: &[Token], pos: &mut usize) -> Result<StatementASTNode, String> {
-    let pos_orig = *pos;
-    let parsing_result = parse_local_variable(tokens, pos)
-        .and_then(|x| Ok(StatementASTNode::LocalVariableAST(x)))
-        .or_else(|_| {
-            parse_assignment(tokens, pos).and_then(|x| Ok(StatementASTNode::AssignmentAST(x)))
-        })
-        .or_else(|_| {
-            parse_expression(tokens, pos).and_then(|x| Ok(StatementASTNode::ExpressionAST(x)))
-        })
-        .or_else(|_| parse_if(tokens, pos).and_then(|x| Ok(StatementASTNode::IfAST(x))))
-        .or_else(|_| parse_while(tokens, pos).and_then(|x| Ok(StatementASTNode::WhileAST(x))));
-    match parsing_result {
-        Ok(statement) => {
-            println!("parse_statement ok @ token {}", pos_orig);
-            Ok(statement)
+    //Loop is preceded by its label
+    if let Some([Token::LoopLabel(label), Token::Keyword(Keyword::Colon)]) =
+        tokens.get(*pos..*pos + 2)
+    {
+        return match tokens.get(*pos + 2) {
+            Some(Token::Keyword(Keyword::While)) => {
+                parse_while(tokens, pos).map(StatementASTNode::WhileAST)
+            }
+            Some(Token::Keyword(Keyword::Loop)) => {
+                parse_loop(tokens, pos).map(StatementASTNode::LoopAST)
+            }
+            Some(Token::Keyword(Keyword::Do)) => {
+                parse_do_while(tokens, pos).map(StatementASTNode::DoWhileAST)
+            }
+            Some(Token::Keyword(Keyword::For)) => {
+                parse_for(tokens, pos).map(StatementASTNode::ForAST)
+            }
+            other => Err(format!(
+                "Expected loop after label '{}, but got \"{:?}\"",
+                label, other
+            )),
+        };
+    }
+    match (tokens.get(*pos), tokens.get(*pos + 1)) {
+        (Some(Token::Keyword(Keyword::Let)), _) if is_array_declaration(tokens, *pos) => {
+            parse_array_declaration(tokens, pos).map(StatementASTNode::ArrayDeclarationAST)
+        }
+        (Some(Token::Keyword(Keyword::Let)), _) => {
+            parse_local_variable(tokens, pos).map(StatementASTNode::LocalVariableAST)
+        }
+        (Some(Token::Keyword(Keyword::If)), _) => {
+            parse_if(tokens, pos).map(StatementASTNode::IfAST)
+        }
+        (Some(Token::Keyword(Keyword::While)), _) => {
+            parse_while(tokens, pos).map(StatementASTNode::WhileAST)
+        }
+        (Some(Token::Keyword(Keyword::Loop)), _) => {
+            parse_loop(tokens, pos).map(StatementASTNode::LoopAST)
+        }
+        (Some(Token::Keyword(Keyword::Do)), _) => {
+            parse_do_while(tokens, pos).map(StatementASTNode::DoWhileAST)
+        }
+        (Some(Token::Keyword(Keyword::For)), _) => {
+            parse_for(tokens, pos).map(StatementASTNode::ForAST)
         }
-        Err(_) => Err(String::from("Invalid statement")),
+        (Some(Token::Keyword(Keyword::Match)), _) => {
+            parse_match(tokens, pos).map(StatementASTNode::MatchAST)
+        }
+        (Some(Token::Keyword(Keyword::Break)), _) => {
+            parse_break(tokens, pos).map(StatementASTNode::BreakAST)
+        }
+        (Some(Token::Keyword(Keyword::Continue)), _) => {
+            parse_continue(tokens, pos).map(StatementASTNode::ContinueAST)
+        }
+        (Some(Token::Keyword(Keyword::Return)), _) => {
+            parse_return(tokens, pos).map(StatementASTNode::ReturnAST)
+        }
+        (Some(Token::Identifier(name)), Some(Token::Keyword(Keyword::LeftCurly)))
+            if name == "@pure_tick" =>
+        {
+            parse_pure_tick(tokens, pos).map(StatementASTNode::PureTickAST)
+        }
+        (Some(Token::Identifier(_)), Some(Token::Keyword(Keyword::Assign))) => {
+            parse_assignment(tokens, pos).map(StatementASTNode::AssignmentAST)
+        }
+        (Some(Token::Identifier(_)), Some(Token::Keyword(keyword)))
+            if compound_operation(keyword).is_some() =>
+        {
+            parse_compound_assignment(tokens, pos).map(StatementASTNode::CompoundAssignmentAST)
+        }
+        (Some(Token::Identifier(_)), Some(Token::Keyword(Keyword::LeftBracket)))
+            if is_index_assignment(tokens, *pos) =>
+        {
+            parse_index_assignment(tokens, pos).map(StatementASTNode::IndexAssignmentAST)
+        }
+        (Some(_), _) => parse_expression(tokens, pos).map(StatementASTNode::ExpressionAST),
+        (None, _) => Err(String::from("Expected statement, but got end of input")),
     }
 }
 
@@ -259,6 +1079,7 @@ fn parse_local_variable(tokens: &[Token], pos: &mut usize) -> Result<LocalVariab
                 *pos += 2;
                 Ok(LocalVariableAST {
                     name: var_name.clone(),
+                    type_name: parse_type_annotation(tokens, pos),
                 })
             }
             _ => Err(String::from("Invalid local variable definition")),
@@ -270,17 +1091,14 @@ fn parse_local_variable(tokens: &[Token], pos: &mut usize) -> Result<LocalVariab
 
 fn parse_assignment(tokens: &[Token], pos: &mut usize) -> Result<AssignmentAST, String> {
     let pos_orig = *pos;
-    match (&tokens[*pos], &tokens[*pos + 1]) {
-        (Token::Identifier(target_var_name), Token::Keyword(Keyword::Assign)) => {
+    match (tokens.get(*pos), tokens.get(*pos + 1)) {
+        (Some(Token::Identifier(target_var_name)), Some(Token::Keyword(Keyword::Assign))) => {
             *pos += 2;
             match parse_expression(tokens, pos) {
-                Ok(expression) => {
-                    println!("parse_assignment ok @ token {}", pos_orig);
-                    Ok(AssignmentAST {
-                        target_var_name: target_var_name.clone(),
-                        value: expression,
-                    })
-                }
+                Ok(expression) => Ok(AssignmentAST {
+                    target_var_name: target_var_name.clone(),
+                    value: expression,
+                }),
                 Err(err) => {
                     *pos = pos_orig;
                     Err(err)
@@ -291,21 +1109,148 @@ fn parse_assignment(tokens: &[Token], pos: &mut usize) -> Result<AssignmentAST,
     }
 }
 
+/// Whether the tokens at the position start with `array[index] =`
+fn is_index_assignment(tokens: &[Token], mut pos: usize) -> bool {
+    parse_index(tokens, &mut pos).is_ok()
+        && matches!(tokens.get(pos), Some(Token::Keyword(Keyword::Assign)))
+}
+
+/// Parses `array[index] = value`
+fn parse_index_assignment(tokens: &[Token], pos: &mut usize) -> Result<IndexAssignmentAST, String> {
+    let pos_orig = *pos;
+    let IndexAST { array_name, index } = parse_index(tokens, pos)?;
+    if !matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::Assign))) {
+        *pos = pos_orig;
+        return Err(String::from("Invalid index assignment"));
+    }
+    *pos += 1;
+    match parse_expression(tokens, pos) {
+        Ok(value) => Ok(IndexAssignmentAST {
+            array_name,
+            index: *index,
+            value,
+        }),
+        Err(err) => {
+            *pos = pos_orig;
+            Err(err)
+        }
+    }
+}
+
+/// Operation of compound assignment or increment operator
+fn compound_operation(keyword: &Keyword) -> Option<&'static str> {
+    match keyword {
+        Keyword::AddAssign | Keyword::Increment => Some("add"),
+        Keyword::SubAssign | Keyword::Decrement => Some("sub"),
+        Keyword::MulAssign => Some("mul"),
+        Keyword::DivAssign => Some("div"),
+        Keyword::ModAssign => Some("mod"),
+        Keyword::AndAssign => Some("and"),
+        Keyword::OrAssign => Some("or"),
+        Keyword::XorAssign => Some("xor"),
+        Keyword::ShlAssign => Some("shl"),
+        Keyword::ShrAssign => Some("shr"),
+        _ => None,
+    }
+}
+
+fn parse_compound_assignment(
+    tokens: &[Token],
+    pos: &mut usize,
+) -> Result<CompoundAssignmentAST, String> {
+    let pos_orig = *pos;
+    match tokens.get(*pos..*pos + 2) {
+        Some([Token::Identifier(target_var_name), Token::Keyword(keyword)]) => {
+            let Some(operation) = compound_operation(keyword) else {
+                return Err(String::from("Invalid compound assignment"));
+            };
+            *pos += 2;
+
+            //Increment and decrement have implicit value
+            if matches!(keyword, Keyword::Increment | Keyword::Decrement) {
+                return Ok(CompoundAssignmentAST {
+                    target_var_name: target_var_name.clone(),
+                    operation: operation.into(),
+                    value: ExpressionASTNode::NumberLiteral(1.0),
+                });
+            }
+
+            match parse_expression(tokens, pos) {
+                Ok(expression) => Ok(CompoundAssignmentAST {
+                    target_var_name: target_var_name.clone(),
+                    operation: operation.into(),
+                    value: expression,
+                }),
+                Err(err) => {
+                    *pos = pos_orig;
+                    Err(err)
+                }
+            }
+        }
+        _ => Err(String::from("Invalid compound assignment")),
+    }
+}
+
 fn parse_expression(tokens: &[Token], pos: &mut usize) -> Result<ExpressionASTNode, String> {
-    let parsing_result = parse_function_call(tokens, pos)
-        .and_then(|x| {
-            println!("parse_expression -> parse_function_call ok");
-            Ok(ExpressionASTNode::FunctionCallAST(x))
-        })
-        .or_else(|_| {
-            parse_string_literal(tokens, pos).and_then(|x| Ok(ExpressionASTNode::StringLiteral(x)))
-        })
-        .or_else(|_| {
-            parse_number_literal(tokens, pos).and_then(|x| Ok(ExpressionASTNode::NumberLiteral(x)))
-        })
+    parse_logical_or(tokens, pos)
+}
+
+fn parse_logical_or(tokens: &[Token], pos: &mut usize) -> Result<ExpressionASTNode, String> {
+    let mut expr = parse_logical_and(tokens, pos)?;
+    while matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::OrOr))) {
+        let pos_orig = *pos;
+        *pos += 1;
+        match parse_logical_and(tokens, pos) {
+            Ok(rhs) => {
+                expr = ExpressionASTNode::LogicalAST(LogicalAST {
+                    operator: LogicalOperator::Or,
+                    lhs: Box::new(expr),
+                    rhs: Box::new(rhs),
+                });
+            }
+            Err(err) => {
+                *pos = pos_orig;
+                return Err(err);
+            }
+        }
+    }
+    Ok(expr)
+}
+
+fn parse_logical_and(tokens: &[Token], pos: &mut usize) -> Result<ExpressionASTNode, String> {
+    let mut expr = parse_primary_expression(tokens, pos)?;
+    while matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::AndAnd))) {
+        let pos_orig = *pos;
+        *pos += 1;
+        match parse_primary_expression(tokens, pos) {
+            Ok(rhs) => {
+                expr = ExpressionASTNode::LogicalAST(LogicalAST {
+                    operator: LogicalOperator::And,
+                    lhs: Box::new(expr),
+                    rhs: Box::new(rhs),
+                });
+            }
+            Err(err) => {
+                *pos = pos_orig;
+                return Err(err);
+            }
+        }
+    }
+    Ok(expr)
+}
+
+fn parse_primary_expression(
+    tokens: &[Token],
+    pos: &mut usize,
+) -> Result<ExpressionASTNode, String> {
+    let parsing_result = parse_parenthesized_expression(tokens, pos)
+        .or_else(|_| parse_if_expression(tokens, pos).map(ExpressionASTNode::IfExpressionAST))
+        .or_else(|_| parse_function_call(tokens, pos).map(ExpressionASTNode::FunctionCallAST))
+        .or_else(|_| parse_index(tokens, pos).map(ExpressionASTNode::IndexAST))
+        .or_else(|_| parse_string_literal(tokens, pos).map(ExpressionASTNode::StringLiteral))
+        .or_else(|_| parse_number_literal(tokens, pos).map(ExpressionASTNode::NumberLiteral))
         .or_else(|_| {
-            parse_variable_reference(tokens, pos)
-                .and_then(|x| Ok(ExpressionASTNode::VariableReference(x)))
+            parse_variable_reference(tokens, pos).map(ExpressionASTNode::VariableReference)
         });
 
     match parsing_result {
@@ -314,17 +1259,130 @@ fn parse_expression(tokens: &[Token], pos: &mut usize) -> Result<ExpressionASTNo
     }
 }
 
+fn parse_parenthesized_expression(
+    tokens: &[Token],
+    pos: &mut usize,
+) -> Result<ExpressionASTNode, String> {
+    let pos_orig = *pos;
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::LeftParenthese)) => {
+            *pos += 1;
+            let expr = match parse_expression(tokens, pos) {
+                Ok(expr) => expr,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+            match tokens.get(*pos) {
+                Some(Token::Keyword(Keyword::RightParenthese)) => {
+                    *pos += 1;
+                    Ok(expr)
+                }
+                other => {
+                    *pos = pos_orig;
+                    Err(format!("Expected \")\", but got \"{:?}\"", other))
+                }
+            }
+        }
+        _ => Err(String::from("Invalid parenthesized expression")),
+    }
+}
+
+fn parse_if_expression(tokens: &[Token], pos: &mut usize) -> Result<IfExpressionAST, String> {
+    let pos_orig = *pos;
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::If)) => {
+            *pos += 1;
+
+            //Condition
+            let condition = match parse_expression(tokens, pos) {
+                Ok(condition_expr) => condition_expr,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+
+            //Then value
+            let then_value = match parse_value_block(tokens, pos) {
+                Ok(then_expr) => then_expr,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+
+            //Else value, which is required
+            if !matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::Else))) {
+                *pos = pos_orig;
+                return Err(String::from("If expression requires else branch"));
+            }
+            *pos += 1;
+            let else_value_result = if matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::If)))
+            {
+                parse_if_expression(tokens, pos).map(ExpressionASTNode::IfExpressionAST)
+            } else {
+                parse_value_block(tokens, pos)
+            };
+            match else_value_result {
+                Ok(else_value) => Ok(IfExpressionAST {
+                    condition: Box::new(condition),
+                    then_value: Box::new(then_value),
+                    else_value: Box::new(else_value),
+                }),
+                Err(err) => {
+                    *pos = pos_orig;
+                    Err(err)
+                }
+            }
+        }
+        _ => Err(String::from("Invalid if expression")),
+    }
+}
+
+/// Parses `{ expression }` used as branch of if expression
+fn parse_value_block(tokens: &[Token], pos: &mut usize) -> Result<ExpressionASTNode, String> {
+    let pos_orig = *pos;
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::LeftCurly)) => {
+            *pos += 1;
+            let expr = match parse_expression(tokens, pos) {
+                Ok(expr) => expr,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+            match tokens.get(*pos) {
+                Some(Token::Keyword(Keyword::RightCurly)) => {
+                    *pos += 1;
+                    Ok(expr)
+                }
+                other => {
+                    *pos = pos_orig;
+                    Err(format!("Expected \"}}\", but got \"{:?}\"", other))
+                }
+            }
+        }
+        _ => Err(String::from("Invalid value block")),
+    }
+}
+
 fn parse_function_call(tokens: &[Token], pos: &mut usize) -> Result<FunctionCallAST, String> {
     let pos_orig = *pos;
 
-    match (&tokens[*pos], &tokens[*pos + 1]) {
-        (Token::Identifier(fn_name), Token::Keyword(Keyword::LeftParenthese)) => {
+    match (tokens.get(*pos), tokens.get(*pos + 1)) {
+        (Some(Token::Identifier(fn_name)), Some(Token::Keyword(Keyword::LeftParenthese))) => {
             *pos += 2;
 
             //Arguments
             let mut args = Vec::<ExpressionASTNode>::new();
             loop {
-                if matches!(&tokens[*pos], Token::Keyword(Keyword::RightParenthese)) {
+                if matches!(
+                    tokens.get(*pos),
+                    Some(Token::Keyword(Keyword::RightParenthese))
+                ) {
                     *pos += 1;
                     break;
                 }
@@ -340,16 +1398,18 @@ fn parse_function_call(tokens: &[Token], pos: &mut usize) -> Result<FunctionCall
                     }
                 }
 
-                if matches!(&tokens[*pos], Token::Keyword(Keyword::RightParenthese)) {
+                if matches!(
+                    tokens.get(*pos),
+                    Some(Token::Keyword(Keyword::RightParenthese))
+                ) {
                     *pos += 1;
                     break;
                 }
-                if matches!(&tokens[*pos], Token::Keyword(Keyword::Comma)) {
+                if matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::Comma))) {
                     *pos += 1;
                 }
             }
 
-            println!("parse_function_call ok @ token {}", pos_orig);
             Ok(FunctionCallAST {
                 function_name: fn_name.clone(),
                 args,
@@ -359,9 +1419,39 @@ fn parse_function_call(tokens: &[Token], pos: &mut usize) -> Result<FunctionCall
     }
 }
 
+fn parse_index(tokens: &[Token], pos: &mut usize) -> Result<IndexAST, String> {
+    let pos_orig = *pos;
+    match (tokens.get(*pos), tokens.get(*pos + 1)) {
+        (Some(Token::Identifier(array_name)), Some(Token::Keyword(Keyword::LeftBracket))) => {
+            *pos += 2;
+            let index = match parse_expression(tokens, pos) {
+                Ok(index) => index,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+            match tokens.get(*pos) {
+                Some(Token::Keyword(Keyword::RightBracket)) => {
+                    *pos += 1;
+                    Ok(IndexAST {
+                        array_name: array_name.clone(),
+                        index: Box::new(index),
+                    })
+                }
+                other => {
+                    *pos = pos_orig;
+                    Err(format!("Expected \"]\", but got \"{:?}\"", other))
+                }
+            }
+        }
+        _ => Err(String::from("Invalid index expression")),
+    }
+}
+
 fn parse_string_literal(tokens: &[Token], pos: &mut usize) -> Result<String, String> {
-    match &tokens[*pos] {
-        Token::String(str) => {
+    match tokens.get(*pos) {
+        Some(Token::String(str)) => {
             *pos += 1;
             Ok(str.clone())
         }
@@ -370,8 +1460,8 @@ fn parse_string_literal(tokens: &[Token], pos: &mut usize) -> Result<String, Str
 }
 
 fn parse_number_literal(tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
-    match &tokens[*pos] {
-        Token::Number(num) => {
+    match tokens.get(*pos) {
+        Some(Token::Number(num)) => {
             *pos += 1;
             Ok(*num)
         }
@@ -380,8 +1470,8 @@ fn parse_number_literal(tokens: &[Token], pos: &mut usize) -> Result<f64, String
 }
 
 fn parse_variable_reference(tokens: &[Token], pos: &mut usize) -> Result<String, String> {
-    match &tokens[*pos] {
-        Token::Identifier(ident) => {
+    match tokens.get(*pos) {
+        Some(Token::Identifier(ident)) => {
             *pos += 1;
             Ok(ident.clone())
         }
@@ -391,8 +1481,8 @@ fn parse_variable_reference(tokens: &[Token], pos: &mut usize) -> Result<String,
 
 fn parse_if(tokens: &[Token], pos: &mut usize) -> Result<IfAST, String> {
     let pos_orig = *pos;
-    match &tokens[*pos] {
-        Token::Keyword(Keyword::If) => {
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::If)) => {
             *pos += 1;
 
             //Condition
@@ -400,12 +1490,20 @@ fn parse_if(tokens: &[Token], pos: &mut usize) -> Result<IfAST, String> {
                 Ok(condition_expr) => {
                     //Then block
                     match parse_statement_block(tokens, pos) {
-                        Ok(then_block) => match &tokens[*pos] {
-                            Token::Keyword(Keyword::Else) => {
+                        Ok(then_block) => match tokens.get(*pos) {
+                            Some(Token::Keyword(Keyword::Else)) => {
                                 *pos += 1;
 
-                                //Else block
-                                match parse_statement_block(tokens, pos) {
+                                //Else block, or another if statement for else-if chains
+                                let else_block_result = if matches!(
+                                    tokens.get(*pos),
+                                    Some(Token::Keyword(Keyword::If))
+                                ) {
+                                    parse_if(tokens, pos).map(|x| vec![StatementASTNode::IfAST(x)])
+                                } else {
+                                    parse_statement_block(tokens, pos)
+                                };
+                                match else_block_result {
                                     Ok(else_block) => Ok(IfAST {
                                         condition: condition_expr,
                                         then_block,
@@ -439,10 +1537,22 @@ fn parse_if(tokens: &[Token], pos: &mut usize) -> Result<IfAST, String> {
     }
 }
 
+/// Parses optional `'label:` preceding a loop
+fn parse_loop_label(tokens: &[Token], pos: &mut usize) -> Option<String> {
+    match (tokens.get(*pos), tokens.get(*pos + 1)) {
+        (Some(Token::LoopLabel(label)), Some(Token::Keyword(Keyword::Colon))) => {
+            *pos += 2;
+            Some(label.clone())
+        }
+        _ => None,
+    }
+}
+
 fn parse_while(tokens: &[Token], pos: &mut usize) -> Result<WhileAST, String> {
     let pos_orig = *pos;
-    match &tokens[*pos] {
-        Token::Keyword(Keyword::While) => {
+    let label = parse_loop_label(tokens, pos);
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::While)) => {
             *pos += 1;
 
             //Condition
@@ -451,6 +1561,7 @@ fn parse_while(tokens: &[Token], pos: &mut usize) -> Result<WhileAST, String> {
                     //Do block
                     match parse_statement_block(tokens, pos) {
                         Ok(do_block) => Ok(WhileAST {
+                            label,
                             condition: condition_expr,
                             do_block,
                         }),
@@ -466,6 +1577,567 @@ fn parse_while(tokens: &[Token], pos: &mut usize) -> Result<WhileAST, String> {
                 }
             }
         }
-        _ => Err(String::from("Invalid while statement")),
+        _ => {
+            *pos = pos_orig;
+            Err(String::from("Invalid while statement"))
+        }
+    }
+}
+
+fn parse_loop(tokens: &[Token], pos: &mut usize) -> Result<LoopAST, String> {
+    let pos_orig = *pos;
+    let label = parse_loop_label(tokens, pos);
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::Loop)) => {
+            *pos += 1;
+
+            //Do block
+            match parse_statement_block(tokens, pos) {
+                Ok(do_block) => Ok(LoopAST { label, do_block }),
+                Err(err) => {
+                    *pos = pos_orig;
+                    Err(err)
+                }
+            }
+        }
+        _ => {
+            *pos = pos_orig;
+            Err(String::from("Invalid loop statement"))
+        }
+    }
+}
+
+fn parse_do_while(tokens: &[Token], pos: &mut usize) -> Result<DoWhileAST, String> {
+    let pos_orig = *pos;
+    let label = parse_loop_label(tokens, pos);
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::Do)) => {
+            *pos += 1;
+
+            //Do block
+            let do_block = match parse_statement_block(tokens, pos) {
+                Ok(do_block) => do_block,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+
+            if !matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::While))) {
+                *pos = pos_orig;
+                return Err(String::from("Expected \"while\" after do block"));
+            }
+            *pos += 1;
+
+            //Condition
+            match parse_expression(tokens, pos) {
+                Ok(condition) => Ok(DoWhileAST {
+                    label,
+                    do_block,
+                    condition,
+                }),
+                Err(err) => {
+                    *pos = pos_orig;
+                    Err(err)
+                }
+            }
+        }
+        _ => {
+            *pos = pos_orig;
+            Err(String::from("Invalid do-while statement"))
+        }
+    }
+}
+
+fn parse_for(tokens: &[Token], pos: &mut usize) -> Result<ForAST, String> {
+    let pos_orig = *pos;
+    let label = parse_loop_label(tokens, pos);
+    match tokens.get(*pos..*pos + 3) {
+        Some(
+            [Token::Keyword(Keyword::For), Token::Identifier(counter_name), Token::Keyword(Keyword::In)],
+        ) => {
+            *pos += 3;
+
+            let start = match parse_expression(tokens, pos) {
+                Ok(start_expr) => start_expr,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+
+            //Units or links
+            if !matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::DotDot))) {
+                let iterable = match start {
+                    ExpressionASTNode::FunctionCallAST(FunctionCallAST {
+                        function_name,
+                        mut args,
+                    }) if function_name == "units" && args.len() == 1 => {
+                        ForIterableAST::Units(args.remove(0))
+                    }
+                    ExpressionASTNode::FunctionCallAST(FunctionCallAST {
+                        function_name,
+                        args,
+                    }) if function_name == "links" && args.is_empty() => ForIterableAST::Links,
+                    _ => {
+                        *pos = pos_orig;
+                        return Err(String::from(
+                            "Expected range, \"units(type)\" or \"links()\" in for loop",
+                        ));
+                    }
+                };
+                return match parse_statement_block(tokens, pos) {
+                    Ok(do_block) => Ok(ForAST {
+                        label,
+                        counter_name: counter_name.clone(),
+                        iterable,
+                        do_block,
+                    }),
+                    Err(err) => {
+                        *pos = pos_orig;
+                        Err(err)
+                    }
+                };
+            }
+
+            //Range
+            *pos += 1;
+            let end = match parse_expression(tokens, pos) {
+                Ok(end_expr) => end_expr,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+
+            //Optional step
+            let mut step = 1.0;
+            if let Some(Token::Identifier(ident)) = tokens.get(*pos) {
+                if ident == "step" {
+                    match tokens.get(*pos + 1) {
+                        Some(Token::Number(num)) if *num != 0.0 => {
+                            step = *num;
+                            *pos += 2;
+                        }
+                        other => {
+                            *pos = pos_orig;
+                            return Err(format!(
+                                "Expected non-zero number literal as for loop step, but got \"{:?}\"",
+                                other
+                            ));
+                        }
+                    }
+                }
+            }
+
+            //Do block
+            match parse_statement_block(tokens, pos) {
+                Ok(do_block) => Ok(ForAST {
+                    label,
+                    counter_name: counter_name.clone(),
+                    iterable: ForIterableAST::Range { start, end, step },
+                    do_block,
+                }),
+                Err(err) => {
+                    *pos = pos_orig;
+                    Err(err)
+                }
+            }
+        }
+        _ => {
+            *pos = pos_orig;
+            Err(String::from("Invalid for statement"))
+        }
+    }
+}
+
+fn parse_break(tokens: &[Token], pos: &mut usize) -> Result<BreakAST, String> {
+    match &tokens[*pos] {
+        Token::Keyword(Keyword::Break) => {
+            *pos += 1;
+            Ok(BreakAST {
+                label: parse_jump_label(tokens, pos),
+            })
+        }
+        _ => Err(String::from("Invalid break statement")),
+    }
+}
+
+fn parse_continue(tokens: &[Token], pos: &mut usize) -> Result<ContinueAST, String> {
+    match &tokens[*pos] {
+        Token::Keyword(Keyword::Continue) => {
+            *pos += 1;
+            Ok(ContinueAST {
+                label: parse_jump_label(tokens, pos),
+            })
+        }
+        _ => Err(String::from("Invalid continue statement")),
+    }
+}
+
+/// Parses `return` with optional value, which is omitted when the block ends right after it
+fn parse_return(tokens: &[Token], pos: &mut usize) -> Result<ReturnAST, String> {
+    let pos_orig = *pos;
+    if !matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::Return))) {
+        return Err(String::from("Invalid return"));
+    }
+    *pos += 1;
+    if matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::RightCurly))) {
+        return Ok(ReturnAST { value: None });
+    }
+    match parse_expression(tokens, pos) {
+        Ok(value) => Ok(ReturnAST { value: Some(value) }),
+        Err(err) => {
+            *pos = pos_orig;
+            Err(err)
+        }
+    }
+}
+
+/// Parses `@pure_tick` block. It has to be tried before expressions, which would take
+/// `@pure_tick` for a built-in variable.
+fn parse_pure_tick(tokens: &[Token], pos: &mut usize) -> Result<PureTickAST, String> {
+    let pos_orig = *pos;
+    match &tokens[*pos] {
+        Token::Identifier(name) if name == "@pure_tick" => {
+            *pos += 1;
+            match parse_statement_block(tokens, pos) {
+                Ok(block) => Ok(PureTickAST { block }),
+                Err(err) => {
+                    *pos = pos_orig;
+                    Err(err)
+                }
+            }
+        }
+        _ => Err(String::from("Invalid @pure_tick block")),
+    }
+}
+
+/// Parses optional `'label` following `break` or `continue`
+fn parse_jump_label(tokens: &[Token], pos: &mut usize) -> Option<String> {
+    match tokens.get(*pos) {
+        Some(Token::LoopLabel(label)) => {
+            *pos += 1;
+            Some(label.clone())
+        }
+        _ => None,
+    }
+}
+
+fn parse_match(tokens: &[Token], pos: &mut usize) -> Result<MatchAST, String> {
+    let pos_orig = *pos;
+    match tokens.get(*pos) {
+        Some(Token::Keyword(Keyword::Match)) => {
+            *pos += 1;
+
+            //Matched value
+            let value = match parse_expression(tokens, pos) {
+                Ok(value_expr) => value_expr,
+                Err(err) => {
+                    *pos = pos_orig;
+                    return Err(err);
+                }
+            };
+
+            if !matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::LeftCurly))) {
+                *pos = pos_orig;
+                return Err(String::from("Expected \"{\" after match value"));
+            }
+            *pos += 1;
+
+            //Arms
+            let mut arms = Vec::<MatchArmAST>::new();
+            loop {
+                if matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::RightCurly))) {
+                    *pos += 1;
+                    break;
+                }
+
+                match parse_match_arm(tokens, pos) {
+                    Ok(arm) => {
+                        arms.push(arm);
+                    }
+                    Err(err) => {
+                        *pos = pos_orig;
+                        return Err(err);
+                    }
+                }
+
+                if matches!(tokens.get(*pos), Some(Token::Keyword(Keyword::Comma))) {
+                    *pos += 1;
+                }
+            }
+
+            Ok(MatchAST { value, arms })
+        }
+        _ => Err(String::from("Invalid match statement")),
+    }
+}
+
+fn parse_match_arm(tokens: &[Token], pos: &mut usize) -> Result<MatchArmAST, String> {
+    let pos_orig = *pos;
+
+    //Patterns separated by "|"
+    let mut patterns = Vec::<MatchPatternAST>::new();
+    loop {
+        match parse_match_pattern(tokens, pos) {
+            Ok(pattern) => {
+                patterns.push(pattern);
+            }
+            Err(err) => {
+                *pos = pos_orig;
+                return Err(err);
+            }
+        }
+
+        match tokens.get(*pos) {
+            Some(Token::Keyword(Keyword::Pipe)) => {
+                *pos += 1;
+            }
+            Some(Token::Keyword(Keyword::FatArrow)) => {
+                *pos += 1;
+                break;
+            }
+            other => {
+                *pos = pos_orig;
+                return Err(format!(
+                    "Expected either \"|\" or \"=>\", but got \"{:?}\"",
+                    other
+                ));
+            }
+        }
+    }
+
+    //Arm block
+    match parse_statement_block(tokens, pos) {
+        Ok(block) => Ok(MatchArmAST { patterns, block }),
+        Err(err) => {
+            *pos = pos_orig;
+            Err(err)
+        }
+    }
+}
+
+fn parse_match_pattern(tokens: &[Token], pos: &mut usize) -> Result<MatchPatternAST, String> {
+    match tokens.get(*pos) {
+        Some(Token::Number(num)) => {
+            *pos += 1;
+            Ok(MatchPatternAST::NumberLiteral(*num))
+        }
+        Some(Token::Identifier(ident)) if ident == "_" => {
+            *pos += 1;
+            Ok(MatchPatternAST::Wildcard)
+        }
+        Some(Token::Identifier(path)) if path.contains('.') => {
+            *pos += 1;
+            Ok(MatchPatternAST::EnumVariant(path.clone()))
+        }
+        other => Err(format!("Invalid match pattern \"{:?}\"", other)),
+    }
+}
+
+/// Calls the visitor for every statement in the block, including nested ones
+pub(crate) fn visit_statements(
+    statements: &[StatementASTNode],
+    visitor: &mut impl FnMut(&StatementASTNode),
+) {
+    for statement in statements {
+        visitor(statement);
+        match statement {
+            StatementASTNode::IfAST(IfAST {
+                then_block,
+                else_block,
+                ..
+            }) => {
+                visit_statements(then_block, visitor);
+                visit_statements(else_block, visitor);
+            }
+            StatementASTNode::WhileAST(WhileAST { do_block, .. })
+            | StatementASTNode::LoopAST(LoopAST { do_block, .. })
+            | StatementASTNode::DoWhileAST(DoWhileAST { do_block, .. })
+            | StatementASTNode::ForAST(ForAST { do_block, .. })
+            | StatementASTNode::PureTickAST(PureTickAST { block: do_block }) => {
+                visit_statements(do_block, visitor);
+            }
+            StatementASTNode::MatchAST(MatchAST { arms, .. }) => {
+                for arm in arms {
+                    visit_statements(&arm.block, visitor);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Calls the visitor for every statement in the block, including nested ones, stopping at the
+/// first error
+pub(crate) fn visit_statements_mut(
+    statements: &mut [StatementASTNode],
+    visitor: &mut impl FnMut(&mut StatementASTNode) -> Result<(), String>,
+) -> Result<(), String> {
+    for statement in statements {
+        visitor(statement)?;
+        match statement {
+            StatementASTNode::IfAST(IfAST {
+                then_block,
+                else_block,
+                ..
+            }) => {
+                visit_statements_mut(then_block, visitor)?;
+                visit_statements_mut(else_block, visitor)?;
+            }
+            StatementASTNode::WhileAST(WhileAST { do_block, .. })
+            | StatementASTNode::LoopAST(LoopAST { do_block, .. })
+            | StatementASTNode::DoWhileAST(DoWhileAST { do_block, .. })
+            | StatementASTNode::ForAST(ForAST { do_block, .. })
+            | StatementASTNode::PureTickAST(PureTickAST { block: do_block }) => {
+                visit_statements_mut(do_block, visitor)?;
+            }
+            StatementASTNode::MatchAST(MatchAST { arms, .. }) => {
+                for arm in arms {
+                    visit_statements_mut(&mut arm.block, visitor)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Calls the visitor for the expression and all its subexpressions, subexpressions first
+pub(crate) fn visit_expression(
+    expression: &ExpressionASTNode,
+    visitor: &mut impl FnMut(&ExpressionASTNode),
+) {
+    match expression {
+        ExpressionASTNode::FunctionCallAST(FunctionCallAST { args, .. }) => {
+            for arg in args {
+                visit_expression(arg, visitor);
+            }
+        }
+        ExpressionASTNode::LogicalAST(LogicalAST { lhs, rhs, .. }) => {
+            visit_expression(lhs, visitor);
+            visit_expression(rhs, visitor);
+        }
+        ExpressionASTNode::IfExpressionAST(IfExpressionAST {
+            condition,
+            then_value,
+            else_value,
+        }) => {
+            visit_expression(condition, visitor);
+            visit_expression(then_value, visitor);
+            visit_expression(else_value, visitor);
+        }
+        ExpressionASTNode::IndexAST(IndexAST { index, .. }) => {
+            visit_expression(index, visitor);
+        }
+        ExpressionASTNode::StringLiteral(_)
+        | ExpressionASTNode::NumberLiteral(_)
+        | ExpressionASTNode::VariableReference(_) => {}
+    }
+    visitor(expression)
+}
+
+/// Calls the visitor for the expression and all its subexpressions, subexpressions first
+pub(crate) fn visit_expression_mut(
+    expression: &mut ExpressionASTNode,
+    visitor: &mut impl FnMut(&mut ExpressionASTNode) -> Result<(), String>,
+) -> Result<(), String> {
+    match expression {
+        ExpressionASTNode::FunctionCallAST(FunctionCallAST { args, .. }) => {
+            for arg in args {
+                visit_expression_mut(arg, visitor)?;
+            }
+        }
+        ExpressionASTNode::LogicalAST(LogicalAST { lhs, rhs, .. }) => {
+            visit_expression_mut(lhs, visitor)?;
+            visit_expression_mut(rhs, visitor)?;
+        }
+        ExpressionASTNode::IfExpressionAST(IfExpressionAST {
+            condition,
+            then_value,
+            else_value,
+        }) => {
+            visit_expression_mut(condition, visitor)?;
+            visit_expression_mut(then_value, visitor)?;
+            visit_expression_mut(else_value, visitor)?;
+        }
+        ExpressionASTNode::IndexAST(IndexAST { index, .. }) => {
+            visit_expression_mut(index, visitor)?;
+        }
+        ExpressionASTNode::StringLiteral(_)
+        | ExpressionASTNode::NumberLiteral(_)
+        | ExpressionASTNode::VariableReference(_) => {}
+    }
+    visitor(expression)
+}
+
+impl StatementASTNode {
+    /// Expressions the statement contains directly, without the ones in nested blocks
+    pub(crate) fn expressions(&self) -> Vec<&ExpressionASTNode> {
+        match self {
+            StatementASTNode::AssignmentAST(AssignmentAST { value, .. })
+            | StatementASTNode::CompoundAssignmentAST(CompoundAssignmentAST { value, .. })
+            | StatementASTNode::ExpressionAST(value)
+            | StatementASTNode::IfAST(IfAST {
+                condition: value, ..
+            })
+            | StatementASTNode::WhileAST(WhileAST {
+                condition: value, ..
+            })
+            | StatementASTNode::DoWhileAST(DoWhileAST {
+                condition: value, ..
+            })
+            | StatementASTNode::MatchAST(MatchAST { value, .. }) => vec![value],
+            StatementASTNode::IndexAssignmentAST(IndexAssignmentAST { index, value, .. }) => {
+                vec![index, value]
+            }
+            StatementASTNode::ForAST(ForAST { iterable, .. }) => match iterable {
+                ForIterableAST::Range { start, end, .. } => vec![start, end],
+                ForIterableAST::Units(unit_type) => vec![unit_type],
+                ForIterableAST::Links => Vec::new(),
+            },
+            StatementASTNode::ReturnAST(ReturnAST { value }) => value.iter().collect(),
+            StatementASTNode::LocalVariableAST(_)
+            | StatementASTNode::ArrayDeclarationAST(_)
+            | StatementASTNode::LoopAST(_)
+            | StatementASTNode::PureTickAST(_)
+            | StatementASTNode::BreakAST(_)
+            | StatementASTNode::ContinueAST(_) => Vec::new(),
+        }
+    }
+
+    /// Mutable version of [`Self::expressions`]
+    pub(crate) fn expressions_mut(&mut self) -> Vec<&mut ExpressionASTNode> {
+        match self {
+            StatementASTNode::AssignmentAST(AssignmentAST { value, .. })
+            | StatementASTNode::CompoundAssignmentAST(CompoundAssignmentAST { value, .. })
+            | StatementASTNode::ExpressionAST(value)
+            | StatementASTNode::IfAST(IfAST {
+                condition: value, ..
+            })
+            | StatementASTNode::WhileAST(WhileAST {
+                condition: value, ..
+            })
+            | StatementASTNode::DoWhileAST(DoWhileAST {
+                condition: value, ..
+            })
+            | StatementASTNode::MatchAST(MatchAST { value, .. }) => vec![value],
+            StatementASTNode::IndexAssignmentAST(IndexAssignmentAST { index, value, .. }) => {
+                vec![index, value]
+            }
+            StatementASTNode::ForAST(ForAST { iterable, .. }) => match iterable {
+                ForIterableAST::Range { start, end, .. } => vec![start, end],
+                ForIterableAST::Units(unit_type) => vec![unit_type],
+                ForIterableAST::Links => Vec::new(),
+            },
+            StatementASTNode::ReturnAST(ReturnAST { value }) => value.iter_mut().collect(),
+            StatementASTNode::LocalVariableAST(_)
+            | StatementASTNode::ArrayDeclarationAST(_)
+            | StatementASTNode::LoopAST(_)
+            | StatementASTNode::PureTickAST(_)
+            | StatementASTNode::BreakAST(_)
+            | StatementASTNode::ContinueAST(_) => Vec::new(),
+        }
     }
 }
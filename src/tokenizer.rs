@@ -5,19 +5,55 @@ pub enum Keyword {
     If,
     Else,
     While,
+    Loop,
+    Do,
+    For,
+    In,
+    Break,
+    Continue,
+    Match,
     Inline,
+    Struct,
+    Enum,
+    Return,
+    Link,
+    As,
     LeftCurly,
     RightCurly,
     LeftParenthese,
     RightParenthese,
     Assign,
     Comma,
+    FatArrow,
+    Pipe,
+    Colon,
+    DotDot,
+    AddAssign,
+    SubAssign,
+    MulAssign,
+    DivAssign,
+    ModAssign,
+    AndAssign,
+    OrAssign,
+    XorAssign,
+    ShlAssign,
+    ShrAssign,
+    Increment,
+    Decrement,
+    AndAnd,
+    OrOr,
+    LeftBracket,
+    RightBracket,
+    Semicolon,
+    At,
+    Hash,
 }
 
 #[derive(Debug)]
 pub enum Token {
     Keyword(Keyword),
     Identifier(String),
+    LoopLabel(String),
     Number(f64),
     String(String),
 }
@@ -30,18 +66,36 @@ pub fn tokenize(code: &str) -> Result<Vec<Token>, String> {
     //let mut prev_newline_char: Option<char> = None;
 
     let mut char_iter = code.chars().peekable();
-    while let Some(ch) = char_iter.peek() {
-        println!("Current character: \'{}\'", ch);
+    while let Some(&ch) = char_iter.peek() {
         match ch {
             letter if letter.is_alphabetic() || matches!(letter, '_' | '@') => {
                 //Either keyword or identifier
                 let mut identifier = String::new();
-                identifier.push(*letter);
+                identifier.push(letter);
                 char_iter.next();
                 let mut ident_len = 1;
-                while let Some(ident_ch) = char_iter.peek() {
-                    if ident_ch.is_alphanumeric() || *ident_ch == '_' {
-                        identifier.push(*ident_ch);
+                while let Some(&ident_ch) = char_iter.peek() {
+                    if ident_ch == '.' {
+                        //Struct field path like `target.pos.x`
+                        let mut lookahead = char_iter.clone();
+                        lookahead.next();
+                        if !lookahead
+                            .peek()
+                            .is_some_and(|next_ch| next_ch.is_alphabetic() || *next_ch == '_')
+                        {
+                            break;
+                        }
+                    }
+                    //Content names like `@phase-fabric` contain dashes, also when sensed like
+                    //`vault1.phase-fabric`
+                    let is_content_dash = ident_ch == '-'
+                        && (identifier.starts_with('@') || identifier.contains('.'))
+                        && second_char(&char_iter).is_some_and(|c| c.is_alphabetic());
+                    if ident_ch.is_alphanumeric()
+                        || matches!(ident_ch, '_' | '.')
+                        || is_content_dash
+                    {
+                        identifier.push(ident_ch);
                         char_iter.next();
                         ident_len += 1;
                     } else {
@@ -50,12 +104,25 @@ pub fn tokenize(code: &str) -> Result<Vec<Token>, String> {
                 }
 
                 let token = match identifier.as_str() {
+                    "@" => Token::Keyword(Keyword::At),
                     "fn" => Token::Keyword(Keyword::Fn),
                     "let" => Token::Keyword(Keyword::Let),
                     "if" => Token::Keyword(Keyword::If),
                     "else" => Token::Keyword(Keyword::Else),
                     "while" => Token::Keyword(Keyword::While),
+                    "loop" => Token::Keyword(Keyword::Loop),
+                    "do" => Token::Keyword(Keyword::Do),
+                    "for" => Token::Keyword(Keyword::For),
+                    "in" => Token::Keyword(Keyword::In),
+                    "break" => Token::Keyword(Keyword::Break),
+                    "continue" => Token::Keyword(Keyword::Continue),
+                    "match" => Token::Keyword(Keyword::Match),
                     "inline" => Token::Keyword(Keyword::Inline),
+                    "struct" => Token::Keyword(Keyword::Struct),
+                    "enum" => Token::Keyword(Keyword::Enum),
+                    "return" => Token::Keyword(Keyword::Return),
+                    "link" => Token::Keyword(Keyword::Link),
+                    "as" => Token::Keyword(Keyword::As),
                     _ => Token::Identifier(identifier),
                 };
                 result.push(token);
@@ -64,66 +131,156 @@ pub fn tokenize(code: &str) -> Result<Vec<Token>, String> {
             '"' => {
                 let mut string_content = String::new();
                 let mut str_total_len = 1;
+                let mut terminated = false;
                 char_iter.next();
                 while let Some(str_ch) = char_iter.next() {
                     match str_ch {
                         '\\' => {
+                            let escape_column = column_counter + str_total_len;
                             let next_ch = match char_iter.next() {
                                 Some(some_ch) => some_ch,
                                 None => {
-                                    return Err(String::from(
-                                        "Expected appropriate character after '\\'",
+                                    return Err(format!(
+                                        "Expected appropriate character after '\\' at {}:{}",
+                                        line_counter, escape_column
                                     ));
                                 }
                             };
+                            str_total_len += 2;
                             match next_ch {
                                 '\\' => string_content.push('\\'),
                                 '\"' => string_content.push('\"'),
                                 'n' => string_content.push('\n'),
+                                't' => string_content.push('\t'),
+                                'u' => {
+                                    //Unicode escape like `\u{E800}`
+                                    if char_iter.next() != Some('{') {
+                                        return Err(format!(
+                                            "Expected '{{' after \"\\u\" at {}:{}",
+                                            line_counter, escape_column
+                                        ));
+                                    }
+                                    let mut code_point = String::new();
+                                    for hex_ch in char_iter.by_ref() {
+                                        if hex_ch == '}' {
+                                            break;
+                                        }
+                                        code_point.push(hex_ch);
+                                    }
+                                    str_total_len += code_point.len() + 2;
+                                    match u32::from_str_radix(&code_point, 16)
+                                        .ok()
+                                        .and_then(char::from_u32)
+                                    {
+                                        Some(unicode_ch) => string_content.push(unicode_ch),
+                                        None => {
+                                            return Err(format!(
+                                                "Invalid unicode escape \"\\u{{{}}}\" at {}:{}",
+                                                code_point, line_counter, escape_column
+                                            ));
+                                        }
+                                    }
+                                }
                                 _ => {
                                     return Err(format!(
-                                        "Unknown escape sequence \"\\{}\"",
-                                        next_ch
+                                        "Unknown escape sequence \"\\{}\" at {}:{}",
+                                        next_ch, line_counter, escape_column
                                     ))
                                 }
                             }
-                            str_total_len += 2;
                         }
                         '\"' => {
                             str_total_len += 1;
+                            terminated = true;
                             break;
                         }
+                        '\n' => {
+                            string_content.push('\n');
+                            line_counter += 1;
+                            column_counter = 1;
+                            str_total_len = 0;
+                        }
                         other_ch => {
                             string_content.push(other_ch);
                             str_total_len += 1;
                         }
                     }
                 }
+                if !terminated {
+                    return Err(format!(
+                        "Unterminated string literal at {}:{}",
+                        line_counter, column_counter
+                    ));
+                }
                 result.push(Token::String(string_content));
                 column_counter += str_total_len;
             }
-            number if number.is_digit(10) => {
-                let mut number_content = String::new();
-                while let Some(digit) = char_iter.peek() {
-                    if digit.is_digit(10) || *digit == '.' {
-                        number_content.push(*digit);
+            number
+                if number.is_ascii_digit()
+                    || (number == '-'
+                        && second_char(&char_iter).is_some_and(|c| c.is_ascii_digit())) =>
+            {
+                let start_column = column_counter;
+                let mut number_content = String::from(number);
+                char_iter.next();
+                column_counter += 1;
+                while let Some(&digit) = char_iter.peek() {
+                    if digit == '.' && second_char(&char_iter) == Some('.') {
+                        //Don't consume range operator following the number
+                        break;
+                    }
+                    let is_exponent_sign = matches!(digit, '+' | '-')
+                        && number_content.ends_with(['e', 'E'])
+                        && !number_content.contains(['x', 'X']);
+                    if digit.is_ascii_alphanumeric()
+                        || matches!(digit, '_' | '.')
+                        || is_exponent_sign
+                    {
+                        number_content.push(digit);
                         char_iter.next();
                         column_counter += 1;
                     } else {
                         break;
                     }
                 }
-                let number_parsed = match number_content.parse::<f64>() {
+                let number_parsed = match number_literal_value(&number_content) {
                     Ok(n_p) => n_p,
                     Err(e) => {
-                        return Err(format!(
-                            "Error occurred when parsing number \"{}\": {}",
-                            number_content, e
-                        ));
+                        return Err(format!("{} at {}:{}", e, line_counter, start_column));
                     }
                 };
                 result.push(Token::Number(number_parsed));
             }
+            '%' if second_char(&char_iter).is_some_and(|c| c.is_ascii_hexdigit()) => {
+                //Color literal `%rrggbb` or `%rrggbbaa`, folded to the value `packcolor` gives
+                char_iter.next();
+                let mut color_content = String::new();
+                while let Some(&hex_ch) = char_iter.peek() {
+                    if hex_ch.is_ascii_alphanumeric() {
+                        color_content.push(hex_ch);
+                        char_iter.next();
+                    } else {
+                        break;
+                    }
+                }
+                let rgba = match color_content.len() {
+                    6 => u32::from_str_radix(&color_content, 16)
+                        .ok()
+                        .map(|rgb| rgb << 8 | 0xff),
+                    8 => u32::from_str_radix(&color_content, 16).ok(),
+                    _ => None,
+                };
+                match rgba {
+                    Some(rgba) => result.push(Token::Number(f64::from_bits(rgba as u64))),
+                    None => {
+                        return Err(format!(
+                            "Invalid color literal \"%{}\" at {}:{}",
+                            color_content, line_counter, column_counter
+                        ));
+                    }
+                }
+                column_counter += color_content.len() + 1;
+            }
             '{' => {
                 char_iter.next();
                 result.push(Token::Keyword(Keyword::LeftCurly));
@@ -134,6 +291,26 @@ pub fn tokenize(code: &str) -> Result<Vec<Token>, String> {
                 result.push(Token::Keyword(Keyword::RightCurly));
                 column_counter += 1;
             }
+            '[' => {
+                char_iter.next();
+                result.push(Token::Keyword(Keyword::LeftBracket));
+                column_counter += 1;
+            }
+            ']' => {
+                char_iter.next();
+                result.push(Token::Keyword(Keyword::RightBracket));
+                column_counter += 1;
+            }
+            ';' => {
+                char_iter.next();
+                result.push(Token::Keyword(Keyword::Semicolon));
+                column_counter += 1;
+            }
+            '#' => {
+                char_iter.next();
+                result.push(Token::Keyword(Keyword::Hash));
+                column_counter += 1;
+            }
             '(' => {
                 char_iter.next();
                 result.push(Token::Keyword(Keyword::LeftParenthese));
@@ -146,14 +323,114 @@ pub fn tokenize(code: &str) -> Result<Vec<Token>, String> {
             }
             '=' => {
                 char_iter.next();
-                result.push(Token::Keyword(Keyword::Assign));
-                column_counter += 1;
+                if char_iter.peek() == Some(&'>') {
+                    char_iter.next();
+                    result.push(Token::Keyword(Keyword::FatArrow));
+                    column_counter += 2;
+                } else {
+                    result.push(Token::Keyword(Keyword::Assign));
+                    column_counter += 1;
+                }
             }
             ',' => {
                 char_iter.next();
                 result.push(Token::Keyword(Keyword::Comma));
                 column_counter += 1;
             }
+            '.' => {
+                char_iter.next();
+                if char_iter.peek() == Some(&'.') {
+                    char_iter.next();
+                    result.push(Token::Keyword(Keyword::DotDot));
+                    column_counter += 2;
+                } else {
+                    return Err(format!(
+                        "Unexpected character '.' at {}:{}",
+                        line_counter, column_counter
+                    ));
+                }
+            }
+            ':' => {
+                char_iter.next();
+                result.push(Token::Keyword(Keyword::Colon));
+                column_counter += 1;
+            }
+            '\'' => {
+                //Loop label
+                char_iter.next();
+                let mut label = String::new();
+                while let Some(label_ch) = char_iter.peek() {
+                    if label_ch.is_alphanumeric() || *label_ch == '_' {
+                        label.push(*label_ch);
+                        char_iter.next();
+                    } else {
+                        break;
+                    }
+                }
+                if label.is_empty() {
+                    return Err(format!(
+                        "Expected loop label name after \"'\" at {}:{}",
+                        line_counter, column_counter
+                    ));
+                }
+                column_counter += label.len() + 1;
+                result.push(Token::LoopLabel(label));
+            }
+            '|' => {
+                char_iter.next();
+                if char_iter.peek() == Some(&'=') {
+                    char_iter.next();
+                    result.push(Token::Keyword(Keyword::OrAssign));
+                    column_counter += 2;
+                } else if char_iter.peek() == Some(&'|') {
+                    char_iter.next();
+                    result.push(Token::Keyword(Keyword::OrOr));
+                    column_counter += 2;
+                } else {
+                    result.push(Token::Keyword(Keyword::Pipe));
+                    column_counter += 1;
+                }
+            }
+            '+' | '-' | '*' | '/' | '%' | '&' | '^' | '<' | '>' => {
+                //Compound assignment and logical operators
+                let operator_ch = ch;
+                char_iter.next();
+                let mut operator = String::from(operator_ch);
+                if matches!(operator_ch, '<' | '>') && char_iter.peek() == Some(&operator_ch) {
+                    operator.push(operator_ch);
+                    char_iter.next();
+                }
+                if matches!(operator_ch, '+' | '-' | '&') && char_iter.peek() == Some(&operator_ch)
+                {
+                    operator.push(operator_ch);
+                    char_iter.next();
+                } else if char_iter.peek() == Some(&'=') {
+                    operator.push('=');
+                    char_iter.next();
+                }
+                let keyword = match operator.as_str() {
+                    "+=" => Keyword::AddAssign,
+                    "-=" => Keyword::SubAssign,
+                    "*=" => Keyword::MulAssign,
+                    "/=" => Keyword::DivAssign,
+                    "%=" => Keyword::ModAssign,
+                    "&=" => Keyword::AndAssign,
+                    "^=" => Keyword::XorAssign,
+                    "<<=" => Keyword::ShlAssign,
+                    ">>=" => Keyword::ShrAssign,
+                    "++" => Keyword::Increment,
+                    "--" => Keyword::Decrement,
+                    "&&" => Keyword::AndAnd,
+                    _ => {
+                        return Err(format!(
+                            "Unexpected operator \"{}\" at {}:{}",
+                            operator, line_counter, column_counter
+                        ));
+                    }
+                };
+                result.push(Token::Keyword(keyword));
+                column_counter += operator.len();
+            }
             ' ' | '\t' => {
                 char_iter.next();
                 column_counter += 1;
@@ -178,3 +455,45 @@ pub fn tokenize(code: &str) -> Result<Vec<Token>, String> {
     }
     Ok(result)
 }
+
+/// Character after the next one
+fn second_char(char_iter: &std::iter::Peekable<std::str::Chars>) -> Option<char> {
+    let mut lookahead = char_iter.clone();
+    lookahead.next();
+    lookahead.next()
+}
+
+/// Value of number literal, which is either decimal with optional exponent, `0x` hexadecimal or
+/// `0b` binary, with optional `-` sign and `_` digit separators
+fn number_literal_value(literal: &str) -> Result<f64, String> {
+    let (negative, unsigned) = match literal.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, literal),
+    };
+    let digits = unsigned.replace('_', "");
+    let invalid = || format!("Invalid number literal \"{}\"", literal);
+
+    let value = if let Some(hex_digits) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex_digits, 16).map_err(|_| invalid())? as f64
+    } else if let Some(binary_digits) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        u64::from_str_radix(binary_digits, 2).map_err(|_| invalid())? as f64
+    } else {
+        if digits.matches('.').count() > 1 {
+            return Err(format!(
+                "Number literal \"{}\" has more than one decimal point",
+                literal
+            ));
+        }
+        digits.parse::<f64>().map_err(|_| invalid())?
+    };
+    if !value.is_finite() {
+        return Err(format!("Number literal \"{}\" is out of range", literal));
+    }
+    Ok(if negative { -value } else { value })
+}